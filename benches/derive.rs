@@ -0,0 +1,82 @@
+// cargo bench --bench derive
+use bitcoin::bip32::{DerivationPath, ExtendedPrivKey};
+use bitcoin::key::KeyPair;
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::{Network, ScriptBuf};
+use btc::key_pair::{AccountGenerator, KeychainKind, Purpose};
+use rayon::prelude::*;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+const MNEMONIC: &str =
+    "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+const COUNT: u32 = 2000;
+
+fn bench(name: &str, f: impl FnOnce() -> Vec<ScriptBuf>) -> Duration {
+    let start = Instant::now();
+    let scripts = f();
+    let elapsed = start.elapsed();
+    assert_eq!(scripts.len(), COUNT as usize);
+
+    println!(
+        "{name:<40} {COUNT} addresses in {elapsed:>12?}, {:>10?} each",
+        elapsed / COUNT
+    );
+    elapsed
+}
+
+fn main() {
+    let ag = AccountGenerator::new(MNEMONIC, Network::Bitcoin).unwrap();
+    let seed = *ag.reveal_seed().unwrap();
+
+    // how every address used to be derived: a new context and the full path from the master key
+    let baseline = bench("new context, full path per address", || {
+        (0..COUNT)
+            .map(|index| {
+                let secp = Secp256k1::new();
+                let master = ExtendedPrivKey::new_master(Network::Bitcoin, &seed).unwrap();
+                let path = DerivationPath::from_str(&format!("m/86'/0'/0'/0/{index}")).unwrap();
+                let derived = master.derive_priv(&secp, &path).unwrap();
+                let keypair = KeyPair::from_secret_key(&secp, &derived.private_key);
+                Purpose::Taproot.script_pubkey(&keypair.public_key())
+            })
+            .collect()
+    });
+
+    let single = bench("get_account per address", || {
+        (0..COUNT)
+            .map(|index| {
+                ag.get_account(KeychainKind::External, index)
+                    .unwrap()
+                    .script_pubkey()
+            })
+            .collect()
+    });
+
+    let range = bench("derive_range", || {
+        ag.derive_range(KeychainKind::External, 0..COUNT)
+            .unwrap()
+            .iter()
+            .map(|account| account.script_pubkey())
+            .collect()
+    });
+
+    let par_range = bench("par_derive_range", || {
+        ag.par_derive_range(KeychainKind::External, 0..COUNT)
+            .unwrap()
+            .par_iter()
+            .map(|account| account.script_pubkey())
+            .collect()
+    });
+
+    for (name, elapsed) in [
+        ("get_account", single),
+        ("derive_range", range),
+        ("par_derive_range", par_range),
+    ] {
+        println!(
+            "{name:<20} {:.1}x faster",
+            baseline.as_secs_f64() / elapsed.as_secs_f64()
+        );
+    }
+}
@@ -0,0 +1,38 @@
+use bitcoin::Network;
+use btc::discovery::{discover, DEFAULT_GAP_LIMIT};
+use btc::key_pair::Purpose;
+use btc::keystore::unlock_from_env;
+use electrum_client::Client;
+
+fn main() -> anyhow::Result<()> {
+    dotenv::dotenv().unwrap();
+
+    let secret = unlock_from_env()?;
+    let network = Network::from_core_arg(&std::env::var("NETWORK")?)?;
+    let gap_limit = std::env::var("GAP_LIMIT")
+        .map(|g| g.parse::<u32>())
+        .unwrap_or(Ok(DEFAULT_GAP_LIMIT))?;
+
+    let ag = secret.account_generator(network)?;
+    let client = Client::new("tcp://127.0.0.1:50001")?;
+
+    for chain in discover(&client, &ag, &Purpose::ALL, gap_limit)? {
+        println!(
+            "m/{}'/{} used: {}, confirmed: {}, unconfirmed: {}, next unused index: {}",
+            chain.purpose,
+            chain.keychain,
+            chain.used.len(),
+            chain.confirmed(),
+            chain.unconfirmed(),
+            chain.next_unused
+        );
+        for used in chain.used.iter() {
+            println!(
+                "    {} {}, txs: {}, confirmed: {}, unconfirmed: {}",
+                used.index, used.address, used.tx_count, used.confirmed, used.unconfirmed
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,32 @@
+use bitcoin::Network;
+use btc::key_pair::{KeychainKind, Purpose};
+use btc::keystore::unlock_from_env;
+
+// print the account xpubs and descriptors to set up a watch-only wallet elsewhere
+fn main() -> anyhow::Result<()> {
+    dotenv::dotenv().unwrap();
+
+    let secret = unlock_from_env()?;
+    let network = Network::from_core_arg(&std::env::var("NETWORK")?)?;
+    let account = std::env::var("ACCOUNT")
+        .map(|a| a.parse::<u32>())
+        .unwrap_or(Ok(0))?;
+
+    let ag = secret.account_generator(network)?;
+    for purpose in Purpose::ALL {
+        let ag = ag.clone().with_purpose(purpose).with_account(account);
+        println!(
+            "[{}/{}] {}",
+            ag.fingerprint(),
+            ag.account_derivation_path()?
+                .to_string()
+                .trim_start_matches("m/"),
+            ag.account_xpub_slip132()?
+        );
+        for keychain in KeychainKind::ALL {
+            println!("    {}", ag.descriptor(keychain)?);
+        }
+    }
+
+    Ok(())
+}
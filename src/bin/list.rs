@@ -1,21 +1,36 @@
 use bitcoin::Network;
-use btc::key_pair::AccountGenerator;
+use btc::key_pair::{KeychainKind, Purpose};
+use btc::keystore::unlock_from_env;
 use dotenv::dotenv;
+use std::str::FromStr;
 
 #[tokio::main]
 async fn main() {
     dotenv().unwrap();
-    let mnemonic_code = std::env::var("MNEMONIC").unwrap();
+    let secret = unlock_from_env().unwrap();
     let network = Network::from_core_arg(&std::env::var("NETWORK").unwrap()).unwrap();
+    // optional, defaults to taproot account 0
+    let purpose = std::env::var("PURPOSE")
+        .map(|p| Purpose::from_str(&p).unwrap())
+        .unwrap_or_default();
+    let account = std::env::var("ACCOUNT")
+        .map(|a| a.parse::<u32>().unwrap())
+        .unwrap_or_default();
 
-    let ag = AccountGenerator::new(&mnemonic_code, network).unwrap();
-    for idx in 0..=100u32 {
-        let account = ag.get_account_from_index(idx).unwrap();
-        println!(
-            "{idx} {}, script: {:?}, hex: {:?}",
-            account,
-            account.script_pubkey().as_script().to_string(),
-            account.script_pubkey().as_script().to_hex_string()
-        );
+    let ag = secret
+        .account_generator(network)
+        .unwrap()
+        .with_purpose(purpose)
+        .with_account(account);
+    for keychain in KeychainKind::ALL {
+        for idx in 0..=100u32 {
+            let account = ag.get_account(keychain, idx).unwrap();
+            println!(
+                "{keychain} {idx} {}, script: {:?}, hex: {:?}",
+                account,
+                account.script_pubkey().as_script().to_string(),
+                account.script_pubkey().as_script().to_hex_string()
+            );
+        }
     }
 }
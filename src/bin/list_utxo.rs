@@ -1,24 +1,27 @@
 use bitcoin::Network;
-use btc::key_pair::AccountGenerator;
+use btc::key_pair::KeychainKind;
+use btc::keystore::unlock_from_env;
 use electrum_client::{Client, ElectrumApi};
 
 fn main() -> anyhow::Result<()> {
     dotenv::dotenv().unwrap();
 
-    let mnemonic = std::env::var("MNEMONIC")?;
+    let secret = unlock_from_env()?;
     let network = Network::from_core_arg(&std::env::var("NETWORK")?)?;
 
     let index = 0u32;
 
-    let ag = AccountGenerator::new(&mnemonic, network)?;
-    let account = ag.get_account_from_index(index)?;
-
-    let script_pubkey = account.script_pubkey();
+    let ag = secret.account_generator(network)?;
     let client = Client::new("tcp://127.0.0.1:50001")?;
 
-    let utxos = client.script_list_unspent(script_pubkey.as_script())?;
-    for utxo in utxos.iter() {
-        println!("utxo: {:?}", utxo);
+    for keychain in KeychainKind::ALL {
+        let account = ag.get_account(keychain, index)?;
+        let script_pubkey = account.script_pubkey();
+
+        let utxos = client.script_list_unspent(script_pubkey.as_script())?;
+        for utxo in utxos.iter() {
+            println!("{keychain} utxo: {:?}", utxo);
+        }
     }
 
     Ok(())
@@ -1,7 +1,10 @@
-use bitcoin::absolute::LockTime;
-use bitcoin::{Network, OutPoint, Sequence, Transaction, TxIn, TxOut, Txid};
+use bitcoin::{FeeRate, Network, OutPoint, TxOut, Txid};
 use bitcoin_private::hex::display::DisplayHex;
-use btc::key_pair::AccountGenerator;
+use btc::discovery::DEFAULT_GAP_LIMIT;
+use btc::error::Error;
+use btc::keystore::unlock_from_env;
+use btc::psbt::{extract_tx, finalize_psbt, write_unsigned_psbt};
+use btc::tx_builder::{ChangePolicy, TxBuilder};
 use electrum_client::{Client, ElectrumApi, ListUnspentRes};
 use ordinals::{RuneId, Runestone};
 use std::str::FromStr;
@@ -9,13 +12,14 @@ use std::str::FromStr;
 fn main() -> anyhow::Result<()> {
     dotenv::dotenv().unwrap();
 
-    let mnemonic = std::env::var("MNEMONIC")?;
+    let secret = unlock_from_env()?;
     let network = Network::from_core_arg(&std::env::var("NETWORK")?)?;
 
     let index = 0u32;
 
-    let ag = AccountGenerator::new(&mnemonic, network)?;
+    let ag = secret.account_generator(network)?;
     let account = ag.get_account_from_index(index)?;
+    let change_account = ag.get_change_account_from_index(index)?;
 
     let runestone = Runestone {
         edicts: vec![],
@@ -59,56 +63,51 @@ fn main() -> anyhow::Result<()> {
         let target_utxos = vec![utxo];
         println!("picked target_utxos: {:?}", &target_utxos);
 
-        let input_value: u64 = target_utxos
+        // the runestone points at output 1, the change, which must exist
+        let built = target_utxos
             .iter()
-            .map(|utxo| utxo.value)
-            .collect::<Vec<_>>()
-            .iter()
-            .sum();
-
-        let inputs = target_utxos
-            .iter()
-            .map(|utxo| TxIn {
-                previous_output: OutPoint {
-                    txid: utxo.tx_hash,
-                    vout: utxo.tx_pos as u32,
+            .fold(
+                TxBuilder::new(FeeRate::from_sat_per_vb_unchecked(200)),
+                |tx_builder, utxo| {
+                    tx_builder.add_utxo(
+                        OutPoint {
+                            txid: utxo.tx_hash,
+                            vout: utxo.tx_pos as u32,
+                        },
+                        TxOut {
+                            value: utxo.value,
+                            script_pubkey: account.script_pubkey(),
+                        },
+                    )
                 },
-                script_sig: Default::default(),
-                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
-                witness: Default::default(),
-            })
-            .collect::<Vec<_>>();
-
-        let mut tx = Transaction {
-            version: 2,
-            lock_time: LockTime::ZERO,
-            input: inputs, // pickup the utxos from step2.1, and convert them to txins
-            output: vec![
-                TxOut {
-                    value: 0,
-                    // runestone
-                    script_pubkey: runestone.encipher(),
-                },
-                TxOut {
-                    // change the value afterwards
-                    value: 0,
-                    // make left balance to itself
-                    script_pubkey: account.script_pubkey(),
-                },
-            ],
+            )
+            .add_op_return(runestone.encipher())
+            .with_change(ChangePolicy::RequiredChange(change_account.script_pubkey()))
+            .build();
+        let mut psbt = match built {
+            Ok(psbt) => psbt,
+            Err(e @ (Error::InsufficientFunds { .. } | Error::DustOutput { .. })) => {
+                println!("skipping utxo: {e}");
+                continue;
+            }
+            Err(e) => return Err(e.into()),
         };
-        let gas = tx.vsize() * 200;
-
-        if input_value == 0 {
-            println!("tx: {:?}", tx);
-            return Ok(());
+        ag.update_psbt(&mut psbt, &[], DEFAULT_GAP_LIMIT)?;
+        let gas = psbt.fee()?.to_sat();
+
+        // with PSBT_DIR the unsigned psbts are written there instead of signing
+        if let Ok(dir) = std::env::var("PSBT_DIR") {
+            let path = write_unsigned_psbt(dir, &psbt)?;
+            println!("unsigned psbt: {}", path.display());
+            continue;
         }
-        tx.output[1].value = input_value.checked_sub(gas as u64).unwrap();
 
-        let signed_tx = ag.sign_tx(&tx, index, input_value)?;
+        ag.sign_psbt(&mut psbt)?;
+        finalize_psbt(&mut psbt)?;
+        let signed_tx = extract_tx(psbt)?;
         println!(
             "gas: {gas}, output_value: {}, signed_tx: {:?}",
-            tx.output[1].value, signed_tx
+            signed_tx.output[1].value, signed_tx
         );
 
         println!(
@@ -0,0 +1,68 @@
+use bip39::Language;
+use btc::mnemonic::{
+    generate_mnemonic, mnemonic_from_coins, mnemonic_from_dice, parse_language, validate_mnemonic,
+};
+
+const USAGE: &str = "usage:
+    mnemonic generate [words] [language]
+    mnemonic dice <words> <rolls> [language]
+    mnemonic coins <words> <flips> [language]
+    mnemonic validate <mnemonic> [language]";
+
+fn language_arg(arg: Option<&String>) -> anyhow::Result<Language> {
+    Ok(match arg {
+        Some(language) => parse_language(language)?,
+        None => Language::English,
+    })
+}
+
+fn word_count_arg(arg: Option<&String>) -> anyhow::Result<usize> {
+    Ok(match arg {
+        Some(words) => words.parse()?,
+        None => 24,
+    })
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+
+    let mnemonic = match args.first().map(String::as_str) {
+        Some("generate") => {
+            generate_mnemonic(word_count_arg(args.get(1))?, language_arg(args.get(2))?)?
+        }
+        Some("dice") if args.len() >= 3 => mnemonic_from_dice(
+            word_count_arg(args.get(1))?,
+            &args[2],
+            language_arg(args.get(3))?,
+        )?,
+        Some("coins") if args.len() >= 3 => mnemonic_from_coins(
+            word_count_arg(args.get(1))?,
+            &args[2],
+            language_arg(args.get(3))?,
+        )?,
+        Some("validate") if args.len() >= 2 => {
+            let language = args.get(2).map(|l| parse_language(l)).transpose()?;
+            match validate_mnemonic(&args[1], language) {
+                Ok(mnemonic) => {
+                    println!(
+                        "valid {} words {} mnemonic",
+                        mnemonic.word_count(),
+                        mnemonic.language()
+                    );
+                    return Ok(());
+                }
+                Err(e) => {
+                    eprintln!("invalid mnemonic: {e}");
+                    std::process::exit(1);
+                }
+            }
+        }
+        _ => {
+            eprintln!("{USAGE}");
+            std::process::exit(2);
+        }
+    };
+
+    println!("{}", mnemonic);
+    Ok(())
+}
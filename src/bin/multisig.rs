@@ -1,18 +1,64 @@
-use bitcoin::{Address, Network};
-use btc::key_pair::AccountGenerator;
+use bitcoin::bip32::ChildNumber;
+use bitcoin::Network;
+use btc::key_pair::KeychainKind;
+use btc::keystore::unlock_from_env;
+use btc::multisig::{multisig_derivation_path, Cosigner, Multisig};
+use btc::script_hash::ScriptHashType;
+use std::str::FromStr;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     dotenv::dotenv().unwrap();
-    let mnemonic_code = std::env::var("MNEMONIC").unwrap();
-    let network = Network::from_core_arg(&std::env::var("NETWORK").unwrap()).unwrap();
+    let secret = unlock_from_env()?;
+    let network = Network::from_core_arg(&std::env::var("NETWORK")?)?;
+    let threshold = std::env::var("THRESHOLD")
+        .map(|t| t.parse::<usize>())
+        .unwrap_or(Ok(2))?;
 
-    let ag = AccountGenerator::new(&mnemonic_code, network).unwrap();
+    let ag = secret.account_generator(network)?;
 
-    let scriptbuf = ag.gen_n_of_n_multisig(&[100, 101, 102], 3)?;
-    println!("script buf: {:?}", scriptbuf);
+    // the keys of each script type are on their own path, so the other cosigners are given
+    // per type as comma separated [fingerprint/path]xpub in COSIGNERS_P2WSH,
+    // COSIGNERS_P2SH_P2WSH and COSIGNERS_P2SH, next to our own xpub.
+    // without them, accounts 0, 1 and 2 of our seed stand in for three cosigners.
+    for (kind, var) in [
+        (ScriptHashType::P2wsh, "COSIGNERS_P2WSH"),
+        (ScriptHashType::P2shP2wsh, "COSIGNERS_P2SH_P2WSH"),
+        (ScriptHashType::P2sh, "COSIGNERS_P2SH"),
+    ] {
+        let cosigners = match std::env::var(var) {
+            Ok(others) => std::iter::once(Cosigner::from_generator(&ag, kind))
+                .chain(others.split(',').map(Cosigner::from_str))
+                .collect::<anyhow::Result<Vec<_>>>()?,
+            Err(_) => (0..3)
+                .map(|account| match kind {
+                    // m/45' has no account level, the accounts of one seed would be the same key
+                    ScriptHashType::P2sh => Cosigner::from_generator_path(
+                        &ag,
+                        multisig_derivation_path(network, account, kind)?
+                            .child(ChildNumber::from_hardened_idx(account)?),
+                    ),
+                    _ => Cosigner::from_generator(&ag.clone().with_account(account), kind),
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?,
+        };
+        for cosigner in &cosigners {
+            println!("{kind:?} cosigner: {cosigner}");
+        }
+
+        let multisig = Multisig::new(threshold, cosigners, network)?.with_kind(kind);
+        println!(
+            "{threshold}-of-{} {kind:?} address: {}",
+            multisig.cosigners().len(),
+            multisig.address(KeychainKind::External, 0)?
+        );
+        for keychain in KeychainKind::ALL {
+            println!(
+                "  {keychain} descriptor: {}",
+                multisig.descriptor(keychain)?
+            );
+        }
+    }
 
-    let address = Address::p2wsh(&scriptbuf, network);
-    println!("multisig address: {:?}", address);
     Ok(())
 }
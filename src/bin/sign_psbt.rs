@@ -0,0 +1,37 @@
+use bitcoin::Network;
+use btc::keystore::unlock_from_env;
+use btc::psbt::{read_psbt_with_format, write_psbt};
+
+const USAGE: &str = "usage:
+    sign_psbt <psbt file> [signed psbt file]
+
+signs the inputs of the psbt that spend our outputs, found by the key origins, and
+writes it back in the same format (to the input file unless another one is given).";
+
+fn main() -> anyhow::Result<()> {
+    dotenv::dotenv().ok();
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let Some(input) = args.first() else {
+        eprintln!("{USAGE}");
+        std::process::exit(2);
+    };
+    let output = args.get(1).unwrap_or(input);
+
+    let secret = unlock_from_env()?;
+    let network = Network::from_core_arg(&std::env::var("NETWORK")?)?;
+    let ag = secret.account_generator(network)?;
+
+    let (mut psbt, format) = read_psbt_with_format(input)?;
+    let report = ag.sign_psbt(&mut psbt)?;
+    for index in &report.signed {
+        println!("input {index}: signed");
+    }
+    for (index, reason) in &report.skipped {
+        println!("input {index}: skipped, {reason}");
+    }
+
+    write_psbt(output, &psbt, format)?;
+    println!("wrote {output}");
+
+    Ok(())
+}
@@ -1,19 +1,22 @@
-use bitcoin::absolute::LockTime;
-use bitcoin::{Network, OutPoint, Sequence, Transaction, TxIn, TxOut, Txid};
-use btc::key_pair::AccountGenerator;
+use bitcoin::{FeeRate, Network, OutPoint, TxOut, Txid};
+use btc::discovery::DEFAULT_GAP_LIMIT;
+use btc::error::Error;
+use btc::keystore::unlock_from_env;
+use btc::psbt::{extract_tx, finalize_psbt, write_unsigned_psbt};
+use btc::tx_builder::{ChangePolicy, TxBuilder};
 use electrum_client::{Client, ElectrumApi, ListUnspentRes};
 use std::str::FromStr;
 
 fn main() -> anyhow::Result<()> {
     dotenv::dotenv().unwrap();
 
-    let mnemonic = std::env::var("MNEMONIC")?;
+    let secret = unlock_from_env()?;
     let network = Network::from_core_arg(&std::env::var("NETWORK")?)?;
 
     let index = 0u32;
     let splits = 5u64;
 
-    let ag = AccountGenerator::new(&mnemonic, network)?;
+    let ag = secret.account_generator(network)?;
     let account = ag.get_account_from_index(index)?;
 
     // let script_pubkey = account.script_pubkey();
@@ -58,44 +61,56 @@ fn main() -> anyhow::Result<()> {
         return Ok(());
     }
 
-    let value = (utxo.value - 225 * 284) / splits;
+    // splits - 1 outputs of the same value, the change (to the same script) gets what the
+    // fee leaves
+    let value = utxo.value / splits;
+    let tx_builder = utxos.iter().fold(
+        TxBuilder::new(FeeRate::from_sat_per_vb_unchecked(225)),
+        |tx_builder, utxo| {
+            tx_builder.add_utxo(
+                OutPoint {
+                    txid: utxo.tx_hash,
+                    vout: utxo.tx_pos as u32,
+                },
+                TxOut {
+                    value: utxo.value,
+                    script_pubkey: account.script_pubkey(),
+                },
+            )
+        },
+    );
+    let built = (1..splits)
+        .fold(tx_builder, |tx_builder, _| {
+            tx_builder.add_recipient(account.script_pubkey(), value)
+        })
+        .with_change(ChangePolicy::RequiredChange(account.script_pubkey()))
+        .build();
+    let mut psbt = match built {
+        Ok(psbt) => psbt,
+        Err(e @ (Error::InsufficientFunds { .. } | Error::DustOutput { .. })) => {
+            eprintln!("can't split {} sats {splits} ways: {e}", utxo.value);
+            std::process::exit(1);
+        }
+        Err(e) => return Err(e.into()),
+    };
+    ag.update_psbt(&mut psbt, &[], DEFAULT_GAP_LIMIT)?;
 
     println!(
         "each got {:} sats, and gas is: {:}",
         value,
-        utxo.value - value * splits
+        psbt.fee()?.to_sat()
     );
 
-    let outputs = (0..splits)
-        .map(|_| TxOut {
-            value,
-            script_pubkey: account.script_pubkey(),
-        })
-        .collect::<Vec<_>>();
-
-    dbg!(outputs.len());
-
-    let inputs = utxos
-        .iter()
-        .map(|utxo| TxIn {
-            previous_output: OutPoint {
-                txid: utxo.tx_hash,
-                vout: utxo.tx_pos as u32,
-            },
-            script_sig: Default::default(),
-            sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
-            witness: Default::default(),
-        })
-        .collect::<Vec<_>>();
-
-    let tx = Transaction {
-        version: 1,
-        lock_time: LockTime::ZERO,
-        input: inputs, // pickup the utxos from step2.1, and convert them to txins
-        output: outputs,
-    };
+    // with PSBT_DIR the unsigned psbt is written there instead of signing
+    if let Ok(dir) = std::env::var("PSBT_DIR") {
+        let path = write_unsigned_psbt(dir, &psbt)?;
+        println!("unsigned psbt: {}", path.display());
+        return Ok(());
+    }
 
-    let signed_tx = ag.sign_tx(&tx, index, utxo.value)?;
+    ag.sign_psbt(&mut psbt)?;
+    finalize_psbt(&mut psbt)?;
+    let signed_tx = extract_tx(psbt)?;
     println!("signed tx: {:?}", signed_tx);
 
     let signed_hex = bitcoin::consensus::serialize(&signed_tx)
@@ -0,0 +1,419 @@
+// picks the utxos to spend for a target amount. every utxo counts at its effective value,
+// what it's worth minus the fee of spending it at the fee rate, so a utxo costing more
+// than it brings is never picked.
+
+use crate::error::Error;
+use crate::tx_builder::ChangePolicy;
+use crate::weight::{fee_for_weight, InputType};
+use bitcoin::secp256k1::rand::{seq::SliceRandom, thread_rng};
+use bitcoin::{FeeRate, OutPoint, Script, Transaction, TxOut, Weight};
+
+// the steps branch and bound searches before giving up
+const BNB_MAX_TRIES: usize = 100_000;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WeightedUtxo {
+    pub outpoint: OutPoint,
+    pub txout: TxOut,
+    // the confirmation height, 0 when unconfirmed (as electrum reports it)
+    pub height: u32,
+    // the tx of `outpoint`, legacy inputs need it in the psbt
+    pub previous_tx: Option<Transaction>,
+    // how it's signed, guessed from the script pubkey when none
+    pub input_type: Option<InputType>,
+}
+
+impl WeightedUtxo {
+    pub fn new(outpoint: OutPoint, txout: TxOut, height: u32) -> Self {
+        Self {
+            outpoint,
+            txout,
+            height,
+            previous_tx: None,
+            input_type: None,
+        }
+    }
+
+    pub fn with_previous_tx(mut self, previous_tx: Transaction) -> Self {
+        self.previous_tx = Some(previous_tx);
+        self
+    }
+
+    pub fn with_input_type(mut self, input_type: InputType) -> Self {
+        self.input_type = Some(input_type);
+        self
+    }
+
+    // the value minus the fee of spending it, none when the input size can't be estimated
+    pub fn effective_value(&self, fee_rate: FeeRate) -> Option<i64> {
+        let weight = self
+            .input_type
+            .clone()
+            .or_else(|| InputType::from_script_pubkey(&self.txout.script_pubkey))?
+            .input_weight();
+        Some(self.txout.value as i64 - fee_for_weight(fee_rate, weight) as i64)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CoinSelection {
+    // the utxos closest above the target without needing change, `SingleRandomDraw` when
+    // there are none (or the change is required)
+    #[default]
+    BranchAndBound,
+    LargestFirst,
+    // by confirmation height, the unconfirmed ones last
+    OldestFirst,
+    // in random order until the target is reached
+    SingleRandomDraw,
+}
+
+#[derive(Clone, Debug)]
+pub struct Selection {
+    pub utxos: Vec<WeightedUtxo>,
+    // whether what's left over is worth a change output, it goes to the fee when not
+    pub change: bool,
+}
+
+// the fees a change output adds: its own, spending it later, and the least it can be worth
+struct ChangeCost {
+    output: u64,
+    spend: u64,
+    dust: u64,
+}
+
+impl ChangeCost {
+    fn new(script_pubkey: &Script, fee_rate: FeeRate) -> Self {
+        // value, script length and script
+        let output = Weight::from_non_witness_data_size(8 + 1 + script_pubkey.len() as u64);
+        let spend = InputType::from_script_pubkey(script_pubkey)
+            .map_or(Weight::ZERO, |input| input.input_weight());
+        Self {
+            output: fee_for_weight(fee_rate, output),
+            spend: fee_for_weight(fee_rate, spend),
+            dust: script_pubkey.dust_value().to_sat(),
+        }
+    }
+}
+
+impl CoinSelection {
+    // selects effective value from `candidates` for `target`, the outputs plus the fee of the
+    // tx without these inputs. a required change output must come out of it too, not dust.
+    pub fn select(
+        &self,
+        candidates: Vec<WeightedUtxo>,
+        target: u64,
+        fee_rate: FeeRate,
+        change: &ChangePolicy,
+    ) -> Result<Selection, Error> {
+        let mut candidates = candidates
+            .into_iter()
+            .map(|utxo| match utxo.effective_value(fee_rate) {
+                Some(value) => Ok((value, utxo)),
+                None => Err(Error::UnknownInputScript(utxo.outpoint)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        // uneconomic utxos would only add to the fee
+        candidates.retain(|(value, _)| *value > 0);
+        let candidates = candidates
+            .into_iter()
+            .map(|(value, utxo)| (value as u64, utxo))
+            .collect::<Vec<_>>();
+
+        let (cost, required) = match change {
+            ChangePolicy::NoChange => (None, false),
+            ChangePolicy::Change(script) => (Some(ChangeCost::new(script, fee_rate)), false),
+            ChangePolicy::RequiredChange(script) => (Some(ChangeCost::new(script, fee_rate)), true),
+        };
+        let minimum = match (&cost, required) {
+            (Some(cost), true) => target + cost.output + cost.dust,
+            _ => target,
+        };
+        let available = candidates.iter().map(|(value, _)| value).sum::<u64>();
+        if available < minimum {
+            return Err(Error::InsufficientFunds {
+                needed: minimum,
+                available,
+            });
+        }
+
+        let selected = match self {
+            CoinSelection::BranchAndBound if !required => {
+                // without change anything left over is lost, the least of it is the best
+                let upper = cost
+                    .as_ref()
+                    .map_or(u64::MAX, |cost| target + cost.output + cost.spend);
+                match branch_and_bound(&candidates, target, upper) {
+                    Some(indexes) => {
+                        let utxos = candidates
+                            .into_iter()
+                            .enumerate()
+                            .filter(|(index, _)| indexes.contains(index))
+                            .map(|(_, (_, utxo))| utxo)
+                            .collect();
+                        return Ok(Selection {
+                            utxos,
+                            change: false,
+                        });
+                    }
+                    None => single_random_draw(candidates, minimum),
+                }
+            }
+            CoinSelection::BranchAndBound | CoinSelection::SingleRandomDraw => {
+                single_random_draw(candidates, minimum)
+            }
+            CoinSelection::LargestFirst => {
+                let mut candidates = candidates;
+                candidates.sort_by(|(a, _), (b, _)| b.cmp(a));
+                accumulate(candidates, minimum)
+            }
+            CoinSelection::OldestFirst => {
+                let mut candidates = candidates;
+                candidates.sort_by_key(|(_, utxo)| (utxo.height == 0, utxo.height));
+                accumulate(candidates, minimum)
+            }
+        };
+
+        let excess = selected.iter().map(|(value, _)| value).sum::<u64>() - target;
+        Ok(Selection {
+            utxos: selected.into_iter().map(|(_, utxo)| utxo).collect(),
+            change: cost.is_some_and(|cost| required || excess >= cost.output + cost.dust),
+        })
+    }
+}
+
+// the candidates in order until they reach `minimum`
+fn accumulate(candidates: Vec<(u64, WeightedUtxo)>, minimum: u64) -> Vec<(u64, WeightedUtxo)> {
+    let mut total = 0;
+    candidates
+        .into_iter()
+        .take_while(|(value, _)| {
+            let reached = total >= minimum;
+            total += value;
+            !reached
+        })
+        .collect()
+}
+
+fn single_random_draw(
+    mut candidates: Vec<(u64, WeightedUtxo)>,
+    minimum: u64,
+) -> Vec<(u64, WeightedUtxo)> {
+    candidates.shuffle(&mut thread_rng());
+    accumulate(candidates, minimum)
+}
+
+// a depth first search, largest values first, for the indexes of `candidates` summing to
+// between `target` and `upper` with the least excess
+fn branch_and_bound(
+    candidates: &[(u64, WeightedUtxo)],
+    target: u64,
+    upper: u64,
+) -> Option<Vec<usize>> {
+    let mut order = (0..candidates.len()).collect::<Vec<_>>();
+    order.sort_by(|a, b| candidates[*b].0.cmp(&candidates[*a].0));
+    let values = order
+        .iter()
+        .map(|index| candidates[*index].0)
+        .collect::<Vec<_>>();
+
+    // whether each of the values so far is included
+    let mut included: Vec<bool> = vec![];
+    let mut current = 0;
+    let mut remaining = values.iter().sum::<u64>();
+    let mut best: Option<(u64, Vec<bool>)> = None;
+    for _ in 0..BNB_MAX_TRIES {
+        let backtrack = if current + remaining < target || current > upper {
+            true
+        } else if current >= target {
+            let excess = current - target;
+            if best.as_ref().is_none_or(|(best, _)| excess < *best) {
+                best = Some((excess, included.clone()));
+            }
+            if excess == 0 {
+                break;
+            }
+            true
+        } else {
+            false
+        };
+
+        if backtrack {
+            // back to the last included value, the excluded ones after it can be picked again
+            while let Some(false) = included.last() {
+                included.pop();
+                remaining += values[included.len()];
+            }
+            match included.last_mut() {
+                Some(last) => *last = false,
+                None => break,
+            }
+            current -= values[included.len() - 1];
+        } else {
+            // below the target with values left, so there is a next one
+            let next = values[included.len()];
+            current += next;
+            remaining -= next;
+            included.push(true);
+        }
+    }
+
+    best.map(|(_, included)| {
+        included
+            .into_iter()
+            .zip(order)
+            .filter_map(|(included, index)| included.then_some(index))
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coin_selection::{CoinSelection, WeightedUtxo};
+    use crate::error::Error;
+    use crate::tx_builder::ChangePolicy;
+    use bitcoin::hashes::Hash;
+    use bitcoin::{FeeRate, OutPoint, ScriptBuf, TxOut, Txid, WPubkeyHash};
+
+    fn p2wpkh() -> ScriptBuf {
+        ScriptBuf::new_v0_p2wpkh(&WPubkeyHash::all_zeros())
+    }
+
+    // p2wpkh utxos of `values`, confirmed at heights 1, 2, ...
+    fn utxos(values: &[u64]) -> Vec<WeightedUtxo> {
+        values
+            .iter()
+            .enumerate()
+            .map(|(index, value)| {
+                WeightedUtxo::new(
+                    OutPoint::new(Txid::all_zeros(), index as u32),
+                    TxOut {
+                        value: *value,
+                        script_pubkey: p2wpkh(),
+                    },
+                    index as u32 + 1,
+                )
+            })
+            .collect()
+    }
+
+    fn values(utxos: &[WeightedUtxo]) -> Vec<u64> {
+        let mut values = utxos
+            .iter()
+            .map(|utxo| utxo.txout.value)
+            .collect::<Vec<_>>();
+        values.sort();
+        values
+    }
+
+    #[test]
+    fn test_branch_and_bound_exact_match() {
+        // without fees the effective values are the values, and only an exact match has no
+        // change left over
+        let selection = CoinSelection::BranchAndBound
+            .select(
+                utxos(&[10_000, 20_000, 35_000, 50_000]),
+                55_000,
+                FeeRate::ZERO,
+                &ChangePolicy::Change(p2wpkh()),
+            )
+            .unwrap();
+        assert_eq!(values(&selection.utxos), vec![20_000, 35_000]);
+        assert!(!selection.change);
+    }
+
+    #[test]
+    fn test_branch_and_bound_fallback() {
+        // no subset adds up to 56_000, the closest is 4_000 over
+        let selection = CoinSelection::BranchAndBound
+            .select(
+                utxos(&[10_000, 20_000, 35_000, 50_000]),
+                56_000,
+                FeeRate::ZERO,
+                &ChangePolicy::Change(p2wpkh()),
+            )
+            .unwrap();
+        assert!(values(&selection.utxos).iter().sum::<u64>() >= 60_000);
+        assert!(selection.change);
+    }
+
+    #[test]
+    fn test_change_or_dust() {
+        let dust = p2wpkh().dust_value().to_sat();
+        for (target, change) in [(50_000 - dust + 1, false), (50_000 - dust, true)] {
+            let selection = CoinSelection::LargestFirst
+                .select(
+                    utxos(&[50_000]),
+                    target,
+                    FeeRate::ZERO,
+                    &ChangePolicy::Change(p2wpkh()),
+                )
+                .unwrap();
+            assert_eq!(selection.change, change);
+        }
+
+        // required change can't be dust
+        assert!(matches!(
+            CoinSelection::LargestFirst.select(
+                utxos(&[50_000]),
+                50_000 - dust + 1,
+                FeeRate::ZERO,
+                &ChangePolicy::RequiredChange(p2wpkh()),
+            ),
+            Err(Error::InsufficientFunds { .. })
+        ));
+    }
+
+    #[test]
+    fn test_uneconomic_utxos_skipped() {
+        // 68 vbytes at 10 sat/vb is more than the first utxo is worth
+        let fee_rate = FeeRate::from_sat_per_vb(10).unwrap();
+        let candidates = utxos(&[500, 100_000]);
+        assert!(candidates[0].effective_value(fee_rate).unwrap() < 0);
+
+        let selection = CoinSelection::OldestFirst
+            .select(candidates.clone(), 1_000, fee_rate, &ChangePolicy::NoChange)
+            .unwrap();
+        assert_eq!(values(&selection.utxos), vec![100_000]);
+
+        assert_eq!(
+            CoinSelection::OldestFirst
+                .select(
+                    candidates[..1].to_vec(),
+                    1,
+                    fee_rate,
+                    &ChangePolicy::NoChange
+                )
+                .err(),
+            Some(Error::InsufficientFunds {
+                needed: 1,
+                available: 0
+            })
+        );
+    }
+
+    #[test]
+    fn test_insufficient_funds() {
+        for algorithm in [
+            CoinSelection::BranchAndBound,
+            CoinSelection::LargestFirst,
+            CoinSelection::OldestFirst,
+            CoinSelection::SingleRandomDraw,
+        ] {
+            assert_eq!(
+                algorithm
+                    .select(
+                        utxos(&[10_000, 20_000]),
+                        40_000,
+                        FeeRate::ZERO,
+                        &ChangePolicy::NoChange
+                    )
+                    .err(),
+                Some(Error::InsufficientFunds {
+                    needed: 40_000,
+                    available: 30_000
+                })
+            );
+        }
+    }
+}
@@ -0,0 +1,515 @@
+// single key output descriptors (BIP380-386) for the BIP44/49/84/86 accounts,
+// in the form Bitcoin Core and Sparrow import and export:
+//   tr([73c5da0a/86h/0h/0h]xpub.../0/*)#checksum
+
+use crate::key_pair::{AccountGenerator, KeychainKind, Purpose};
+use crate::secp::secp;
+use crate::watch_only::WatchOnlyGenerator;
+use anyhow::Error;
+use bitcoin::bip32::{ChildNumber, DerivationPath, ExtendedPrivKey, ExtendedPubKey, Fingerprint};
+use std::str::FromStr;
+
+const INPUT_CHARSET: &str = "0123456789()[],'/*abcdefgh@:$%{}IJKLMNOPQRSTUVWXYZ&+-.;<=>?!^_|~ijklmnopqrstuvwxyzABCDEFGH`#\"\\ ";
+const CHECKSUM_CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+fn poly_mod(mut c: u64, val: u64) -> u64 {
+    let c0 = c >> 35;
+    c = ((c & 0x7ffffffff) << 5) ^ val;
+    if c0 & 1 > 0 {
+        c ^= 0xf5dee51989
+    };
+    if c0 & 2 > 0 {
+        c ^= 0xa9fdca3312
+    };
+    if c0 & 4 > 0 {
+        c ^= 0x1bab10e32d
+    };
+    if c0 & 8 > 0 {
+        c ^= 0x3706b1677a
+    };
+    if c0 & 16 > 0 {
+        c ^= 0x644d626ffd
+    };
+    c
+}
+
+// the 8 characters after `#`, see BIP380
+pub fn descriptor_checksum(desc: &str) -> anyhow::Result<String> {
+    let mut c = 1;
+    let mut cls = 0;
+    let mut clscount = 0;
+
+    for ch in desc.chars() {
+        let pos = INPUT_CHARSET
+            .find(ch)
+            .ok_or_else(|| Error::msg(format!("invalid character in descriptor: {ch:?}")))?
+            as u64;
+        c = poly_mod(c, pos & 31);
+        cls = cls * 3 + (pos >> 5);
+        clscount += 1;
+        if clscount == 3 {
+            c = poly_mod(c, cls);
+            cls = 0;
+            clscount = 0;
+        }
+    }
+    if clscount > 0 {
+        c = poly_mod(c, cls);
+    }
+    (0..8).for_each(|_| c = poly_mod(c, 0));
+    c ^= 1;
+
+    Ok((0..8)
+        .map(|j| CHECKSUM_CHARSET[((c >> (5 * (7 - j))) & 31) as usize] as char)
+        .collect())
+}
+
+pub fn add_checksum(desc: &str) -> anyhow::Result<String> {
+    Ok(format!("{desc}#{}", descriptor_checksum(desc)?))
+}
+
+// returns the descriptor without the checksum, the checksum is optional
+pub fn verify_checksum(desc: &str) -> anyhow::Result<&str> {
+    match desc.split_once('#') {
+        Some((body, checksum)) => {
+            let expected = descriptor_checksum(body)?;
+            if expected != checksum {
+                return Err(Error::msg(format!(
+                    "invalid descriptor checksum {checksum}, expected {expected}"
+                )));
+            }
+            Ok(body)
+        }
+        None => Ok(desc),
+    }
+}
+
+fn wrap(purpose: Purpose, key: &str) -> String {
+    match purpose {
+        Purpose::Legacy => format!("pkh({key})"),
+        Purpose::NestedSegwit => format!("sh(wpkh({key}))"),
+        Purpose::NativeSegwit => format!("wpkh({key})"),
+        Purpose::Taproot => format!("tr({key})"),
+    }
+}
+
+// [fingerprint/path] with `h` for hardened steps
+pub fn key_origin(fingerprint: Fingerprint, path: &DerivationPath) -> String {
+    path.into_iter()
+        .fold(format!("[{fingerprint}"), |acc, child| match child {
+            ChildNumber::Normal { index } => format!("{acc}/{index}"),
+            ChildNumber::Hardened { index } => format!("{acc}/{index}h"),
+        })
+        + "]"
+}
+
+pub fn descriptor(
+    purpose: Purpose,
+    fingerprint: Fingerprint,
+    account_path: &DerivationPath,
+    account_key: &str,
+    keychain: KeychainKind,
+) -> anyhow::Result<String> {
+    let key = format!(
+        "{}{account_key}/{}/*",
+        key_origin(fingerprint, account_path),
+        keychain.index()
+    );
+
+    add_checksum(&wrap(purpose, &key))
+}
+
+#[derive(Clone)]
+pub enum DescriptorKey {
+    Public(ExtendedPubKey),
+    Private(ExtendedPrivKey),
+}
+
+impl std::fmt::Debug for DescriptorKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DescriptorKey::Public(xpub) => f.debug_tuple("Public").field(xpub).finish(),
+            DescriptorKey::Private(_) => f.debug_tuple("Private").field(&"<redacted>").finish(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ParsedDescriptor {
+    pub purpose: Purpose,
+    pub origin: Option<(Fingerprint, DerivationPath)>,
+    pub key: DescriptorKey,
+    // steps between the key and the keychain, e.g. 86h/0h/0h after a master xprv
+    pub path: DerivationPath,
+    // `/0/*`, `/1/*` or both for `/<0;1>/*`
+    pub keychains: Vec<KeychainKind>,
+}
+
+impl ParsedDescriptor {
+    // the key origin of the account key, i.e. the origin plus `path`
+    pub fn account_origin(&self) -> anyhow::Result<(Fingerprint, DerivationPath)> {
+        let secp = secp();
+        let (fingerprint, origin_path) = match &self.origin {
+            Some(origin) => origin.clone(),
+            None => {
+                let fingerprint = match &self.key {
+                    DescriptorKey::Public(xpub) => xpub.fingerprint(),
+                    DescriptorKey::Private(xprv) => xprv.fingerprint(secp),
+                };
+                (fingerprint, DerivationPath::master())
+            }
+        };
+
+        Ok((fingerprint, origin_path.extend(&self.path)))
+    }
+}
+
+fn parse_path(steps: &[&str]) -> anyhow::Result<DerivationPath> {
+    let children = steps
+        .iter()
+        .map(|step| {
+            let (index, hardened) = match step.strip_suffix(['h', 'H', '\'']) {
+                Some(index) => (index, true),
+                None => (*step, false),
+            };
+            let index = index.parse::<u32>()?;
+            Ok(match hardened {
+                true => ChildNumber::from_hardened_idx(index)?,
+                false => ChildNumber::from_normal_idx(index)?,
+            })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    Ok(DerivationPath::from(children))
+}
+
+// splits `[fingerprint/path]key` into the origin and the rest, the origin is optional
+pub(crate) fn parse_key_origin(
+    key: &str,
+) -> anyhow::Result<(Option<(Fingerprint, DerivationPath)>, &str)> {
+    match key.strip_prefix('[') {
+        Some(rest) => {
+            let (origin, key) = rest
+                .split_once(']')
+                .ok_or_else(|| Error::msg("unclosed key origin"))?;
+            let mut steps = origin.split('/');
+            let fingerprint = steps
+                .next()
+                .filter(|fingerprint| !fingerprint.is_empty())
+                .ok_or_else(|| Error::msg("key origin without a fingerprint"))?;
+            let fingerprint = Fingerprint::from_str(fingerprint)?;
+            let path = parse_path(&steps.collect::<Vec<_>>())?;
+            Ok((Some((fingerprint, path)), key))
+        }
+        None => Ok((None, key)),
+    }
+}
+
+pub fn parse_descriptor(desc: &str) -> anyhow::Result<ParsedDescriptor> {
+    let body = verify_checksum(desc.trim())?;
+
+    let (purpose, key) = [
+        ("sh(wpkh(", "))", Purpose::NestedSegwit),
+        ("wpkh(", ")", Purpose::NativeSegwit),
+        ("pkh(", ")", Purpose::Legacy),
+        ("tr(", ")", Purpose::Taproot),
+    ]
+    .into_iter()
+    .find_map(|(prefix, suffix, purpose)| {
+        body.strip_prefix(prefix)
+            .and_then(|rest| rest.strip_suffix(suffix))
+            .map(|key| (purpose, key))
+    })
+    .ok_or_else(|| Error::msg(format!("unsupported descriptor: {body}")))?;
+
+    if key.contains([',', '(']) {
+        return Err(Error::msg(
+            "only single key descriptors are supported, without taproot script trees",
+        ));
+    }
+
+    let (origin, key) = parse_key_origin(key)?;
+
+    let mut steps = key.split('/').collect::<Vec<_>>();
+    let xkey = steps.remove(0);
+    let keychains = match steps.as_slice() {
+        [.., "0", "*"] => vec![KeychainKind::External],
+        [.., "1", "*"] => vec![KeychainKind::Internal],
+        [.., "<0;1>", "*"] => KeychainKind::ALL.to_vec(),
+        _ => {
+            return Err(Error::msg(
+                "the key must end with /0/*, /1/* or /<0;1>/* to derive addresses",
+            ))
+        }
+    };
+    let path = parse_path(&steps[..steps.len() - 2])?;
+
+    let key = match ExtendedPubKey::from_str(xkey) {
+        Ok(xpub) => DescriptorKey::Public(xpub),
+        Err(_) => DescriptorKey::Private(ExtendedPrivKey::from_str(xkey)?),
+    };
+
+    Ok(ParsedDescriptor {
+        purpose,
+        origin,
+        key,
+        path,
+        keychains,
+    })
+}
+
+impl<'a> AccountGenerator<'a> {
+    pub fn descriptor(&self, keychain: KeychainKind) -> anyhow::Result<String> {
+        descriptor(
+            self.purpose(),
+            self.fingerprint(),
+            &self.account_derivation_path()?,
+            &self.account_xpub()?.to_string(),
+            keychain,
+        )
+    }
+
+    // the same with the account xprv, keep it as secret as the mnemonic
+    pub fn private_descriptor(&self, keychain: KeychainKind) -> anyhow::Result<String> {
+        descriptor(
+            self.purpose(),
+            self.fingerprint(),
+            &self.account_derivation_path()?,
+            &self.account_xprv()?.to_string(),
+            keychain,
+        )
+    }
+
+    // an xprv descriptor, e.g. from bitcoin core's `listdescriptors true`
+    pub fn from_descriptor(desc: &str) -> anyhow::Result<AccountGenerator<'static>> {
+        let parsed = parse_descriptor(desc)?;
+        let xprv = match parsed.key {
+            DescriptorKey::Private(xprv) => xprv,
+            DescriptorKey::Public(_) => {
+                return Err(Error::msg(
+                    "not a private descriptor, use WatchOnlyGenerator::from_descriptor",
+                ))
+            }
+        };
+
+        // addresses derive from the key path as written, below a bare master key that's
+        // m/0/* and not an account, which only the watch-only generator does
+        let secp = secp();
+        let (fingerprint, account_path) = parsed.account_origin()?;
+        if !matches!(
+            account_path.as_ref(),
+            [
+                ChildNumber::Hardened { .. },
+                ChildNumber::Hardened { .. },
+                ChildNumber::Hardened { .. }
+            ]
+        ) {
+            return Err(Error::msg(format!(
+                "the descriptor key at {account_path} is not a purpose'/coin'/account' account key"
+            )));
+        }
+        let account_xprv = xprv.derive_priv(secp, &parsed.path)?;
+        let ag = AccountGenerator::from_xprv(account_xprv, Some((fingerprint, account_path)))?;
+
+        if ag.purpose() != parsed.purpose {
+            return Err(Error::msg(format!(
+                "{} descriptor with a BIP{} path",
+                parsed.purpose.address_type(),
+                ag.purpose()
+            )));
+        }
+
+        Ok(ag)
+    }
+}
+
+impl WatchOnlyGenerator {
+    pub fn descriptor(&self, keychain: KeychainKind) -> anyhow::Result<String> {
+        descriptor(
+            self.purpose(),
+            self.fingerprint(),
+            self.account_derivation_path(),
+            &self.account_xpub().to_string(),
+            keychain,
+        )
+    }
+
+    pub fn from_descriptor(desc: &str) -> anyhow::Result<Self> {
+        let parsed = parse_descriptor(desc)?;
+
+        let secp = secp();
+        let account_xpub = match &parsed.key {
+            DescriptorKey::Public(xpub) => xpub.derive_pub(secp, &parsed.path)?,
+            DescriptorKey::Private(xprv) => {
+                ExtendedPubKey::from_priv(secp, &xprv.derive_priv(secp, &parsed.path)?)
+            }
+        };
+        let (fingerprint, account_path) = parsed.account_origin()?;
+
+        Ok(WatchOnlyGenerator::new(account_xpub, parsed.purpose)
+            .with_origin(fingerprint, account_path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::descriptor::{
+        add_checksum, descriptor, descriptor_checksum, parse_descriptor, verify_checksum,
+        DescriptorKey,
+    };
+    use crate::error::Error as BtcError;
+    use crate::key_pair::{AccountGenerator, KeychainKind, Purpose};
+    use crate::watch_only::WatchOnlyGenerator;
+    use bip39::Mnemonic;
+    use bitcoin::bip32::ExtendedPrivKey;
+    use bitcoin::Network;
+
+    const MNEMONIC: &str =
+        "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+
+    // the checksum test vectors of BIP380
+    #[test]
+    fn test_checksum_vectors() {
+        assert_eq!(descriptor_checksum("raw(deadbeef)").unwrap(), "89f8spxm");
+        assert_eq!(
+            verify_checksum("raw(deadbeef)#89f8spxm").unwrap(),
+            "raw(deadbeef)"
+        );
+        // the checksum is optional
+        assert_eq!(verify_checksum("raw(deadbeef)").unwrap(), "raw(deadbeef)");
+
+        for invalid in [
+            // missing checksum
+            "raw(deadbeef)#",
+            // too long checksum
+            "raw(deadbeef)#89f8spxmx",
+            // too short checksum
+            "raw(deadbeef)#89f8spx",
+            // error in the payload
+            "raw(deedbeef)#89f8spxm",
+            // error in the checksum
+            "raw(deadbeef)##9f8spxm",
+            // invalid character in the payload
+            "raw(Ü)#00000000",
+        ] {
+            assert!(verify_checksum(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for purpose in Purpose::ALL {
+            let ag = AccountGenerator::new(MNEMONIC, Network::Bitcoin)
+                .unwrap()
+                .with_purpose(purpose);
+            for keychain in KeychainKind::ALL {
+                let public = ag.descriptor(keychain).unwrap();
+                let parsed = parse_descriptor(&public).unwrap();
+                assert_eq!(parsed.purpose, purpose);
+                assert_eq!(parsed.keychains, vec![keychain]);
+                let (fingerprint, path) = parsed.account_origin().unwrap();
+                assert_eq!(fingerprint, ag.fingerprint());
+                assert_eq!(path, ag.account_derivation_path().unwrap());
+                let key = match parsed.key {
+                    DescriptorKey::Public(xpub) => xpub.to_string(),
+                    DescriptorKey::Private(_) => panic!("{public} has a public key"),
+                };
+                assert_eq!(
+                    descriptor(purpose, fingerprint, &path, &key, keychain).unwrap(),
+                    public
+                );
+                assert_eq!(
+                    WatchOnlyGenerator::from_descriptor(&public)
+                        .unwrap()
+                        .descriptor(keychain)
+                        .unwrap(),
+                    public
+                );
+
+                let private = ag.private_descriptor(keychain).unwrap();
+                let imported = AccountGenerator::from_descriptor(&private).unwrap();
+                assert_eq!(imported.purpose(), purpose);
+                assert_eq!(imported.private_descriptor(keychain).unwrap(), private);
+                assert_eq!(imported.descriptor(keychain).unwrap(), public);
+            }
+        }
+    }
+
+    #[test]
+    fn test_invalid_origins() {
+        let ag = AccountGenerator::new(MNEMONIC, Network::Bitcoin)
+            .unwrap()
+            .with_purpose(Purpose::NativeSegwit);
+        let xpub = ag.account_xpub().unwrap();
+        let xprv = ag.account_xprv().unwrap();
+
+        // the fingerprint is not optional
+        assert!(parse_descriptor(&format!("wpkh([/84h/0h/0h]{xpub}/0/*)")).is_err());
+        assert!(parse_descriptor(&format!("wpkh([]{xpub}/0/*)")).is_err());
+        assert!(parse_descriptor(&format!("wpkh([73c5da0a/84h/0h/0h]{xpub}/0/*)")).is_ok());
+
+        // an account key of no standard account path
+        for origin in ["84h/0h", "84h/0h/0h/0", "84h/0/0h"] {
+            let desc = add_checksum(&format!("wpkh([73c5da0a/{origin}]{xprv}/0/*)")).unwrap();
+            assert!(AccountGenerator::from_descriptor(&desc).is_err(), "{desc}");
+        }
+
+        // a bitcoin account path with a testnet key
+        let tprv = AccountGenerator::new(MNEMONIC, Network::Testnet)
+            .unwrap()
+            .with_purpose(Purpose::NativeSegwit)
+            .account_xprv()
+            .unwrap();
+        let desc = add_checksum(&format!("wpkh([73c5da0a/84h/0h/0h]{tprv}/0/*)")).unwrap();
+        let e = AccountGenerator::from_descriptor(&desc).unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<BtcError>(),
+            Some(&BtcError::NetworkMismatch {
+                expected: Network::Bitcoin,
+                found: Network::Testnet
+            })
+        );
+    }
+
+    // the private and the watch-only import of a descriptor are the same wallet
+    #[test]
+    fn test_private_and_watch_only_import() {
+        let seed = Mnemonic::parse(MNEMONIC).unwrap().to_seed("");
+        let master = ExtendedPrivKey::new_master(Network::Bitcoin, &seed).unwrap();
+        let account = AccountGenerator::new(MNEMONIC, Network::Bitcoin)
+            .unwrap()
+            .private_descriptor(KeychainKind::External)
+            .unwrap();
+        for desc in [
+            account,
+            add_checksum(&format!("tr({master}/86h/0h/0h/0/*)")).unwrap(),
+        ] {
+            let ag = AccountGenerator::from_descriptor(&desc).unwrap();
+            let wo = WatchOnlyGenerator::from_descriptor(&desc).unwrap();
+            for index in 0..5 {
+                assert_eq!(
+                    ag.get_account(KeychainKind::External, index)
+                        .unwrap()
+                        .address()
+                        .unwrap(),
+                    wo.get_account(KeychainKind::External, index)
+                        .unwrap()
+                        .address()
+                        .unwrap()
+                );
+            }
+        }
+
+        // m/0/* below a bare master key is no account, it can only be watched
+        let desc = add_checksum(&format!("tr({master}/0/*)")).unwrap();
+        assert!(AccountGenerator::from_descriptor(&desc).is_err());
+        let wo = WatchOnlyGenerator::from_descriptor(&desc).unwrap();
+        assert_eq!(
+            wo.get_account(KeychainKind::External, 0)
+                .unwrap()
+                .derivation_path()
+                .to_string(),
+            "m/0/0"
+        );
+    }
+}
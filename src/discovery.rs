@@ -0,0 +1,287 @@
+// find the used addresses of a wallet by walking each chain until `gap_limit`
+// consecutive script pubkeys have no history, the same as BIP44 account discovery.
+
+use crate::key_pair::{AccountGenerator, KeychainKind, Purpose};
+use crate::watch_only::WatchOnlyGenerator;
+use bitcoin::{Address, Network, Script, ScriptBuf};
+use electrum_client::ElectrumApi;
+
+pub const DEFAULT_GAP_LIMIT: u32 = 20;
+
+// anything that derives the script pubkeys of an account
+pub trait DeriveScriptPubkey {
+    fn network(&self) -> Network;
+
+    fn purpose(&self) -> Purpose;
+
+    fn script_pubkey_at(&self, keychain: KeychainKind, index: u32) -> anyhow::Result<ScriptBuf>;
+}
+
+impl<'a> DeriveScriptPubkey for AccountGenerator<'a> {
+    fn network(&self) -> Network {
+        *self.network()
+    }
+
+    fn purpose(&self) -> Purpose {
+        self.purpose()
+    }
+
+    fn script_pubkey_at(&self, keychain: KeychainKind, index: u32) -> anyhow::Result<ScriptBuf> {
+        Ok(self.get_account(keychain, index)?.script_pubkey())
+    }
+}
+
+impl DeriveScriptPubkey for WatchOnlyGenerator {
+    fn network(&self) -> Network {
+        *self.network()
+    }
+
+    fn purpose(&self) -> Purpose {
+        self.purpose()
+    }
+
+    fn script_pubkey_at(&self, keychain: KeychainKind, index: u32) -> anyhow::Result<ScriptBuf> {
+        Ok(self.get_account(keychain, index)?.script_pubkey())
+    }
+}
+
+// anything that knows the history of a script pubkey, e.g. an electrum server
+pub trait ScriptHistory {
+    // the number of txs paying to or spending from `script_pubkey`
+    fn tx_count(&self, script_pubkey: &Script) -> anyhow::Result<usize>;
+
+    // the confirmed and unconfirmed balance of `script_pubkey`
+    fn balance(&self, script_pubkey: &Script) -> anyhow::Result<(u64, i64)>;
+}
+
+impl<E: ElectrumApi> ScriptHistory for E {
+    fn tx_count(&self, script_pubkey: &Script) -> anyhow::Result<usize> {
+        Ok(self.script_get_history(script_pubkey)?.len())
+    }
+
+    fn balance(&self, script_pubkey: &Script) -> anyhow::Result<(u64, i64)> {
+        let balance = self.script_get_balance(script_pubkey)?;
+        Ok((balance.confirmed, balance.unconfirmed))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct UsedScriptPubkey {
+    pub index: u32,
+    pub address: Address,
+    pub tx_count: usize,
+    pub confirmed: u64,
+    pub unconfirmed: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct ChainDiscovery {
+    pub purpose: Purpose,
+    pub keychain: KeychainKind,
+    pub used: Vec<UsedScriptPubkey>,
+    // the first index after the last used one
+    pub next_unused: u32,
+}
+
+impl ChainDiscovery {
+    pub fn confirmed(&self) -> u64 {
+        self.used.iter().map(|used| used.confirmed).sum()
+    }
+
+    pub fn unconfirmed(&self) -> i64 {
+        self.used.iter().map(|used| used.unconfirmed).sum()
+    }
+
+    pub fn is_used(&self) -> bool {
+        !self.used.is_empty()
+    }
+}
+
+pub fn discover_chain<H: ScriptHistory, D: DeriveScriptPubkey>(
+    client: &H,
+    generator: &D,
+    keychain: KeychainKind,
+    gap_limit: u32,
+) -> anyhow::Result<ChainDiscovery> {
+    let mut used = vec![];
+    let mut next_unused = 0;
+
+    let mut index = 0;
+    while index < next_unused + gap_limit {
+        let script_pubkey = generator.script_pubkey_at(keychain, index)?;
+        let tx_count = client.tx_count(&script_pubkey)?;
+
+        if tx_count > 0 {
+            let (confirmed, unconfirmed) = client.balance(&script_pubkey)?;
+            used.push(UsedScriptPubkey {
+                index,
+                address: Address::from_script(&script_pubkey, generator.network())?,
+                tx_count,
+                confirmed,
+                unconfirmed,
+            });
+            next_unused = index + 1;
+        }
+
+        index += 1;
+    }
+
+    Ok(ChainDiscovery {
+        purpose: generator.purpose(),
+        keychain,
+        used,
+        next_unused,
+    })
+}
+
+// both chains of every purpose, for the account the generator is set to
+pub fn discover<H: ScriptHistory>(
+    client: &H,
+    ag: &AccountGenerator,
+    purposes: &[Purpose],
+    gap_limit: u32,
+) -> anyhow::Result<Vec<ChainDiscovery>> {
+    let mut result = vec![];
+
+    for purpose in purposes {
+        let ag = ag.clone().with_purpose(*purpose);
+        for keychain in KeychainKind::ALL {
+            result.push(discover_chain(client, &ag, keychain, gap_limit)?);
+        }
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::discovery::{
+        discover, discover_chain, ChainDiscovery, DeriveScriptPubkey, ScriptHistory,
+    };
+    use crate::key_pair::{AccountGenerator, KeychainKind, Purpose};
+    use bitcoin::{Network, Script, ScriptBuf};
+    use std::cell::Cell;
+    use std::collections::HashMap;
+
+    const MNEMONIC: &str =
+        "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+
+    // script pubkey -> (tx count, confirmed balance), counting the lookups
+    #[derive(Default)]
+    struct History {
+        scripts: HashMap<ScriptBuf, (usize, u64)>,
+        lookups: Cell<usize>,
+    }
+
+    impl History {
+        fn with_used(
+            mut self,
+            ag: &AccountGenerator,
+            keychain: KeychainKind,
+            indexes: &[u32],
+        ) -> Self {
+            for index in indexes {
+                let script_pubkey = ag.script_pubkey_at(keychain, *index).unwrap();
+                self.scripts
+                    .insert(script_pubkey, (2, 1_000 * (*index as u64 + 1)));
+            }
+            self
+        }
+    }
+
+    impl ScriptHistory for History {
+        fn tx_count(&self, script_pubkey: &Script) -> anyhow::Result<usize> {
+            self.lookups.set(self.lookups.get() + 1);
+            Ok(self
+                .scripts
+                .get(script_pubkey)
+                .map_or(0, |(count, _)| *count))
+        }
+
+        fn balance(&self, script_pubkey: &Script) -> anyhow::Result<(u64, i64)> {
+            Ok((
+                self.scripts
+                    .get(script_pubkey)
+                    .map_or(0, |(_, value)| *value),
+                0,
+            ))
+        }
+    }
+
+    fn indexes(chain: &ChainDiscovery) -> Vec<u32> {
+        chain.used.iter().map(|used| used.index).collect()
+    }
+
+    #[test]
+    fn test_discover_chain() {
+        let ag = AccountGenerator::new(MNEMONIC, Network::Testnet)
+            .unwrap()
+            .with_purpose(Purpose::NativeSegwit);
+        let history = History::default()
+            .with_used(&ag, KeychainKind::External, &[0, 3, 25])
+            .with_used(&ag, KeychainKind::Internal, &[1]);
+
+        // 4 to 23 are the 20 unused after 3, 25 is past the gap
+        let chain = discover_chain(&history, &ag, KeychainKind::External, 20).unwrap();
+        assert_eq!(indexes(&chain), vec![0, 3]);
+        assert_eq!(chain.next_unused, 4);
+        assert_eq!(history.lookups.get(), 24);
+        assert_eq!(chain.confirmed(), 1_000 + 4_000);
+        assert_eq!(
+            chain.used[1].address,
+            ag.get_account(KeychainKind::External, 3)
+                .unwrap()
+                .address()
+                .unwrap()
+        );
+
+        // a larger gap finds it
+        let chain = discover_chain(&history, &ag, KeychainKind::External, 22).unwrap();
+        assert_eq!(indexes(&chain), vec![0, 3, 25]);
+        assert_eq!(chain.next_unused, 26);
+
+        history.lookups.set(0);
+        let chain = discover_chain(&history, &ag, KeychainKind::Internal, 20).unwrap();
+        assert_eq!(indexes(&chain), vec![1]);
+        assert_eq!(chain.next_unused, 2);
+        assert_eq!(history.lookups.get(), 22);
+    }
+
+    #[test]
+    fn test_discover() {
+        let ag = AccountGenerator::new(MNEMONIC, Network::Testnet).unwrap();
+        let segwit = ag.clone().with_purpose(Purpose::NativeSegwit);
+        let history = History::default()
+            .with_used(&segwit, KeychainKind::External, &[0, 3, 25])
+            .with_used(&segwit, KeychainKind::Internal, &[1]);
+
+        let chains = discover(
+            &history,
+            &ag,
+            &[Purpose::NativeSegwit, Purpose::Taproot],
+            20,
+        )
+        .unwrap();
+        let found = chains
+            .iter()
+            .map(|chain| {
+                (
+                    chain.purpose,
+                    chain.keychain,
+                    indexes(chain),
+                    chain.next_unused,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            found,
+            vec![
+                (Purpose::NativeSegwit, KeychainKind::External, vec![0, 3], 4),
+                (Purpose::NativeSegwit, KeychainKind::Internal, vec![1], 2),
+                (Purpose::Taproot, KeychainKind::External, vec![], 0),
+                (Purpose::Taproot, KeychainKind::Internal, vec![], 0),
+            ]
+        );
+        assert!(!chains[2].is_used());
+    }
+}
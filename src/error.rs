@@ -0,0 +1,78 @@
+// the errors a caller may want to act on, e.g. wait for more funds or skip a utxo. they're
+// also returned through `anyhow::Result`, `downcast_ref::<Error>()` tells them apart.
+
+use bitcoin::bip32::DerivationPath;
+use bitcoin::{Network, OutPoint};
+use std::fmt::{Display, Formatter};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    NoInputs,
+    NoOutputs,
+    InsufficientFunds {
+        needed: u64,
+        available: u64,
+    },
+    // an output worth less than it costs to spend it, nodes don't relay it
+    DustOutput {
+        index: usize,
+        value: u64,
+        dust: u64,
+    },
+    OpReturnTooLarge(usize),
+    // the size of the signed input can't be estimated
+    UnknownInputScript(OutPoint),
+    // a legacy input without the transaction it spends, see `psbt::update_input`
+    MissingPreviousTx(OutPoint),
+    NoSuchOutput(OutPoint),
+    // `path` is not below the key at `origin`
+    InvalidDerivation {
+        path: DerivationPath,
+        origin: DerivationPath,
+    },
+    SigningFailed {
+        input: usize,
+        reason: String,
+    },
+    NetworkMismatch {
+        expected: Network,
+        found: Network,
+    },
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::NoInputs => write!(f, "no inputs"),
+            Error::NoOutputs => write!(f, "no outputs"),
+            Error::InsufficientFunds { needed, available } => write!(
+                f,
+                "insufficient funds: {needed} sats needed, {available} available"
+            ),
+            Error::DustOutput { index, value, dust } => {
+                write!(f, "output {index} of {value} sats is dust (below {dust})")
+            }
+            Error::OpReturnTooLarge(size) => write!(
+                f,
+                "OP_RETURN script of {size} bytes, the limit is {}",
+                crate::tx_builder::MAX_OP_RETURN_SIZE
+            ),
+            Error::UnknownInputScript(outpoint) => {
+                write!(f, "can't estimate the size of input {outpoint}")
+            }
+            Error::MissingPreviousTx(outpoint) => {
+                write!(f, "legacy input {outpoint} needs its previous transaction")
+            }
+            Error::NoSuchOutput(outpoint) => write!(f, "{outpoint} doesn't exist"),
+            Error::InvalidDerivation { path, origin } => {
+                write!(f, "{path} is not derivable from the key at {origin}")
+            }
+            Error::SigningFailed { input, reason } => write!(f, "input {input}: {reason}"),
+            Error::NetworkMismatch { expected, found } => {
+                write!(f, "expected a {expected} key, got a {found} one")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
@@ -1,26 +1,141 @@
+use crate::error::Error as BtcError;
+use crate::mnemonic::validate_mnemonic;
+use crate::multisig::sorted_multisig_script;
+use crate::script_hash::{single_key_script, ScriptHashOutput};
+use crate::secp::secp;
+use crate::signer::Signer;
+use crate::taproot::{multi_a_leaf, TaprootScriptTree};
+use crate::watch_only::{encode_slip132, WatchOnlyGenerator};
 use anyhow::Error;
-use bip39::{Language, Mnemonic};
 use bitcoin::{
-    bip32::{DerivationPath, ExtendedPrivKey, Fingerprint},
+    bip32::{ChildNumber, DerivationPath, ExtendedPrivKey, ExtendedPubKey, Fingerprint},
+    ecdsa,
     hashes::{sha256, Hash},
     key::{KeyPair, TapTweak, XOnlyPublicKey},
-    opcodes::{all, All},
-    psbt::{Input, Psbt},
-    script::Builder as SBuilder,
-    secp256k1::{Secp256k1, SecretKey},
-    sighash::{self, SighashCache, TapSighashType},
-    taproot, Address, AddressType, Network, PrivateKey, PublicKey, ScriptBuf, Transaction, TxOut,
-    Witness,
+    secp256k1::SecretKey,
+    sighash::{self, EcdsaSighashType, SighashCache, TapSighashType},
+    taproot::{self, TapNodeHash},
+    Address, AddressType, Network, PrivateKey, PubkeyHash, PublicKey, Script, ScriptBuf,
+    Transaction, TxOut, WPubkeyHash,
 };
-use std::collections::BTreeMap;
+use rayon::prelude::*;
 use std::fmt::{Display, Formatter};
+use std::ops::Range;
 use std::str::FromStr;
+use std::sync::OnceLock;
+use zeroize::{Zeroize, Zeroizing};
+
+/// BIP43 purpose of a derivation path, it also decides which script an account receives on.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Purpose {
+    /// BIP44, p2pkh
+    Legacy,
+    /// BIP49, p2sh-p2wpkh
+    NestedSegwit,
+    /// BIP84, p2wpkh
+    NativeSegwit,
+    /// BIP86, p2tr key path
+    #[default]
+    Taproot,
+}
+
+impl Purpose {
+    pub const ALL: [Purpose; 4] = [
+        Purpose::Legacy,
+        Purpose::NestedSegwit,
+        Purpose::NativeSegwit,
+        Purpose::Taproot,
+    ];
+
+    pub fn number(&self) -> u32 {
+        match self {
+            Purpose::Legacy => 44,
+            Purpose::NestedSegwit => 49,
+            Purpose::NativeSegwit => 84,
+            Purpose::Taproot => 86,
+        }
+    }
+
+    pub fn address_type(&self) -> AddressType {
+        match self {
+            Purpose::Legacy => AddressType::P2pkh,
+            Purpose::NestedSegwit => AddressType::P2sh,
+            Purpose::NativeSegwit => AddressType::P2wpkh,
+            Purpose::Taproot => AddressType::P2tr,
+        }
+    }
+
+    pub fn script_pubkey(&self, public_key: &bitcoin::secp256k1::PublicKey) -> ScriptBuf {
+        match self {
+            Purpose::Legacy => ScriptBuf::new_p2pkh(&PubkeyHash::hash(&public_key.serialize())),
+            Purpose::NestedSegwit => {
+                ScriptBuf::new_v0_p2wpkh(&WPubkeyHash::hash(&public_key.serialize())).to_p2sh()
+            }
+            Purpose::NativeSegwit => {
+                ScriptBuf::new_v0_p2wpkh(&WPubkeyHash::hash(&public_key.serialize()))
+            }
+            Purpose::Taproot => {
+                let secp = secp();
+                ScriptBuf::new_v1_p2tr(secp, XOnlyPublicKey::from(*public_key), None)
+            }
+        }
+    }
+}
+
+impl FromStr for Purpose {
+    type Err = Error;
 
-#[derive(Clone, Debug)]
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "44" | "bip44" | "p2pkh" | "legacy" => Ok(Purpose::Legacy),
+            "49" | "bip49" | "p2sh-p2wpkh" | "p2shwpkh" => Ok(Purpose::NestedSegwit),
+            "84" | "bip84" | "p2wpkh" | "segwit" => Ok(Purpose::NativeSegwit),
+            "86" | "bip86" | "p2tr" | "taproot" => Ok(Purpose::Taproot),
+            _ => Err(Error::msg(format!("unknown purpose: {s}"))),
+        }
+    }
+}
+
+impl Display for Purpose {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.number())
+    }
+}
+
+/// receive (external) or change (internal) chain of an account, the `/0` and `/1` in the path
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum KeychainKind {
+    #[default]
+    External,
+    Internal,
+}
+
+impl KeychainKind {
+    pub const ALL: [KeychainKind; 2] = [KeychainKind::External, KeychainKind::Internal];
+
+    pub fn index(&self) -> u32 {
+        match self {
+            KeychainKind::External => 0,
+            KeychainKind::Internal => 1,
+        }
+    }
+}
+
+impl Display for KeychainKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeychainKind::External => write!(f, "receive"),
+            KeychainKind::Internal => write!(f, "change"),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Account {
     network: Network,
     keypair: KeyPair,
     derivation_path: DerivationPath,
+    purpose: Purpose,
 }
 
 impl Account {
@@ -29,41 +144,54 @@ impl Account {
             keypair,
             network,
             derivation_path,
+            purpose: Purpose::default(),
         }
     }
 
-    pub fn secret_key(&self) -> SecretKey {
+    pub fn with_purpose(mut self, purpose: Purpose) -> Self {
+        self.purpose = purpose;
+        self
+    }
+
+    // the secrets are `reveal_*` so handing them out is never an accident
+    pub fn reveal_secret_key(&self) -> SecretKey {
         self.keypair.secret_key()
     }
 
-    pub fn private_key(&self) -> anyhow::Result<PrivateKey> {
+    pub fn reveal_private_key(&self) -> anyhow::Result<PrivateKey> {
         let secret_key = self.keypair.secret_key();
         Ok(PrivateKey::new(secret_key, self.network))
     }
 
-    pub fn private_key_uncompressed(&self) -> anyhow::Result<PrivateKey> {
+    // the WIF to import the key elsewhere, wiped from memory when dropped
+    pub fn reveal_wif(&self) -> anyhow::Result<Zeroizing<String>> {
+        Ok(Zeroizing::new(self.reveal_private_key()?.to_wif()))
+    }
+
+    pub fn reveal_private_key_uncompressed(&self) -> anyhow::Result<PrivateKey> {
         let secret_key = self.keypair.secret_key();
         Ok(PrivateKey::new_uncompressed(secret_key, self.network))
     }
 
     pub fn public_key(&self) -> anyhow::Result<PublicKey> {
-        let secp = Secp256k1::new();
-        let private_key = self.private_key()?;
-        Ok(PublicKey::from_private_key(&secp, &private_key))
+        Ok(PublicKey::new(self.keypair.public_key()))
     }
 
     pub fn public_key_uncompressed(&self) -> anyhow::Result<PublicKey> {
-        let secp = Secp256k1::new();
-        let private_key = self.private_key_uncompressed()?;
-        Ok(PublicKey::from_private_key(&secp, &private_key))
+        Ok(PublicKey::new_uncompressed(self.keypair.public_key()))
     }
 
     pub fn x_only_public_key(&self) -> XOnlyPublicKey {
         XOnlyPublicKey::from_keypair(&self.keypair).0
     }
 
+    // the script of the purpose this account was derived for
     pub fn script_pubkey(&self) -> ScriptBuf {
-        self.p2tr_address().script_pubkey()
+        self.purpose.script_pubkey(&self.keypair.public_key())
+    }
+
+    pub fn address(&self) -> anyhow::Result<Address> {
+        Ok(Address::from_script(&self.script_pubkey(), self.network)?)
     }
 
     pub fn script_hash(&self) -> String {
@@ -77,7 +205,7 @@ impl Account {
             .join("")
     }
 
-    pub fn keypair(&self) -> KeyPair {
+    pub fn reveal_keypair(&self) -> KeyPair {
         self.keypair
     }
 
@@ -88,6 +216,10 @@ impl Account {
     pub fn network(&self) -> &Network {
         &self.network
     }
+
+    pub fn purpose(&self) -> Purpose {
+        self.purpose
+    }
 }
 
 // address
@@ -116,42 +248,142 @@ impl Account {
 
     // pay-to-taproot
     pub fn p2tr_address(&self) -> Address {
-        let secp = Secp256k1::new();
+        self.p2tr_address_with_merkle_root(None)
+    }
+
+    // pay-to-taproot committing to a script tree, see `script_tree`
+    pub fn p2tr_address_with_merkle_root(&self, merkle_root: Option<TapNodeHash>) -> Address {
+        let secp = secp();
         let untweak_public_key = XOnlyPublicKey::from_keypair(&self.keypair);
-        Address::p2tr(&secp, untweak_public_key.0, None, self.network)
+        Address::p2tr(secp, untweak_public_key.0, merkle_root, self.network)
+    }
+
+    // the leaves under this account's key as the internal key
+    pub fn script_tree(&self, leaves: Vec<ScriptBuf>) -> anyhow::Result<TaprootScriptTree> {
+        TaprootScriptTree::new(self.x_only_public_key(), leaves)
     }
 
-    // p2wsh
-    // pub fn p2wsh_address(&self) -> anyhow::Result<Address> {
-    //     let private_key = self.private_key()?;
-    //
-    //     todo!()
-    // }
-    // p2sh
-    // p2shwsh
+    // `<pubkey> OP_CHECKSIG`, to receive on a script hash address with this key alone
+    pub fn single_key_script(&self) -> anyhow::Result<ScriptBuf> {
+        Ok(single_key_script(&self.public_key()?))
+    }
+
+    // `single_key_script` behind a p2wsh address, see `ScriptHashOutput` for other scripts
+    pub fn p2wsh_address(&self) -> anyhow::Result<Address> {
+        ScriptHashOutput::p2wsh(self.single_key_script()?)?.address(self.network)
+    }
+
+    // `single_key_script` as the redeem script of a legacy p2sh address
+    pub fn p2sh_address(&self) -> anyhow::Result<Address> {
+        ScriptHashOutput::p2sh(self.single_key_script()?)?.address(self.network)
+    }
+
+    // `single_key_script` behind a p2sh-p2wsh address
+    pub fn p2shwsh_address(&self) -> anyhow::Result<Address> {
+        ScriptHashOutput::p2shwsh(self.single_key_script()?)?.address(self.network)
+    }
 }
 
+// spend script hash outputs
 impl Account {
-    pub fn transfer_btc_to_self(address_type: AddressType) {
-        match address_type {
-            AddressType::P2pkh => {}
-            AddressType::P2tr => {}
-            AddressType::P2wpkh => {}
-            AddressType::P2wsh => {}
-            _ => {}
+    // this key's signature for an input locked to `output`, to put on the stack of
+    // `ScriptHashOutput::finalize` where the script expects it
+    pub fn sign_script_hash_input(
+        &self,
+        tx: &Transaction,
+        input_index: usize,
+        output: &ScriptHashOutput,
+        value: u64,
+        sighash_type: EcdsaSighashType,
+    ) -> anyhow::Result<ecdsa::Signature> {
+        let secp = secp();
+        let msg = output.signature_hash(tx, input_index, value, sighash_type)?;
+
+        Ok(ecdsa::Signature {
+            sig: secp.sign_ecdsa_low_r(&msg, &self.reveal_secret_key()),
+            hash_ty: sighash_type,
+        })
+    }
+
+    // signs and finalizes an input locked to `single_key_script` of this account
+    pub fn spend_single_key_script_hash(
+        &self,
+        tx: &mut Transaction,
+        input_index: usize,
+        output: &ScriptHashOutput,
+        value: u64,
+    ) -> anyhow::Result<()> {
+        if output.script() != self.single_key_script()?.as_script() {
+            return Err(Error::msg(
+                "the script is not this account's single key script",
+            ));
         }
+
+        let signature =
+            self.sign_script_hash_input(tx, input_index, output, value, EcdsaSighashType::All)?;
+        let input = tx
+            .input
+            .get_mut(input_index)
+            .ok_or_else(|| Error::msg(format!("no input {input_index}")))?;
+        output.finalize_input(input, vec![signature.to_vec()])
+    }
+}
+
+// spend taproot outputs
+impl Account {
+    // key path, tweaked with the merkle root of the script tree if there is one
+    pub fn sign_tap_key_spend(
+        &self,
+        tx: &Transaction,
+        input_index: usize,
+        prevouts: &[TxOut],
+        merkle_root: Option<TapNodeHash>,
+        sighash_type: TapSighashType,
+    ) -> anyhow::Result<taproot::Signature> {
+        let secp = secp();
+        let sighash = SighashCache::new(tx).taproot_key_spend_signature_hash(
+            input_index,
+            &sighash::Prevouts::All(prevouts),
+            sighash_type,
+        )?;
+        let keypair = self.keypair.tap_tweak(secp, merkle_root).to_inner();
+
+        Ok(taproot::Signature {
+            sig: secp.sign_schnorr(&sighash.into(), &keypair),
+            hash_ty: sighash_type,
+        })
+    }
+
+    // script path, this account's (untweaked) key signs for a key in `leaf`
+    pub fn sign_tap_leaf(
+        &self,
+        tx: &Transaction,
+        input_index: usize,
+        prevouts: &[TxOut],
+        tree: &TaprootScriptTree,
+        leaf: &Script,
+        sighash_type: TapSighashType,
+    ) -> anyhow::Result<taproot::Signature> {
+        let secp = secp();
+        let sighash = tree.script_spend_sighash(tx, input_index, prevouts, leaf, sighash_type)?;
+
+        Ok(taproot::Signature {
+            sig: secp.sign_schnorr(&sighash.into(), &self.keypair),
+            hash_ty: sighash_type,
+        })
     }
 }
 
 impl From<PrivateKey> for Account {
     fn from(value: PrivateKey) -> Self {
-        let secp = Secp256k1::new();
+        let secp = secp();
 
-        let keypair = KeyPair::from_secret_key(&secp, &value.inner);
+        let keypair = KeyPair::from_secret_key(secp, &value.inner);
         Self {
             network: value.network,
             keypair,
             derivation_path: Default::default(),
+            purpose: Purpose::default(),
         }
     }
 }
@@ -170,7 +402,7 @@ impl From<i128> for Account {
 
 impl Account {
     pub fn from_number(network: Network, number: i128) -> Self {
-        let secp = Secp256k1::new();
+        let secp = secp();
 
         let private_key_bytes = number.to_be_bytes();
         let mut padded_bytes = [0u8; 32];
@@ -178,30 +410,32 @@ impl Account {
 
         let secret_key =
             SecretKey::from_slice(&padded_bytes).expect("Failed to create SecretKey from bytes");
-        let keypair = KeyPair::from_secret_key(&secp, &secret_key);
+        let keypair = KeyPair::from_secret_key(secp, &secret_key);
 
         Self {
             network,
             keypair,
             derivation_path: Default::default(),
+            purpose: Purpose::default(),
         }
     }
 
     pub fn from_secret_key(network: Network, secret_key: SecretKey) -> Self {
-        let secp = Secp256k1::new();
-        let keypair = KeyPair::from_secret_key(&secp, &secret_key);
+        let secp = secp();
+        let keypair = KeyPair::from_secret_key(secp, &secret_key);
 
         Self {
             network,
             keypair,
             derivation_path: Default::default(),
+            purpose: Purpose::default(),
         }
     }
 }
 
 impl From<u128> for Account {
     fn from(value: u128) -> Self {
-        let secp = Secp256k1::new();
+        let secp = secp();
 
         let private_key_bytes = value.to_be_bytes();
         let mut padded_bytes = [0u8; 32];
@@ -209,18 +443,19 @@ impl From<u128> for Account {
 
         let secret_key =
             SecretKey::from_slice(&padded_bytes).expect("Failed to create SecretKey from bytes");
-        let keypair = KeyPair::from_secret_key(&secp, &secret_key);
+        let keypair = KeyPair::from_secret_key(secp, &secret_key);
         Account::new(keypair, Network::Bitcoin, DerivationPath::default())
     }
 }
 
+// the private key is left out, use `reveal_wif` when it is really needed
 impl Display for Account {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "privatekey: {:}, network:{:}, addresses: \n {:?}, \n {:?}, \n {:?} \n {:?} \n {:?}",
-            self.private_key().unwrap().to_wif(),
-            self.network.to_string(),
+            "path: {:}, network:{:}, addresses: \n {:?}, \n {:?}, \n {:?} \n {:?} \n {:?}",
+            self.derivation_path,
+            self.network,
             self.p2pkh_address(),
             self.p2pkh_address_uncompressed(),
             self.p2wpkh_address(),
@@ -230,177 +465,482 @@ impl Display for Account {
     }
 }
 
+impl std::fmt::Debug for Account {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Account")
+            .field("network", &self.network)
+            .field("public_key", &self.keypair.public_key())
+            .field("secret_key", &"<redacted>")
+            .field("derivation_path", &self.derivation_path)
+            .field("purpose", &self.purpose)
+            .finish()
+    }
+}
+
+impl Drop for Account {
+    fn drop(&mut self) {
+        self.keypair.non_secure_erase();
+    }
+}
+
+fn erase_xprv(xprv: &mut ExtendedPrivKey) {
+    xprv.private_key.non_secure_erase();
+    AsMut::<[u8; 32]>::as_mut(&mut xprv.chain_code).zeroize();
+}
+
+#[derive(Clone)]
 pub struct AccountGenerator<'a> {
-    mnemonic_code: &'a str,
-    seed: [u8; 64],
+    mnemonic_code: Option<&'a str>,
+    seed: Option<Zeroizing<[u8; 64]>>,
     network: Network,
+    // the master key, or an account key imported from a descriptor
     master_private_key: ExtendedPrivKey,
+    // master fingerprint and the path of `master_private_key`, `m` for the master key
+    origin: (Fingerprint, DerivationPath),
+    purpose: Purpose,
+    account: u32,
+    // xprv of the receive and change chain, so only the last step is derived per index
+    chain_xprvs: [OnceLock<(ExtendedPrivKey, DerivationPath)>; 2],
 }
 
 impl<'a> AccountGenerator<'a> {
     pub fn new(mnemonic_code: &'a str, network: Network) -> anyhow::Result<Self> {
-        let mnemonic = Mnemonic::parse_in_normalized(Language::English, mnemonic_code)?;
-        let seed = mnemonic.to_seed("");
-        let master_private_key = ExtendedPrivKey::new_master(network, &seed)?;
+        Self::new_with_passphrase(mnemonic_code, None, network)
+    }
+
+    // the optional BIP39 passphrase, aka the 25th word
+    pub fn new_with_passphrase(
+        mnemonic_code: &'a str,
+        passphrase: Option<&str>,
+        network: Network,
+    ) -> anyhow::Result<Self> {
+        // any of the BIP39 wordlists, the error tells which word is wrong
+        let mnemonic = validate_mnemonic(mnemonic_code, None)?;
+        let seed = Zeroizing::new(mnemonic.to_seed(passphrase.unwrap_or_default()));
+        let master_private_key = ExtendedPrivKey::new_master(network, seed.as_ref())?;
 
         Ok(Self {
-            mnemonic_code,
-            seed,
+            mnemonic_code: Some(mnemonic_code),
+            seed: Some(seed),
+            origin: (
+                master_private_key.fingerprint(secp()),
+                DerivationPath::master(),
+            ),
             master_private_key,
             network,
+            purpose: Purpose::default(),
+            account: 0,
+            chain_xprvs: Default::default(),
         })
     }
 
-    pub fn get_account_from_index(&self, index: u32) -> anyhow::Result<Account> {
-        let secp = Secp256k1::new();
-        let path = {
-            let dp_prefix = match self.network {
-                Network::Bitcoin => "m/86'/0'/0'/0",
-                _ => "m/86'/1'/0'/0",
-            };
-
-            DerivationPath::from_str(&format!("{dp_prefix}/{index}")).unwrap()
+    // a master xprv, or an account xprv with its key origin, e.g. [73c5da0a/86'/0'/0']xprv...
+    // the purpose and account are taken from the origin path.
+    pub fn from_xprv(
+        xprv: ExtendedPrivKey,
+        origin: Option<(Fingerprint, DerivationPath)>,
+    ) -> anyhow::Result<Self> {
+        let origin = match origin {
+            Some(origin) => origin,
+            None if xprv.depth == 0 => (xprv.fingerprint(secp()), DerivationPath::master()),
+            None => return Err(Error::msg("key origin is required for a non-master xprv")),
         };
 
-        let keypair = {
-            let derived_key = self.master_private_key.derive_priv(&secp, &path).unwrap();
-            let secret_key = SecretKey::from_slice(derived_key.private_key[..].as_ref())?;
-            KeyPair::from_secret_key(&secp, &secret_key)
+        let mut ag = Self {
+            mnemonic_code: None,
+            seed: None,
+            network: xprv.network,
+            master_private_key: xprv,
+            origin,
+            purpose: Purpose::default(),
+            account: 0,
+            chain_xprvs: Default::default(),
         };
 
+        match ag.origin.1.as_ref() {
+            // a master key derives every purpose and account
+            [] => {}
+            [ChildNumber::Hardened { index: purpose }, ChildNumber::Hardened { index: coin_type }, ChildNumber::Hardened { index: account }] =>
+            {
+                // SLIP-44: 0' for bitcoin, 1' for every test network
+                let expected = match coin_type {
+                    0 => Network::Bitcoin,
+                    1 => Network::Testnet,
+                    _ => return Err(Error::msg(format!("unknown coin type: {coin_type}"))),
+                };
+                if (expected == Network::Bitcoin) != (xprv.network == Network::Bitcoin) {
+                    return Err(BtcError::NetworkMismatch {
+                        expected,
+                        found: xprv.network,
+                    }
+                    .into());
+                }
+                ag.purpose = Purpose::ALL
+                    .into_iter()
+                    .find(|p| p.number() == *purpose)
+                    .ok_or_else(|| Error::msg(format!("unknown purpose: {purpose}")))?;
+                ag.account = *account;
+            }
+            _ => {
+                return Err(Error::msg(format!(
+                    "key origin {} is neither a master key nor a purpose'/coin'/account' path",
+                    ag.origin.1
+                )))
+            }
+        }
+
+        Ok(ag)
+    }
+
+    // derive accounts for another scheme, e.g. m/84'/0'/{account}'
+    pub fn with_purpose(mut self, purpose: Purpose) -> Self {
+        self.purpose = purpose;
+        self.chain_xprvs = Default::default();
+        self
+    }
+
+    pub fn with_account(mut self, account: u32) -> Self {
+        self.account = account;
+        self.chain_xprvs = Default::default();
+        self
+    }
+
+    pub fn get_account_from_index(&self, index: u32) -> anyhow::Result<Account> {
+        self.get_account(KeychainKind::External, index)
+    }
+
+    pub fn get_change_account_from_index(&self, index: u32) -> anyhow::Result<Account> {
+        self.get_account(KeychainKind::Internal, index)
+    }
+
+    pub fn get_account(&self, keychain: KeychainKind, index: u32) -> anyhow::Result<Account> {
+        let (chain_xprv, chain_path) = self.chain_xprv(keychain)?;
+        self.account_from_chain(chain_xprv, chain_path, index)
+    }
+
+    // m/{purpose}'/{coin_type}'/{account}'/{0 or 1}, derived once and cached
+    fn chain_xprv(
+        &self,
+        keychain: KeychainKind,
+    ) -> anyhow::Result<&(ExtendedPrivKey, DerivationPath)> {
+        let cell = &self.chain_xprvs[keychain.index() as usize];
+        if let Some(chain) = cell.get() {
+            return Ok(chain);
+        }
+
+        let path = self
+            .account_derivation_path()?
+            .child(ChildNumber::from_normal_idx(keychain.index())?);
+        let chain_xprv = self.derive_priv(&path)?;
+        Ok(cell.get_or_init(|| (chain_xprv, path)))
+    }
+
+    fn account_from_chain(
+        &self,
+        chain_xprv: &ExtendedPrivKey,
+        chain_path: &DerivationPath,
+        index: u32,
+    ) -> anyhow::Result<Account> {
+        let secp = secp();
+        let child = ChildNumber::from_normal_idx(index)?;
+        let derived_key = chain_xprv.ckd_priv(secp, child)?;
+
         Ok(Account {
             network: self.network,
-            keypair,
-            derivation_path: path,
+            keypair: KeyPair::from_secret_key(secp, &derived_key.private_key),
+            derivation_path: chain_path.child(child),
+            purpose: self.purpose,
         })
     }
 
+    // the accounts for a range of indexes, e.g. 0..1000 to scan a wallet
+    pub fn derive_range(
+        &self,
+        keychain: KeychainKind,
+        range: Range<u32>,
+    ) -> anyhow::Result<Vec<Account>> {
+        let (chain_xprv, chain_path) = self.chain_xprv(keychain)?;
+        range
+            .map(|index| self.account_from_chain(chain_xprv, chain_path, index))
+            .collect()
+    }
+
+    // the same as `derive_range`, spread over the rayon thread pool
+    pub fn par_derive_range(
+        &self,
+        keychain: KeychainKind,
+        range: Range<u32>,
+    ) -> anyhow::Result<Vec<Account>> {
+        let (chain_xprv, chain_path) = self.chain_xprv(keychain)?;
+        range
+            .into_par_iter()
+            .map(|index| self.account_from_chain(chain_xprv, chain_path, index))
+            .collect()
+    }
+
+    // signs the inputs of `tx` spending outputs of the account at `idx`, `prevouts` are the
+    // outputs spent by each input in order
     pub fn sign_tx(
         &self,
         tx: &Transaction,
         idx: u32,
-        input_value: u64,
+        prevouts: &[TxOut],
     ) -> anyhow::Result<Transaction> {
-        let secp = Secp256k1::new();
+        self.sign_tx_with_keychain(tx, KeychainKind::External, idx, prevouts)
+    }
 
-        let account = self.get_account_from_index(idx)?;
-        let mut psbt = Psbt::from_unsigned_tx(tx.clone())?;
-        let mut origins = BTreeMap::new();
-        origins.insert(
-            account.x_only_public_key(),
-            (
-                vec![],
-                (self.fingerprint(), account.derivation_path().clone()),
-            ),
-        );
+    pub fn sign_tx_with_keychain(
+        &self,
+        tx: &Transaction,
+        keychain: KeychainKind,
+        idx: u32,
+        prevouts: &[TxOut],
+    ) -> anyhow::Result<Transaction> {
+        let account = self.get_account(keychain, idx)?;
+        let mut tx = tx.clone();
+        Signer::new(vec![account])?.sign_all(&mut tx, prevouts)?;
+        Ok(tx)
+    }
 
-        let input = Input {
-            witness_utxo: Some(TxOut {
-                value: input_value,
-                script_pubkey: account.script_pubkey(),
-            }),
-            sighash_type: Some(TapSighashType::All.into()),
-            tap_internal_key: Some(account.x_only_public_key()),
-            tap_key_origins: origins,
-            ..Default::default()
-        };
+    // n-of-n multisig script of the accounts at `ids`
+    pub fn gen_n_of_n_multisig(&self, ids: &[u32]) -> anyhow::Result<ScriptBuf> {
+        self.gen_multisig(ids, ids.len())
+    }
 
-        psbt.inputs = vec![input];
+    // `threshold`-of-n multisig script of the accounts at `ids`, with the keys sorted per
+    // BIP67. see `multisig::Multisig` for keys from several seeds or xpubs.
+    pub fn gen_multisig(&self, ids: &[u32], threshold: usize) -> anyhow::Result<ScriptBuf> {
+        let pks = ids
+            .iter()
+            .map(|idx| self.get_account_from_index(*idx)?.public_key())
+            .collect::<anyhow::Result<Vec<_>>>()?;
 
-        let unsigned_tx = psbt.unsigned_tx.clone();
-        println!("unsigned_tx: {:?}", unsigned_tx);
-        psbt.inputs
-            .iter_mut()
-            .enumerate()
-            .try_for_each::<_, Result<(), Box<dyn std::error::Error>>>(|(vout, input)| {
-                let hash_ty = input.sighash_type.unwrap().taproot_hash_ty()?;
+        sorted_multisig_script(threshold, &pks)
+    }
 
-                let hash = SighashCache::new(&unsigned_tx).taproot_key_spend_signature_hash(
-                    vout,
-                    &sighash::Prevouts::All(&[TxOut {
-                        value: input_value,
-                        script_pubkey: account.script_pubkey(),
-                    }]),
-                    hash_ty,
-                )?;
+    // `threshold`-of-n tapscript leaf of the accounts at `ids`, the OP_CHECKSIGADD
+    // counterpart of `gen_multisig`. see `taproot::TaprootMultisig` for the address.
+    pub fn gen_multi_a_leaf(&self, ids: &[u32], threshold: usize) -> anyhow::Result<ScriptBuf> {
+        let keys = ids
+            .iter()
+            .map(|idx| Ok(self.get_account_from_index(*idx)?.x_only_public_key()))
+            .collect::<anyhow::Result<Vec<_>>>()?;
 
-                let keypair = KeyPair::from_seckey_slice(&secp, account.secret_key().as_ref())?;
-                let keypair = keypair.tap_tweak(&secp, input.tap_merkle_root).to_inner();
+        multi_a_leaf(threshold, &keys)
+    }
 
-                let sig = secp.sign_schnorr(&hash.into(), &keypair);
-                let final_signature = taproot::Signature { sig, hash_ty };
-                input.tap_key_sig = Some(final_signature);
+    pub fn fingerprint(&self) -> Fingerprint {
+        self.origin.0
+    }
 
-                println!("tap_key_sig: {:?}", input.tap_key_sig);
+    // derive a full path (from m), which must be below the origin of an imported account key
+    pub(crate) fn derive_priv(&self, path: &DerivationPath) -> anyhow::Result<ExtendedPrivKey> {
+        let secp = secp();
+        let relative = path
+            .as_ref()
+            .strip_prefix(self.origin.1.as_ref())
+            .ok_or_else(|| BtcError::InvalidDerivation {
+                path: path.clone(),
+                origin: self.origin.1.clone(),
+            })?;
 
-                Ok(())
-            })
-            .unwrap();
+        Ok(self.master_private_key.derive_priv(secp, &relative)?)
+    }
 
-        psbt.inputs.iter_mut().for_each(|input| {
-            let mut script_witness: Witness = Witness::new();
-            script_witness.push(input.tap_key_sig.unwrap().to_vec());
-            input.final_script_witness = Some(script_witness);
+    // the xprv (tprv) at m/{purpose}'/{coin_type}'/{account}'
+    pub fn account_xprv(&self) -> anyhow::Result<ExtendedPrivKey> {
+        self.derive_priv(&self.account_derivation_path()?)
+    }
 
-            // Clear all the data fields as per the spec.
-            input.partial_sigs = BTreeMap::new();
-            input.sighash_type = None;
-            input.redeem_script = None;
-            input.witness_script = None;
-            input.bip32_derivation = BTreeMap::new();
-        });
+    // the xpub (tpub) at m/{purpose}'/{coin_type}'/{account}'
+    pub fn account_xpub(&self) -> anyhow::Result<ExtendedPubKey> {
+        let secp = secp();
+        Ok(ExtendedPubKey::from_priv(secp, &self.account_xprv()?))
+    }
 
-        // EXTRACTOR
-        let tx = psbt.extract_tx();
-        println!("vsize: {:?}", tx.vsize());
-        println!("tx: {:?}, ", tx);
+    // ypub/zpub (upub/vpub) for p2sh-p2wpkh and p2wpkh, xpub (tpub) otherwise
+    pub fn account_xpub_slip132(&self) -> anyhow::Result<String> {
+        Ok(encode_slip132(&self.account_xpub()?, self.purpose))
+    }
 
-        Ok(tx)
+    // derives the same addresses from the account xpub, with the key origin kept
+    pub fn watch_only(&self) -> anyhow::Result<WatchOnlyGenerator> {
+        Ok(WatchOnlyGenerator::new(self.account_xpub()?, self.purpose)
+            .with_origin(self.fingerprint(), self.account_derivation_path()?))
     }
 
-    pub fn gen_n_of_n_multisig(&self, ids: &[u32], n: u32) -> anyhow::Result<ScriptBuf> {
-        // m-of-n multisig, the n accounts are from ids
-        if ids.len() as u32 != 3 {
-            return Err(Error::msg("not valid"));
-        }
-        let pks = ids
-            .iter()
-            .map(|idx| self.get_account_from_index(*idx).unwrap())
-            .map(|account| account.public_key().unwrap())
-            .collect::<Vec<_>>();
-
-        let op_push_num = All::from(all::OP_PUSHNUM_1.to_u8() + n as u8 - 1u8);
-        let mut redeem_script = SBuilder::new().push_opcode(op_push_num);
-        for pk in &pks {
-            redeem_script = redeem_script.push_key(pk);
-        }
-        let redeemscript = redeem_script
-            .push_opcode(op_push_num)
-            .push_opcode(all::OP_CHECKMULTISIG)
-            .into_script();
+    // m/{purpose}'/{coin_type}'/{account}'
+    pub fn account_derivation_path(&self) -> anyhow::Result<DerivationPath> {
+        let coin_type = match self.network {
+            Network::Bitcoin => 0,
+            _ => 1,
+        };
 
-        Ok(redeemscript)
+        Ok(DerivationPath::from_str(&format!(
+            "m/{}'/{coin_type}'/{}'",
+            self.purpose.number(),
+            self.account
+        ))?)
     }
 
-    pub fn fingerprint(&self) -> Fingerprint {
-        let secp = Secp256k1::new();
-        self.master_private_key.fingerprint(&secp)
+    pub fn derivation_path(&self, index: u32) -> anyhow::Result<DerivationPath> {
+        self.keychain_derivation_path(KeychainKind::External, index)
     }
 
-    pub fn derivation_path(&self, index: u32) -> anyhow::Result<DerivationPath> {
-        let dp_prefix = match self.network {
-            Network::Bitcoin => "m/86'/0'/0'/0",
-            _ => "m/86'/1'/0'/0",
-        };
+    // m/{purpose}'/{coin_type}'/{account}'/{0 or 1}/{index}
+    pub fn keychain_derivation_path(
+        &self,
+        keychain: KeychainKind,
+        index: u32,
+    ) -> anyhow::Result<DerivationPath> {
+        let dp_prefix = self.account_derivation_path()?;
+
+        Ok(DerivationPath::from_str(&format!(
+            "{dp_prefix}/{}/{index}",
+            keychain.index()
+        ))?)
+    }
+
+    pub fn network(&self) -> &Network {
+        &self.network
+    }
+
+    pub fn purpose(&self) -> Purpose {
+        self.purpose
+    }
 
-        Ok(DerivationPath::from_str(&format!("{dp_prefix}/{index}"))?)
+    pub fn account(&self) -> u32 {
+        self.account
     }
 
-    pub fn mnemonic_code(&self) -> &str {
+    // None when imported from an xprv
+    pub fn reveal_mnemonic_code(&self) -> Option<&str> {
         self.mnemonic_code
     }
 
-    pub fn seed(&self) -> [u8; 64] {
-        self.seed
+    pub fn reveal_seed(&self) -> Option<&[u8; 64]> {
+        self.seed.as_deref()
+    }
+}
+
+impl<'a> std::fmt::Debug for AccountGenerator<'a> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AccountGenerator")
+            .field("network", &self.network)
+            .field("fingerprint", &self.origin.0)
+            .field("origin_path", &self.origin.1)
+            .field("purpose", &self.purpose)
+            .field("account", &self.account)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'a> Drop for AccountGenerator<'a> {
+    fn drop(&mut self) {
+        erase_xprv(&mut self.master_private_key);
+        for cell in self.chain_xprvs.iter_mut() {
+            if let Some((chain_xprv, _)) = cell.get_mut() {
+                erase_xprv(chain_xprv);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::key_pair::{AccountGenerator, KeychainKind};
+    use bitcoin::bip32::ExtendedPrivKey;
+    use bitcoin::secp256k1::Secp256k1;
+    use bitcoin::Network;
+    use std::str::FromStr;
+
+    // https://github.com/trezor/python-mnemonic/blob/master/vectors.json, passphrase "TREZOR"
+    const VECTORS: [(&str, &str, &str); 5] = [
+        (
+            "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about",
+            "c55257c360c07c72029aebc1b53c05ed0362ada38ead3e3e9efa3708e53495531f09a6987599d18264c1e1c92f2cf141630c7a3c4ab7c81b2f001698e7463b04",
+            "xprv9s21ZrQH143K3h3fDYiay8mocZ3afhfULfb5GX8kCBdno77K4HiA15Tg23wpbeF1pLfs1c5SPmYHrEpTuuRhxMwvKDwqdKiGJS9XFKzUsAF",
+        ),
+        (
+            "legal winner thank year wave sausage worth useful legal winner thank yellow",
+            "2e8905819b8723fe2c1d161860e5ee1830318dbf49a83bd451cfb8440c28bd6fa457fe1296106559a3c80937a1c1069be3a3a5bd381ee6260e8d9739fce1f607",
+            "xprv9s21ZrQH143K2gA81bYFHqU68xz1cX2APaSq5tt6MFSLeXnCKV1RVUJt9FWNTbrrryem4ZckN8k4Ls1H6nwdvDTvnV7zEXs2HgPezuVccsq",
+        ),
+        (
+            "letter advice cage absurd amount doctor acoustic avoid letter advice cage above",
+            "d71de856f81a8acc65e6fc851a38d4d7ec216fd0796d0a6827a3ad6ed5511a30fa280f12eb2e47ed2ac03b5c462a0358d18d69fe4f985ec81778c1b370b652a8",
+            "xprv9s21ZrQH143K2shfP28KM3nr5Ap1SXjz8gc2rAqqMEynmjt6o1qboCDpxckqXavCwdnYds6yBHZGKHv7ef2eTXy461PXUjBFQg6PrwY4Gzq",
+        ),
+        (
+            "zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo wrong",
+            "ac27495480225222079d7be181583751e86f571027b0497b5b5d11218e0a8a13332572917f0f8e5a589620c6f15b11c61dee327651a14c34e18231052e48c069",
+            "xprv9s21ZrQH143K2V4oox4M8Zmhi2Fjx5XK4Lf7GKRvPSgydU3mjZuKGCTg7UPiBUD7ydVPvSLtg9hjp7MQTYsW67rZHAXeccqYqrsx8LcXnyd",
+        ),
+        (
+            "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon art",
+            "bda85446c68413707090a52022edd26a1c9462295029f2e60cd7c4f2bbd3097170af7a4d73245cafa9c3cca8d561a7c3de6f5d4a10be8ed2a5e608d68f92fcc8",
+            "xprv9s21ZrQH143K32qBagUJAMU2LsHg3ka7jqMcV98Y7gVeVyNStwYS3U7yVVoDZ4btbRNf4h6ibWpY22iRmXq35qgLs79f312g2kj5539ebPM",
+        ),
+    ];
+
+    #[test]
+    fn test_passphrase_seed_and_fingerprint() {
+        let secp = Secp256k1::new();
+
+        for (mnemonic_code, seed, xprv) in VECTORS {
+            let ag = AccountGenerator::new_with_passphrase(
+                mnemonic_code,
+                Some("TREZOR"),
+                Network::Bitcoin,
+            )
+            .unwrap();
+            let xprv = ExtendedPrivKey::from_str(xprv).unwrap();
+
+            assert_eq!(hex::encode(ag.reveal_seed().unwrap()), seed);
+            assert_eq!(ag.fingerprint(), xprv.fingerprint(&secp));
+        }
+    }
+
+    #[test]
+    fn test_empty_passphrase() {
+        let (mnemonic_code, _, _) = VECTORS[0];
+        let ag = AccountGenerator::new(mnemonic_code, Network::Bitcoin).unwrap();
+        let with_empty =
+            AccountGenerator::new_with_passphrase(mnemonic_code, Some(""), Network::Bitcoin)
+                .unwrap();
+        let with_trezor =
+            AccountGenerator::new_with_passphrase(mnemonic_code, Some("TREZOR"), Network::Bitcoin)
+                .unwrap();
+
+        assert_eq!(ag.reveal_seed(), with_empty.reveal_seed());
+        assert_ne!(ag.fingerprint(), with_trezor.fingerprint());
+        assert_eq!(
+            ag.get_account_from_index(0)
+                .unwrap()
+                .p2tr_address()
+                .to_string(),
+            "bc1p5cyxnuxmeuwuvkwfem96lqzszd02n6xdcjrs20cac6yqjjwudpxqkedrcr"
+        );
+    }
+
+    #[test]
+    fn test_account_formatting_hides_secrets() {
+        let (mnemonic_code, _, _) = VECTORS[0];
+        let account = AccountGenerator::new(mnemonic_code, Network::Bitcoin)
+            .unwrap()
+            .get_account(KeychainKind::External, 0)
+            .unwrap();
+        let secrets = [
+            account.reveal_wif().unwrap().to_string(),
+            account.reveal_private_key_uncompressed().unwrap().to_wif(),
+            account.reveal_secret_key().display_secret().to_string(),
+        ];
+
+        for formatted in [format!("{account:?}"), account.to_string()] {
+            assert!(formatted.contains(&account.derivation_path().to_string()));
+            for secret in &secrets {
+                assert!(!formatted.contains(secret.as_str()), "{formatted}");
+            }
+        }
     }
 }
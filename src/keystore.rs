@@ -0,0 +1,494 @@
+// an encrypted keystore for mnemonics (with their passphrases) and imported WIF keys.
+//
+// the entries live in a redb file, each one serialized to json and sealed with
+// XChaCha20-Poly1305 under a key derived from the password with Argon2id.
+// the entry name is the associated data, so sealed entries can't be swapped around.
+
+use crate::define_table;
+use crate::key_pair::{Account, AccountGenerator};
+use crate::mnemonic::validate_mnemonic;
+use anyhow::Error;
+use argon2::{Algorithm, Argon2, Params, Version};
+use bitcoin::secp256k1::rand::{thread_rng, RngCore};
+use bitcoin::{Network, PrivateKey};
+use chacha20poly1305::aead::{Aead, Payload};
+use chacha20poly1305::{Key, KeyInit, XChaCha20Poly1305, XNonce};
+use redb::{Database, ReadableTable, TableDefinition};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::Path;
+use zeroize::{Zeroize, Zeroizing};
+
+define_table!(KEYSTORE_META, &str, &[u8]);
+define_table!(KEYSTORE_ENTRIES, &str, &[u8]);
+
+const SALT: &str = "salt";
+const KDF_PARAMS: &str = "kdf_params";
+// a known plaintext sealed with the key, to tell a wrong password from a corrupted entry
+const VERIFIER: &str = "verifier";
+const VERIFIER_PLAINTEXT: &[u8] = b"btc keystore v1";
+
+const SALT_LEN: usize = 16;
+// the most key derivation a keystore file can ask for, 1 GiB of memory and 16 passes and
+// lanes, so a tampered file can't make unlocking it exhaust the machine
+const MAX_M_COST: u32 = 1024 * 1024;
+const MAX_T_COST: u32 = 16;
+const MAX_P_COST: u32 = 16;
+const NONCE_LEN: usize = 24;
+
+pub const DEFAULT_ENTRY: &str = "default";
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum KeystoreEntry {
+    Mnemonic {
+        mnemonic: String,
+        passphrase: Option<String>,
+    },
+    Wif {
+        wif: String,
+    },
+}
+
+impl KeystoreEntry {
+    pub fn account_generator(&self, network: Network) -> anyhow::Result<AccountGenerator<'_>> {
+        match self {
+            KeystoreEntry::Mnemonic {
+                mnemonic,
+                passphrase,
+            } => AccountGenerator::new_with_passphrase(mnemonic, passphrase.as_deref(), network),
+            KeystoreEntry::Wif { .. } => Err(Error::msg(
+                "the keystore entry is a WIF key, not a mnemonic",
+            )),
+        }
+    }
+
+    pub fn account(&self) -> anyhow::Result<Account> {
+        match self {
+            KeystoreEntry::Wif { wif } => Ok(Account::from(PrivateKey::from_wif(wif)?)),
+            KeystoreEntry::Mnemonic { .. } => Err(Error::msg(
+                "the keystore entry is a mnemonic, derive accounts with account_generator",
+            )),
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            KeystoreEntry::Mnemonic { .. } => "mnemonic",
+            KeystoreEntry::Wif { .. } => "wif",
+        }
+    }
+}
+
+impl std::fmt::Debug for KeystoreEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeystoreEntry")
+            .field("kind", &self.kind())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for KeystoreEntry {
+    fn drop(&mut self) {
+        match self {
+            KeystoreEntry::Mnemonic {
+                mnemonic,
+                passphrase,
+            } => {
+                mnemonic.zeroize();
+                passphrase.zeroize();
+            }
+            KeystoreEntry::Wif { wif } => wif.zeroize(),
+        }
+    }
+}
+
+pub struct Keystore {
+    database: Database,
+    cipher: XChaCha20Poly1305,
+}
+
+impl Keystore {
+    // a new keystore file, fails if `path` already exists
+    pub fn create<P: AsRef<Path>>(path: P, password: &str) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        if path.exists() {
+            return Err(Error::msg(format!(
+                "keystore {} already exists",
+                path.display()
+            )));
+        }
+
+        let mut salt = [0u8; SALT_LEN];
+        thread_rng().fill_bytes(&mut salt);
+        let params = Params::default();
+        let cipher = derive_cipher(password, &salt, &params)?;
+        let verifier = seal(&cipher, VERIFIER, VERIFIER_PLAINTEXT)?;
+
+        let database = Database::create(path)?;
+        let write_txn = database.begin_write()?;
+        {
+            let mut meta = write_txn.open_table(KEYSTORE_META)?;
+            meta.insert(SALT, salt.as_slice())?;
+            meta.insert(KDF_PARAMS, encode_params(&params).as_slice())?;
+            meta.insert(VERIFIER, verifier.as_slice())?;
+            write_txn.open_table(KEYSTORE_ENTRIES)?;
+        }
+        write_txn.commit()?;
+
+        Ok(Self { database, cipher })
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P, password: &str) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Err(Error::msg(format!(
+                "keystore {} does not exist",
+                path.display()
+            )));
+        }
+
+        // `create` would make an empty keystore of any file that isn't one
+        let database = Database::open(path)?;
+        let cipher = {
+            let read_txn = database.begin_read()?;
+            let meta = read_txn.open_table(KEYSTORE_META)?;
+            let get = |key: &str| -> anyhow::Result<Vec<u8>> {
+                Ok(meta
+                    .get(key)?
+                    .ok_or_else(|| Error::msg(format!("not a keystore, {key} is missing")))?
+                    .value()
+                    .to_vec())
+            };
+
+            let cipher = derive_cipher(password, &get(SALT)?, &decode_params(&get(KDF_PARAMS)?)?)?;
+            open_sealed(&cipher, VERIFIER, &get(VERIFIER)?)
+                .map_err(|_| Error::msg("wrong keystore password"))?;
+            cipher
+        };
+
+        Ok(Self { database, cipher })
+    }
+
+    pub fn insert(&self, name: &str, entry: &KeystoreEntry) -> anyhow::Result<()> {
+        let plaintext = Zeroizing::new(serde_json::to_vec(entry)?);
+        let sealed = seal(&self.cipher, name, &plaintext)?;
+
+        let write_txn = self.database.begin_write()?;
+        {
+            let mut entries = write_txn.open_table(KEYSTORE_ENTRIES)?;
+            if entries.get(name)?.is_some() {
+                return Err(Error::msg(format!("keystore entry {name} already exists")));
+            }
+            entries.insert(name, sealed.as_slice())?;
+        }
+        write_txn.commit()?;
+        Ok(())
+    }
+
+    // the mnemonic is validated before it's stored, with any of the BIP39 wordlists
+    pub fn add_mnemonic(
+        &self,
+        name: &str,
+        mnemonic: &str,
+        passphrase: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let normalized = Zeroizing::new(validate_mnemonic(mnemonic, None)?.to_string());
+        self.insert(
+            name,
+            &KeystoreEntry::Mnemonic {
+                mnemonic: normalized.to_string(),
+                passphrase: passphrase.map(str::to_string),
+            },
+        )
+    }
+
+    pub fn add_wif(&self, name: &str, wif: &str) -> anyhow::Result<()> {
+        PrivateKey::from_wif(wif)?;
+        self.insert(
+            name,
+            &KeystoreEntry::Wif {
+                wif: wif.to_string(),
+            },
+        )
+    }
+
+    pub fn get(&self, name: &str) -> anyhow::Result<Option<KeystoreEntry>> {
+        let read_txn = self.database.begin_read()?;
+        let entries = read_txn.open_table(KEYSTORE_ENTRIES)?;
+        let sealed = match entries.get(name)? {
+            Some(sealed) => sealed.value().to_vec(),
+            None => return Ok(None),
+        };
+
+        let plaintext = open_sealed(&self.cipher, name, &sealed)?;
+        Ok(Some(serde_json::from_slice(&plaintext)?))
+    }
+
+    // the entry names, no decryption needed
+    pub fn names(&self) -> anyhow::Result<Vec<String>> {
+        let read_txn = self.database.begin_read()?;
+        let entries = read_txn.open_table(KEYSTORE_ENTRIES)?;
+
+        entries
+            .iter()?
+            .map(|entry| Ok(entry?.0.value().to_string()))
+            .collect()
+    }
+
+    pub fn remove(&self, name: &str) -> anyhow::Result<bool> {
+        let write_txn = self.database.begin_write()?;
+        let removed = write_txn
+            .open_table(KEYSTORE_ENTRIES)?
+            .remove(name)?
+            .is_some();
+        write_txn.commit()?;
+        Ok(removed)
+    }
+}
+
+fn derive_cipher(
+    password: &str,
+    salt: &[u8],
+    params: &Params,
+) -> anyhow::Result<XChaCha20Poly1305> {
+    let mut key = Zeroizing::new([0u8; 32]);
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params.clone())
+        .hash_password_into(password.as_bytes(), salt, key.as_mut())
+        .map_err(|e| Error::msg(format!("key derivation failed: {e}")))?;
+
+    Ok(XChaCha20Poly1305::new(Key::from_slice(key.as_ref())))
+}
+
+// m_cost (KiB), t_cost and p_cost, so the defaults can change without breaking old files
+fn encode_params(params: &Params) -> Vec<u8> {
+    [params.m_cost(), params.t_cost(), params.p_cost()]
+        .iter()
+        .flat_map(|p| p.to_le_bytes())
+        .collect()
+}
+
+fn decode_params(bytes: &[u8]) -> anyhow::Result<Params> {
+    let costs = bytes
+        .chunks_exact(4)
+        .map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap_or_default()))
+        .collect::<Vec<_>>();
+    match costs.as_slice() {
+        [m_cost, t_cost, p_cost] => Params::new(
+            (*m_cost).min(MAX_M_COST),
+            (*t_cost).min(MAX_T_COST),
+            (*p_cost).min(MAX_P_COST),
+            None,
+        )
+        .map_err(|e| Error::msg(format!("invalid key derivation params: {e}"))),
+        _ => Err(Error::msg("invalid key derivation params")),
+    }
+}
+
+// random nonce || ciphertext and tag
+fn seal(cipher: &XChaCha20Poly1305, name: &str, plaintext: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut nonce = [0u8; NONCE_LEN];
+    thread_rng().fill_bytes(&mut nonce);
+
+    let ciphertext = cipher
+        .encrypt(
+            XNonce::from_slice(&nonce),
+            Payload {
+                msg: plaintext,
+                aad: name.as_bytes(),
+            },
+        )
+        .map_err(|_| Error::msg("encryption failed"))?;
+
+    Ok([nonce.as_slice(), &ciphertext].concat())
+}
+
+fn open_sealed(
+    cipher: &XChaCha20Poly1305,
+    name: &str,
+    sealed: &[u8],
+) -> anyhow::Result<Zeroizing<Vec<u8>>> {
+    if sealed.len() < NONCE_LEN {
+        return Err(Error::msg(format!("keystore entry {name} is truncated")));
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+
+    let plaintext = cipher
+        .decrypt(
+            XNonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: name.as_bytes(),
+            },
+        )
+        .map_err(|_| Error::msg(format!("can't decrypt keystore entry {name}")))?;
+
+    Ok(Zeroizing::new(plaintext))
+}
+
+// KEYSTORE_PASSWORD, or a line from stdin. stdin isn't hidden, pipe it in or use the env var.
+pub fn read_password() -> anyhow::Result<Zeroizing<String>> {
+    if let Ok(password) = std::env::var("KEYSTORE_PASSWORD") {
+        return Ok(Zeroizing::new(password));
+    }
+
+    eprint!("keystore password: ");
+    std::io::stderr().flush()?;
+    let mut password = Zeroizing::new(String::new());
+    std::io::stdin().read_line(&mut password)?;
+    let trimmed = password.trim_end_matches(['\r', '\n']).len();
+    password.truncate(trimmed);
+    Ok(password)
+}
+
+// what the bins unlock at start: the KEYSTORE_ENTRY entry (`default` if unset) of the
+// keystore at KEYSTORE_PATH. without KEYSTORE_PATH it falls back to the plaintext
+// MNEMONIC (and optional PASSPHRASE) env vars.
+pub fn unlock_from_env() -> anyhow::Result<KeystoreEntry> {
+    let path = match std::env::var("KEYSTORE_PATH") {
+        Ok(path) => path,
+        Err(_) => {
+            let mnemonic = std::env::var("MNEMONIC")
+                .map_err(|_| Error::msg("set KEYSTORE_PATH, or MNEMONIC for a plaintext seed"))?;
+            return Ok(KeystoreEntry::Mnemonic {
+                mnemonic,
+                passphrase: std::env::var("PASSPHRASE").ok(),
+            });
+        }
+    };
+
+    let name = std::env::var("KEYSTORE_ENTRY").unwrap_or_else(|_| DEFAULT_ENTRY.to_string());
+    let keystore = Keystore::open(&path, &read_password()?)?;
+    keystore
+        .get(&name)?
+        .ok_or_else(|| Error::msg(format!("no entry {name} in keystore {path}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::keystore::{
+        decode_params, encode_params, Keystore, KeystoreEntry, KEYSTORE_ENTRIES, MAX_M_COST,
+        MAX_P_COST, MAX_T_COST,
+    };
+    use argon2::Params;
+    use std::path::PathBuf;
+
+    const MNEMONIC: &str =
+        "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+    const PASSWORD: &str = "correct horse battery staple";
+
+    // a fresh file in the temp dir, removed when dropped
+    struct TempPath(PathBuf);
+
+    impl TempPath {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("btc-{}-{name}.redb", std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            Self(path)
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let path = TempPath::new("round-trip");
+        let keystore = Keystore::create(&path.0, PASSWORD).unwrap();
+        keystore
+            .add_mnemonic("default", MNEMONIC, Some(" with spaces "))
+            .unwrap();
+        drop(keystore);
+
+        let keystore = Keystore::open(&path.0, PASSWORD).unwrap();
+        assert_eq!(keystore.names().unwrap(), vec!["default"]);
+        match keystore.get("default").unwrap() {
+            Some(KeystoreEntry::Mnemonic {
+                ref mnemonic,
+                ref passphrase,
+            }) => {
+                assert_eq!(mnemonic, MNEMONIC);
+                assert_eq!(passphrase.as_deref(), Some(" with spaces "));
+            }
+            entry => panic!("expected the mnemonic, got {entry:?}"),
+        }
+        assert!(keystore.get("other").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_wrong_password() {
+        let path = TempPath::new("wrong-password");
+        drop(Keystore::create(&path.0, PASSWORD).unwrap());
+
+        let e = Keystore::open(&path.0, "wrong password").err().unwrap();
+        assert_eq!(e.to_string(), "wrong keystore password");
+    }
+
+    #[test]
+    fn test_tampered_ciphertext() {
+        let path = TempPath::new("tampered");
+        let keystore = Keystore::create(&path.0, PASSWORD).unwrap();
+        keystore.add_mnemonic("default", MNEMONIC, None).unwrap();
+
+        let sealed = {
+            let read_txn = keystore.database.begin_read().unwrap();
+            let entries = read_txn.open_table(KEYSTORE_ENTRIES).unwrap();
+            let sealed = entries.get("default").unwrap().unwrap().value().to_vec();
+            sealed
+        };
+        let replace = |name: &str, sealed: &[u8]| {
+            let write_txn = keystore.database.begin_write().unwrap();
+            write_txn
+                .open_table(KEYSTORE_ENTRIES)
+                .unwrap()
+                .insert(name, sealed)
+                .unwrap();
+            write_txn.commit().unwrap();
+        };
+
+        // a flipped bit of the ciphertext
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        replace("default", &tampered);
+        assert!(keystore.get("default").is_err());
+
+        // an intact entry under another name
+        replace("default", &sealed);
+        replace("moved", &sealed);
+        assert!(keystore.get("default").unwrap().is_some());
+        assert!(keystore.get("moved").is_err());
+    }
+
+    #[test]
+    fn test_open_not_a_keystore() {
+        // a mistyped path isn't created
+        let path = TempPath::new("missing");
+        assert!(Keystore::open(&path.0, PASSWORD).is_err());
+        assert!(!path.0.exists());
+
+        // nor is any other file turned into an empty keystore
+        let path = TempPath::new("not-a-keystore");
+        std::fs::write(&path.0, b"not a keystore").unwrap();
+        assert!(Keystore::open(&path.0, PASSWORD).is_err());
+        assert_eq!(std::fs::read(&path.0).unwrap(), b"not a keystore");
+    }
+
+    #[test]
+    fn test_params_clamped() {
+        let params = decode_params(&encode_params(&Params::default())).unwrap();
+        assert_eq!(params, Params::default());
+
+        let bytes = [u32::MAX; 3]
+            .iter()
+            .flat_map(|cost| cost.to_le_bytes())
+            .collect::<Vec<_>>();
+        let params = decode_params(&bytes).unwrap();
+        assert_eq!(
+            (params.m_cost(), params.t_cost(), params.p_cost()),
+            (MAX_M_COST, MAX_T_COST, MAX_P_COST)
+        );
+    }
+}
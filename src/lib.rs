@@ -1,7 +1,23 @@
+pub mod coin_selection;
+pub mod descriptor;
+pub mod discovery;
+pub mod error;
 pub mod fee;
 pub mod fetcher;
 pub mod key_pair;
 pub mod keypair;
+pub mod keystore;
 #[macro_use]
 pub mod macros;
+pub mod mnemonic;
+pub mod multisig;
+pub mod musig;
+pub mod psbt;
+pub mod script_hash;
+pub mod secp;
+pub mod signer;
+pub mod taproot;
+pub mod tx_builder;
 pub mod wallet;
+pub mod watch_only;
+pub mod weight;
@@ -0,0 +1,350 @@
+// generate and validate BIP39 mnemonic codes.
+
+use bip39::{Language, Mnemonic};
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::secp256k1::rand::{thread_rng, RngCore};
+use std::fmt::{Display, Formatter};
+
+pub const WORD_COUNTS: [usize; 5] = [12, 15, 18, 21, 24];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MnemonicError {
+    BadWordCount(usize),
+    UnknownWord {
+        // 0-based position in the phrase
+        index: usize,
+        word: String,
+        suggestions: Vec<&'static str>,
+    },
+    InvalidChecksum,
+    AmbiguousLanguages(Vec<Language>),
+    UnknownLanguage(String),
+    NotEnoughEntropy {
+        needed: usize,
+        got: usize,
+    },
+    InvalidEntropyCharacter(char),
+}
+
+impl Display for MnemonicError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MnemonicError::BadWordCount(count) => write!(
+                f,
+                "mnemonic has {count} words, it must be one of {:?}",
+                WORD_COUNTS
+            ),
+            MnemonicError::UnknownWord {
+                index,
+                word,
+                suggestions,
+            } => {
+                write!(f, "word #{} \"{word}\" is not in the wordlist", index + 1)?;
+                if !suggestions.is_empty() {
+                    write!(f, ", did you mean: {}?", suggestions.join(", "))?;
+                }
+                Ok(())
+            }
+            MnemonicError::InvalidChecksum => write!(
+                f,
+                "checksum mismatch, all words are valid but at least one is wrong or out of order"
+            ),
+            MnemonicError::AmbiguousLanguages(languages) => {
+                write!(f, "mnemonic fits several wordlists: {:?}", languages)
+            }
+            MnemonicError::UnknownLanguage(language) => {
+                write!(f, "unknown mnemonic language: {language}")
+            }
+            MnemonicError::NotEnoughEntropy { needed, got } => {
+                write!(f, "not enough entropy, needs at least {needed}, got {got}")
+            }
+            MnemonicError::InvalidEntropyCharacter(c) => {
+                write!(f, "invalid entropy character: {c:?}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for MnemonicError {}
+
+pub fn parse_language(s: &str) -> Result<Language, MnemonicError> {
+    Language::ALL
+        .iter()
+        .find(|language| {
+            language
+                .to_string()
+                .replace(['-', '_', ' '], "")
+                .eq_ignore_ascii_case(&s.replace(['-', '_', ' '], ""))
+        })
+        .copied()
+        .ok_or_else(|| MnemonicError::UnknownLanguage(s.to_string()))
+}
+
+fn entropy_bytes(word_count: usize) -> Result<usize, MnemonicError> {
+    if !WORD_COUNTS.contains(&word_count) {
+        return Err(MnemonicError::BadWordCount(word_count));
+    }
+
+    // every 3 words carry 32 bits of entropy and 1 bit of checksum
+    Ok(word_count / 3 * 4)
+}
+
+// a new mnemonic from the os random number generator
+pub fn generate_mnemonic(word_count: usize, language: Language) -> anyhow::Result<Mnemonic> {
+    let mut entropy = vec![0u8; entropy_bytes(word_count)?];
+    thread_rng().fill_bytes(&mut entropy);
+
+    Ok(Mnemonic::from_entropy_in(language, &entropy)?)
+}
+
+// dice rolls like "3615242...", hashed with sha256 (the same as coldcard does),
+// each roll of a d6 carries log2(6) ~ 2.58 bits so 24 words need at least 100 rolls.
+pub fn mnemonic_from_dice(
+    word_count: usize,
+    rolls: &str,
+    language: Language,
+) -> anyhow::Result<Mnemonic> {
+    let bytes = entropy_bytes(word_count)?;
+    let rolls = rolls
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .map(|c| match c {
+            '1'..='6' => Ok(c),
+            _ => Err(MnemonicError::InvalidEntropyCharacter(c)),
+        })
+        .collect::<Result<String, _>>()?;
+
+    let needed = (bytes as f64 * 8.0 / 6f64.log2()).ceil() as usize;
+    if rolls.len() < needed {
+        return Err(MnemonicError::NotEnoughEntropy {
+            needed,
+            got: rolls.len(),
+        }
+        .into());
+    }
+
+    let hash = sha256::Hash::hash(rolls.as_bytes());
+    Ok(Mnemonic::from_entropy_in(language, &hash[..bytes])?)
+}
+
+// coin flips like "HTTH..." or "0110...", used as the entropy bits directly.
+pub fn mnemonic_from_coins(
+    word_count: usize,
+    flips: &str,
+    language: Language,
+) -> anyhow::Result<Mnemonic> {
+    let bytes = entropy_bytes(word_count)?;
+    let bits = flips
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .map(|c| match c {
+            '1' | 'H' | 'h' => Ok(1u8),
+            '0' | 'T' | 't' => Ok(0u8),
+            _ => Err(MnemonicError::InvalidEntropyCharacter(c)),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if bits.len() < bytes * 8 {
+        return Err(MnemonicError::NotEnoughEntropy {
+            needed: bytes * 8,
+            got: bits.len(),
+        }
+        .into());
+    }
+
+    let entropy = bits[..bytes * 8]
+        .chunks(8)
+        .map(|byte| byte.iter().fold(0u8, |acc, bit| acc << 1 | bit))
+        .collect::<Vec<_>>();
+    Ok(Mnemonic::from_entropy_in(language, &entropy)?)
+}
+
+// validate the words and the checksum, the language is detected when `language` is None.
+pub fn validate_mnemonic(
+    mnemonic_code: &str,
+    language: Option<Language>,
+) -> Result<Mnemonic, MnemonicError> {
+    let parsed = match language {
+        Some(language) => Mnemonic::parse_in(language, mnemonic_code),
+        None => Mnemonic::parse(mnemonic_code),
+    };
+
+    parsed
+        .map_err(|e| match e {
+            bip39::Error::BadWordCount(count) => MnemonicError::BadWordCount(count),
+            bip39::Error::UnknownWord(index) => {
+                let word = mnemonic_code
+                    .split_whitespace()
+                    .nth(index)
+                    .unwrap_or_default()
+                    .to_string();
+                let language = language
+                    .or_else(|| Mnemonic::language_of(mnemonic_code).ok())
+                    .unwrap_or_default();
+
+                MnemonicError::UnknownWord {
+                    index,
+                    suggestions: suggest_words(&word, language),
+                    word,
+                }
+            }
+            bip39::Error::AmbiguousLanguages(languages) => {
+                MnemonicError::AmbiguousLanguages(languages.to_vec())
+            }
+            _ => MnemonicError::InvalidChecksum,
+        })
+        .or_else(|e| match e {
+            // e.g. english and french share "abandon", the seed only depends on the words,
+            // so any wordlist with a valid checksum will do.
+            MnemonicError::AmbiguousLanguages(ref languages) => languages
+                .iter()
+                .find_map(|language| Mnemonic::parse_in(*language, mnemonic_code).ok())
+                .ok_or(MnemonicError::InvalidChecksum),
+            e => Err(e),
+        })
+}
+
+// words sharing the longest prefix with the unknown word, english words are unique in 4 letters
+fn suggest_words(word: &str, language: Language) -> Vec<&'static str> {
+    let chars = word.chars().collect::<Vec<_>>();
+
+    (1..=chars.len().min(4))
+        .rev()
+        .map(|len| {
+            let prefix = chars[..len].iter().collect::<String>();
+            language
+                .word_list()
+                .iter()
+                .filter(|w| w.starts_with(&prefix))
+                .copied()
+                .take(5)
+                .collect::<Vec<_>>()
+        })
+        .find(|words| !words.is_empty())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mnemonic::{
+        generate_mnemonic, mnemonic_from_coins, mnemonic_from_dice, parse_language,
+        validate_mnemonic, MnemonicError,
+    };
+    use bip39::Language;
+
+    #[test]
+    fn test_unknown_word() {
+        let e = validate_mnemonic(
+            "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abanden about",
+            None,
+        )
+        .unwrap_err();
+        match e {
+            MnemonicError::UnknownWord {
+                index,
+                word,
+                suggestions,
+            } => {
+                assert_eq!(index, 10);
+                assert_eq!(word, "abanden");
+                assert!(suggestions.contains(&"abandon"));
+            }
+            e => panic!("expected an unknown word, got {e}"),
+        }
+
+        assert_eq!(
+            validate_mnemonic(
+                "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon",
+                None,
+            ),
+            Err(MnemonicError::InvalidChecksum)
+        );
+    }
+
+    #[test]
+    fn test_language_round_trip() {
+        for (name, language) in [
+            ("spanish", Language::Spanish),
+            ("Japanese", Language::Japanese),
+        ] {
+            assert_eq!(parse_language(name), Ok(language));
+            let mnemonic = generate_mnemonic(24, language).unwrap();
+            assert_eq!(mnemonic.language(), language);
+
+            let phrase = mnemonic.to_string();
+            assert_eq!(validate_mnemonic(&phrase, None), Ok(mnemonic.clone()));
+            assert_eq!(validate_mnemonic(&phrase, Some(language)), Ok(mnemonic));
+            assert!(validate_mnemonic(&phrase, Some(Language::English)).is_err());
+        }
+    }
+
+    #[test]
+    fn test_dice() {
+        let rolls = "1234563".repeat(15);
+        let mnemonic = mnemonic_from_dice(24, &rolls[..100], Language::English).unwrap();
+        assert_eq!(mnemonic.word_count(), 24);
+        // the same rolls give the same words, spaces between them don't matter
+        let spaced = rolls[..100]
+            .chars()
+            .map(|c| format!("{c} "))
+            .collect::<String>();
+        assert_eq!(
+            mnemonic_from_dice(24, &spaced, Language::English).unwrap(),
+            mnemonic
+        );
+        assert_ne!(
+            mnemonic_from_dice(24, &rolls[1..101], Language::English).unwrap(),
+            mnemonic
+        );
+
+        let e = mnemonic_from_dice(24, &rolls[..99], Language::English).unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<MnemonicError>(),
+            Some(&MnemonicError::NotEnoughEntropy {
+                needed: 100,
+                got: 99
+            })
+        );
+        // 128 bits for 12 words
+        assert!(mnemonic_from_dice(12, &rolls[..50], Language::English).is_ok());
+        assert!(mnemonic_from_dice(12, &rolls[..49], Language::English).is_err());
+
+        let e =
+            mnemonic_from_dice(12, &format!("{}7", &rolls[..50]), Language::English).unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<MnemonicError>(),
+            Some(&MnemonicError::InvalidEntropyCharacter('7'))
+        );
+    }
+
+    #[test]
+    fn test_coins() {
+        // the entropy bits as they are, the all zeros and all ones vectors of BIP39
+        assert_eq!(
+            mnemonic_from_coins(12, &"T".repeat(128), Language::English)
+                .unwrap()
+                .to_string(),
+            "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about"
+        );
+        assert_eq!(
+            mnemonic_from_coins(12, &"1".repeat(130), Language::English)
+                .unwrap()
+                .to_string(),
+            "zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo wrong"
+        );
+
+        let e = mnemonic_from_coins(12, &"h".repeat(127), Language::English).unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<MnemonicError>(),
+            Some(&MnemonicError::NotEnoughEntropy {
+                needed: 128,
+                got: 127
+            })
+        );
+        let e = mnemonic_from_coins(12, "HTHX", Language::English).unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<MnemonicError>(),
+            Some(&MnemonicError::InvalidEntropyCharacter('X'))
+        );
+    }
+}
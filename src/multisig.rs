@@ -0,0 +1,870 @@
+// m-of-n CHECKMULTISIG wallets with the keys sorted per BIP67, the same as the
+// `sortedmulti` descriptors of bitcoin core, sparrow and electrum. every cosigner is an
+// account xpub (from any seed) with its key origin, the keys of an address are the
+// children at /{keychain}/{index} of each xpub.
+
+use crate::descriptor::{add_checksum, key_origin, parse_key_origin};
+use crate::error::Error as BtcError;
+use crate::key_pair::{AccountGenerator, KeychainKind};
+use crate::script_hash::{ScriptHashOutput, ScriptHashType};
+use crate::secp::secp;
+use crate::signer::{check_single, psbt_utxo, Signer};
+use anyhow::Error;
+use bitcoin::{
+    absolute::LockTime,
+    bip32::{ChildNumber, DerivationPath, ExtendedPubKey, Fingerprint, KeySource},
+    ecdsa,
+    opcodes::all,
+    psbt::{Input, Psbt, PsbtSighashType},
+    script::{Builder as SBuilder, Instruction},
+    sighash::EcdsaSighashType,
+    Address, Network, OutPoint, PublicKey, Script, ScriptBuf, Sequence, Transaction, TxIn, TxOut,
+    Witness,
+};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+// the limit of OP_CHECKMULTISIG in p2sh (and the standardness limit in p2wsh)
+pub const MAX_MULTISIG_KEYS: usize = 15;
+
+// OP_m <keys sorted by their serialization> OP_n OP_CHECKMULTISIG
+pub fn sorted_multisig_script(threshold: usize, keys: &[PublicKey]) -> anyhow::Result<ScriptBuf> {
+    if keys.is_empty() || keys.len() > MAX_MULTISIG_KEYS {
+        return Err(Error::msg(format!(
+            "multisig needs 1 to {MAX_MULTISIG_KEYS} keys, got {}",
+            keys.len()
+        )));
+    }
+    if threshold == 0 || threshold > keys.len() {
+        return Err(Error::msg(format!(
+            "threshold must be between 1 and {}, got {threshold}",
+            keys.len()
+        )));
+    }
+    // BIP67 only defines the order of compressed keys
+    if keys.iter().any(|key| !key.compressed) {
+        return Err(Error::msg("multisig keys must be compressed"));
+    }
+
+    let mut keys = keys.to_vec();
+    keys.sort_by_key(|key| key.to_bytes());
+    if keys.windows(2).any(|pair| pair[0] == pair[1]) {
+        return Err(Error::msg("duplicate multisig key"));
+    }
+
+    Ok(keys
+        .iter()
+        .fold(
+            SBuilder::new().push_int(threshold as i64),
+            |builder, key| builder.push_key(key),
+        )
+        .push_int(keys.len() as i64)
+        .push_opcode(all::OP_CHECKMULTISIG)
+        .into_script())
+}
+
+// BIP48 m/48'/{coin_type}'/{account}'/{1' p2sh-p2wsh, 2' p2wsh},
+// BIP48 doesn't cover legacy p2sh, which uses the BIP45 m/45' like sparrow does.
+pub fn multisig_derivation_path(
+    network: Network,
+    account: u32,
+    kind: ScriptHashType,
+) -> anyhow::Result<DerivationPath> {
+    let coin_type = match network {
+        Network::Bitcoin => 0,
+        _ => 1,
+    };
+
+    Ok(DerivationPath::from_str(&match kind {
+        ScriptHashType::P2sh => "m/45'".to_string(),
+        ScriptHashType::P2shP2wsh => format!("m/48'/{coin_type}'/{account}'/1'"),
+        ScriptHashType::P2wsh => format!("m/48'/{coin_type}'/{account}'/2'"),
+    })?)
+}
+
+// one participant of a multisig wallet, written as [fingerprint/path]xpub
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cosigner {
+    xpub: ExtendedPubKey,
+    fingerprint: Fingerprint,
+    derivation_path: DerivationPath,
+}
+
+impl Cosigner {
+    // without a key origin, the xpub itself is treated as the root
+    pub fn new(xpub: ExtendedPubKey) -> Self {
+        Self {
+            xpub,
+            fingerprint: xpub.fingerprint(),
+            derivation_path: DerivationPath::master(),
+        }
+    }
+
+    pub fn with_origin(mut self, fingerprint: Fingerprint, path: DerivationPath) -> Self {
+        self.fingerprint = fingerprint;
+        self.derivation_path = path;
+        self
+    }
+
+    // the xpub of the generator's account at the multisig path of `kind`
+    pub fn from_generator(ag: &AccountGenerator, kind: ScriptHashType) -> anyhow::Result<Self> {
+        Self::from_generator_path(
+            ag,
+            multisig_derivation_path(*ag.network(), ag.account(), kind)?,
+        )
+    }
+
+    // the xpub of the generator's seed at any path, for wallets off the BIP45/BIP48 paths
+    pub fn from_generator_path(
+        ag: &AccountGenerator,
+        path: DerivationPath,
+    ) -> anyhow::Result<Self> {
+        let xpub = ExtendedPubKey::from_priv(secp(), &ag.derive_priv(&path)?);
+
+        Ok(Self::new(xpub).with_origin(ag.fingerprint(), path))
+    }
+
+    pub fn xpub(&self) -> &ExtendedPubKey {
+        &self.xpub
+    }
+
+    pub fn fingerprint(&self) -> Fingerprint {
+        self.fingerprint
+    }
+
+    pub fn derivation_path(&self) -> &DerivationPath {
+        &self.derivation_path
+    }
+
+    // the key at /{keychain}/{index} and where it comes from
+    pub fn derive(
+        &self,
+        keychain: KeychainKind,
+        index: u32,
+    ) -> anyhow::Result<(PublicKey, KeySource)> {
+        let secp = secp();
+        let children = [
+            ChildNumber::from_normal_idx(keychain.index())?,
+            ChildNumber::from_normal_idx(index)?,
+        ];
+        let derived = self.xpub.derive_pub(secp, &children)?;
+
+        Ok((
+            PublicKey::new(derived.public_key),
+            (self.fingerprint, self.derivation_path.extend(children)),
+        ))
+    }
+}
+
+impl FromStr for Cosigner {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (origin, xpub) = parse_key_origin(s.trim())?;
+        let cosigner = Cosigner::new(ExtendedPubKey::from_str(xpub)?);
+
+        Ok(match origin {
+            Some((fingerprint, path)) => cosigner.with_origin(fingerprint, path),
+            None => cosigner,
+        })
+    }
+}
+
+impl Display for Cosigner {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}{}",
+            key_origin(self.fingerprint, &self.derivation_path),
+            self.xpub
+        )
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Multisig {
+    network: Network,
+    threshold: usize,
+    cosigners: Vec<Cosigner>,
+    kind: ScriptHashType,
+}
+
+impl Multisig {
+    // p2wsh by default, see `with_kind`
+    pub fn new(
+        threshold: usize,
+        cosigners: Vec<Cosigner>,
+        network: Network,
+    ) -> anyhow::Result<Self> {
+        if cosigners.is_empty() || cosigners.len() > MAX_MULTISIG_KEYS {
+            return Err(Error::msg(format!(
+                "multisig needs 1 to {MAX_MULTISIG_KEYS} cosigners, got {}",
+                cosigners.len()
+            )));
+        }
+        if threshold == 0 || threshold > cosigners.len() {
+            return Err(Error::msg(format!(
+                "threshold must be between 1 and {}, got {threshold}",
+                cosigners.len()
+            )));
+        }
+        // xpubs only know mainnet or testnet
+        let is_mainnet = network == Network::Bitcoin;
+        if let Some(cosigner) = cosigners
+            .iter()
+            .find(|cosigner| (cosigner.xpub.network == Network::Bitcoin) != is_mainnet)
+        {
+            return Err(BtcError::NetworkMismatch {
+                expected: network,
+                found: cosigner.xpub.network,
+            }
+            .into());
+        }
+        if cosigners
+            .iter()
+            .enumerate()
+            .any(|(i, cosigner)| cosigners[..i].iter().any(|c| c.xpub == cosigner.xpub))
+        {
+            return Err(Error::msg("duplicate cosigner xpub"));
+        }
+
+        Ok(Self {
+            network,
+            threshold,
+            cosigners,
+            kind: ScriptHashType::P2wsh,
+        })
+    }
+
+    pub fn with_kind(mut self, kind: ScriptHashType) -> Self {
+        self.kind = kind;
+        self
+    }
+
+    pub fn network(&self) -> &Network {
+        &self.network
+    }
+
+    pub fn threshold(&self) -> usize {
+        self.threshold
+    }
+
+    pub fn cosigners(&self) -> &[Cosigner] {
+        &self.cosigners
+    }
+
+    pub fn kind(&self) -> ScriptHashType {
+        self.kind
+    }
+
+    // the keys of an address in script order (BIP67)
+    pub fn keys(
+        &self,
+        keychain: KeychainKind,
+        index: u32,
+    ) -> anyhow::Result<Vec<(PublicKey, KeySource)>> {
+        let mut keys = self
+            .cosigners
+            .iter()
+            .map(|cosigner| cosigner.derive(keychain, index))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        keys.sort_by_key(|(key, _)| key.to_bytes());
+        Ok(keys)
+    }
+
+    // the witness script for p2wsh and p2sh-p2wsh, the redeem script for p2sh
+    pub fn script(&self, keychain: KeychainKind, index: u32) -> anyhow::Result<ScriptBuf> {
+        let keys = self
+            .keys(keychain, index)?
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect::<Vec<_>>();
+        sorted_multisig_script(self.threshold, &keys)
+    }
+
+    pub fn output(&self, keychain: KeychainKind, index: u32) -> anyhow::Result<ScriptHashOutput> {
+        ScriptHashOutput::new(self.kind, self.script(keychain, index)?)
+    }
+
+    pub fn address(&self, keychain: KeychainKind, index: u32) -> anyhow::Result<Address> {
+        self.output(keychain, index)?.address(self.network)
+    }
+
+    // wsh(sortedmulti(2,[fp/48h/0h/0h/2h]xpub.../0/*,...))#checksum
+    pub fn descriptor(&self, keychain: KeychainKind) -> anyhow::Result<String> {
+        let keys = self
+            .cosigners
+            .iter()
+            .map(|cosigner| format!("{cosigner}/{}/*", keychain.index()))
+            .collect::<Vec<_>>()
+            .join(",");
+        let multi = format!("sortedmulti({},{keys})", self.threshold);
+
+        add_checksum(&match self.kind {
+            ScriptHashType::P2sh => format!("sh({multi})"),
+            ScriptHashType::P2shP2wsh => format!("sh(wsh({multi}))"),
+            ScriptHashType::P2wsh => format!("wsh({multi})"),
+        })
+    }
+}
+
+// a coin of the multisig wallet to spend
+#[derive(Clone, Debug)]
+pub struct MultisigUtxo {
+    pub outpoint: OutPoint,
+    pub txout: TxOut,
+    pub keychain: KeychainKind,
+    pub index: u32,
+    // the previous transaction, required by BIP174 for p2sh inputs
+    pub previous_tx: Option<Transaction>,
+}
+
+impl Multisig {
+    // an unsigned psbt, every input carries its script and the key origins of all
+    // cosigners so each of them can sign it without knowing the wallet
+    pub fn create_psbt(&self, utxos: &[MultisigUtxo], outputs: Vec<TxOut>) -> anyhow::Result<Psbt> {
+        let unsigned_tx = Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: utxos
+                .iter()
+                .map(|utxo| TxIn {
+                    previous_output: utxo.outpoint,
+                    script_sig: ScriptBuf::new(),
+                    sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                    witness: Witness::new(),
+                })
+                .collect(),
+            output: outputs,
+        };
+        let mut psbt = Psbt::from_unsigned_tx(unsigned_tx)?;
+
+        for (input, utxo) in psbt.inputs.iter_mut().zip(utxos) {
+            let output = self.output(utxo.keychain, utxo.index)?;
+            if output.script_pubkey() != utxo.txout.script_pubkey {
+                return Err(Error::msg(format!(
+                    "{} is not the {} {} address of the wallet",
+                    utxo.outpoint, utxo.keychain, utxo.index
+                )));
+            }
+
+            match self.kind {
+                ScriptHashType::P2sh => {
+                    if utxo.previous_tx.is_none() {
+                        return Err(Error::msg(format!(
+                            "p2sh input {} needs the previous transaction",
+                            utxo.outpoint
+                        )));
+                    }
+                    input.redeem_script = Some(output.script().to_owned());
+                }
+                ScriptHashType::P2shP2wsh => {
+                    input.witness_utxo = Some(utxo.txout.clone());
+                    input.redeem_script =
+                        Some(ScriptBuf::new_v0_p2wsh(&output.script().wscript_hash()));
+                    input.witness_script = Some(output.script().to_owned());
+                }
+                ScriptHashType::P2wsh => {
+                    input.witness_utxo = Some(utxo.txout.clone());
+                    input.witness_script = Some(output.script().to_owned());
+                }
+            }
+            input.non_witness_utxo = utxo.previous_tx.clone();
+            input.bip32_derivation = self
+                .keys(utxo.keychain, utxo.index)?
+                .into_iter()
+                .map(|(key, source)| (key.inner, source))
+                .collect();
+        }
+
+        Ok(psbt)
+    }
+}
+
+// the script an input is locked to, from the scripts `create_psbt` put in the input
+fn input_output(input: &Input) -> anyhow::Result<ScriptHashOutput> {
+    match (&input.redeem_script, &input.witness_script) {
+        (Some(_), Some(witness_script)) => ScriptHashOutput::p2shwsh(witness_script.clone()),
+        (None, Some(witness_script)) => ScriptHashOutput::p2wsh(witness_script.clone()),
+        (Some(redeem_script), None) => ScriptHashOutput::p2sh(redeem_script.clone()),
+        (None, None) => Err(Error::msg("the input has no redeem or witness script")),
+    }
+}
+
+// OP_m <keys> OP_n OP_CHECKMULTISIG, the threshold and the keys in script order
+fn parse_multisig_script(script: &Script) -> anyhow::Result<(usize, Vec<PublicKey>)> {
+    let instructions = script.instructions().collect::<Result<Vec<_>, _>>()?;
+    let pushnum = |instruction: &Instruction| match instruction {
+        Instruction::Op(op)
+            if (all::OP_PUSHNUM_1.to_u8()..=all::OP_PUSHNUM_16.to_u8()).contains(&op.to_u8()) =>
+        {
+            Some((op.to_u8() - all::OP_PUSHNUM_1.to_u8() + 1) as usize)
+        }
+        _ => None,
+    };
+
+    match instructions.as_slice() {
+        [first, keys @ .., n, Instruction::Op(all::OP_CHECKMULTISIG)] => {
+            let threshold = pushnum(first).ok_or_else(|| Error::msg("not a multisig script"))?;
+            if pushnum(n) != Some(keys.len()) {
+                return Err(Error::msg("not a multisig script"));
+            }
+            let keys = keys
+                .iter()
+                .map(|key| match key {
+                    Instruction::PushBytes(bytes) => Ok(PublicKey::from_slice(bytes.as_bytes())?),
+                    _ => Err(Error::msg("not a multisig script")),
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            Ok((threshold, keys))
+        }
+        _ => Err(Error::msg("not a multisig script")),
+    }
+}
+
+impl<'a> AccountGenerator<'a> {
+    // adds a partial signature for every key of this seed in the multisig inputs of the
+    // psbt, found by the master fingerprint of `bip32_derivation`. the single key inputs are
+    // left as they are. an input asking for a sighash type other than ALL is an error and
+    // nothing is signed. returns how many signatures were added.
+    pub fn sign_multisig_psbt(&self, psbt: &mut Psbt) -> anyhow::Result<usize> {
+        self.sign_multisig_psbt_allowing(psbt, &[])
+    }
+
+    // like `sign_multisig_psbt`, the inputs may also ask for one of `sighash_types`
+    pub fn sign_multisig_psbt_allowing(
+        &self,
+        psbt: &mut Psbt,
+        sighash_types: &[PsbtSighashType],
+    ) -> anyhow::Result<usize> {
+        let secp = secp();
+        let allowed = sighash_types
+            .iter()
+            .fold(Signer::default(), |signer, sighash_type| {
+                signer.allow_sighash(*sighash_type)
+            });
+
+        // every input is checked before anything is signed
+        let mut messages = vec![];
+        for index in 0..psbt.inputs.len() {
+            // single key inputs, and the ones without our keys, are someone else's to sign
+            let input = &psbt.inputs[index];
+            if !input
+                .bip32_derivation
+                .values()
+                .any(|(fingerprint, _)| *fingerprint == self.fingerprint())
+            {
+                continue;
+            }
+            let output = match input_output(input) {
+                Ok(output) if parse_multisig_script(output.script()).is_ok() => output,
+                _ => continue,
+            };
+            let value = psbt_utxo(psbt, index)?
+                .ok_or_else(|| Error::msg(format!("input {index} has no utxo")))?
+                .value;
+            let sighash_type = psbt.inputs[index]
+                .sighash_type
+                .unwrap_or(EcdsaSighashType::All.into());
+            if !allowed.allows_sighash(sighash_type) {
+                return Err(BtcError::SigningFailed {
+                    input: index,
+                    reason: format!("sighash type {sighash_type} is not allowed"),
+                }
+                .into());
+            }
+            let sighash_type = sighash_type.ecdsa_hash_ty()?;
+            if matches!(
+                sighash_type,
+                EcdsaSighashType::Single | EcdsaSighashType::SinglePlusAnyoneCanPay
+            ) {
+                check_single(index, psbt.unsigned_tx.output.len())?;
+            }
+            let msg = output.signature_hash(&psbt.unsigned_tx, index, value, sighash_type)?;
+            messages.push((index, msg, sighash_type));
+        }
+
+        let mut signed = 0;
+        for (index, msg, sighash_type) in messages {
+            let input = &mut psbt.inputs[index];
+            let ours = input
+                .bip32_derivation
+                .iter()
+                .filter(|(_, (fingerprint, _))| *fingerprint == self.fingerprint())
+                .map(|(key, (_, path))| (*key, path.clone()))
+                .collect::<Vec<_>>();
+            for (key, path) in ours {
+                let xprv = self.derive_priv(&path)?;
+                if xprv.private_key.public_key(secp) != key {
+                    return Err(Error::msg(format!(
+                        "the key at {path} of input {index} is not from this seed"
+                    )));
+                }
+
+                let signature = ecdsa::Signature {
+                    sig: secp.sign_ecdsa_low_r(&msg, &xprv.private_key),
+                    hash_ty: sighash_type,
+                };
+                input.partial_sigs.insert(PublicKey::new(key), signature);
+                signed += 1;
+            }
+        }
+
+        Ok(signed)
+    }
+}
+
+// builds the final scriptSig and witness of every input: the dummy element for the
+// CHECKMULTISIG off-by-one bug, then `threshold` signatures in the order of the keys.
+pub fn finalize_multisig_psbt(psbt: &mut Psbt) -> anyhow::Result<()> {
+    psbt.inputs
+        .iter_mut()
+        .enumerate()
+        .try_for_each(|(index, input)| finalize_multisig_input(index, input))
+}
+
+pub(crate) fn finalize_multisig_input(index: usize, input: &mut Input) -> anyhow::Result<()> {
+    let output = input_output(input)?;
+    let (threshold, keys) = parse_multisig_script(output.script())?;
+
+    let signatures = keys
+        .iter()
+        .filter_map(|key| input.partial_sigs.get(key))
+        .take(threshold)
+        .map(|signature| signature.to_vec())
+        .collect::<Vec<_>>();
+    if signatures.len() < threshold {
+        return Err(Error::msg(format!(
+            "input {index} has {} of {threshold} signatures",
+            signatures.len()
+        )));
+    }
+
+    let stack = std::iter::once(vec![]).chain(signatures).collect();
+    let (script_sig, witness) = output.finalize(stack)?;
+    input.final_script_sig = Some(script_sig).filter(|script_sig| !script_sig.is_empty());
+    input.final_script_witness = Some(witness).filter(|witness| !witness.is_empty());
+
+    // clear everything but the utxos and the final fields, as BIP174 asks of the finalizer
+    input.partial_sigs.clear();
+    input.sighash_type = None;
+    input.redeem_script = None;
+    input.witness_script = None;
+    input.bip32_derivation.clear();
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::key_pair::{AccountGenerator, KeychainKind, Purpose};
+    use crate::multisig::{
+        finalize_multisig_psbt, parse_multisig_script, sorted_multisig_script, Cosigner, Multisig,
+        MultisigUtxo,
+    };
+    use crate::psbt::combine_psbts;
+    use crate::script_hash::{ScriptHashOutput, ScriptHashType};
+    use crate::secp::secp;
+    use bitcoin::absolute::LockTime;
+    use bitcoin::hashes::Hash;
+    use bitcoin::psbt::{Input, Psbt};
+    use bitcoin::sighash::EcdsaSighashType;
+    use bitcoin::{
+        ecdsa, Network, OutPoint, PublicKey, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Txid,
+        WPubkeyHash, Witness,
+    };
+    use std::str::FromStr;
+
+    const MNEMONICS: [&str; 3] = [
+        "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about",
+        "legal winner thank year wave sausage worth useful legal winner thank yellow",
+        "letter advice cage absurd amount doctor acoustic avoid letter advice cage above",
+    ];
+
+    const KINDS: [ScriptHashType; 3] = [
+        ScriptHashType::P2wsh,
+        ScriptHashType::P2shP2wsh,
+        ScriptHashType::P2sh,
+    ];
+
+    // the wallet as every cosigner sees it, only the xpubs are shared
+    fn wallet(threshold: usize, kind: ScriptHashType) -> Multisig {
+        let cosigners = MNEMONICS
+            .iter()
+            .map(|mnemonic| {
+                let ag = AccountGenerator::new(mnemonic, Network::Testnet).unwrap();
+                Cosigner::from_generator(&ag, kind).unwrap()
+            })
+            .collect();
+        Multisig::new(threshold, cosigners, Network::Testnet)
+            .unwrap()
+            .with_kind(kind)
+    }
+
+    // a receive and a change coin of the wallet
+    fn utxos(multisig: &Multisig) -> Vec<MultisigUtxo> {
+        let previous_tx = Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn::default()],
+            output: [(KeychainKind::External, 0), (KeychainKind::Internal, 3)]
+                .iter()
+                .map(|(keychain, index)| TxOut {
+                    value: 50_000,
+                    script_pubkey: multisig.output(*keychain, *index).unwrap().script_pubkey(),
+                })
+                .collect(),
+        };
+
+        [(KeychainKind::External, 0), (KeychainKind::Internal, 3)]
+            .into_iter()
+            .enumerate()
+            .map(|(vout, (keychain, index))| MultisigUtxo {
+                outpoint: OutPoint::new(previous_tx.txid(), vout as u32),
+                txout: previous_tx.output[vout].clone(),
+                keychain,
+                index,
+                previous_tx: Some(previous_tx.clone()),
+            })
+            .collect()
+    }
+
+    fn unsigned_psbt(multisig: &Multisig) -> Vec<u8> {
+        let outputs = vec![TxOut {
+            value: 99_000,
+            script_pubkey: ScriptBuf::new_v0_p2wpkh(&WPubkeyHash::all_zeros()),
+        }];
+        multisig
+            .create_psbt(&utxos(multisig), outputs)
+            .unwrap()
+            .serialize()
+    }
+
+    // a cosigner only gets the serialized psbt and its own mnemonic
+    fn cosign(mnemonic: &str, psbt: &[u8]) -> Vec<u8> {
+        let ag = AccountGenerator::new(mnemonic, Network::Testnet).unwrap();
+        let mut psbt = Psbt::deserialize(psbt).unwrap();
+        assert_eq!(ag.sign_multisig_psbt(&mut psbt).unwrap(), psbt.inputs.len());
+        psbt.serialize()
+    }
+
+    // what OP_CHECKMULTISIG does: the dummy, then each signature against the remaining keys
+    fn verify(tx: &Transaction, multisig: &Multisig) {
+        for (index, (txin, utxo)) in tx.input.iter().zip(utxos(multisig)).enumerate() {
+            let mut stack = match multisig.kind() {
+                ScriptHashType::P2sh => txin
+                    .script_sig
+                    .instructions()
+                    .map(|i| i.unwrap().push_bytes().unwrap().as_bytes().to_vec())
+                    .collect::<Vec<_>>(),
+                _ => txin.witness.to_vec(),
+            };
+            let script = ScriptBuf::from_bytes(stack.pop().unwrap());
+            let output = ScriptHashOutput::new(multisig.kind(), script.clone()).unwrap();
+            assert_eq!(output.script_pubkey(), utxo.txout.script_pubkey);
+            if multisig.kind() == ScriptHashType::P2shP2wsh {
+                let redeem_script = ScriptBuf::new_v0_p2wsh(&script.wscript_hash());
+                assert_eq!(
+                    txin.script_sig.as_bytes()[1..],
+                    redeem_script.as_bytes()[..]
+                );
+            }
+
+            let (threshold, keys) = parse_multisig_script(&script).unwrap();
+            assert_eq!(stack.remove(0), Vec::<u8>::new());
+            assert_eq!(stack.len(), threshold);
+
+            let msg = output
+                .signature_hash(tx, index, utxo.txout.value, EcdsaSighashType::All)
+                .unwrap();
+            let mut keys = keys.iter();
+            for signature in stack {
+                let signature = ecdsa::Signature::from_slice(&signature).unwrap();
+                assert!(keys.any(|key| secp()
+                    .verify_ecdsa(&msg, &signature.sig, &key.inner)
+                    .is_ok()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_bip67_vectors() {
+        // (threshold, keys as given, sorted redeem script, p2sh address) from BIP67
+        let vectors = [
+            (
+                2,
+                vec![
+                    "02ff12471208c14bd580709cb2358d98975247d8765f92bc25eab3b2763ed605f8",
+                    "02fe6f0a5a297eb38c391581c4413e084773ea23954d93f7753db7dc0adc188b2f",
+                ],
+                "522102fe6f0a5a297eb38c391581c4413e084773ea23954d93f7753db7dc0adc188b2f2102ff12471208c14bd580709cb2358d98975247d8765f92bc25eab3b2763ed605f852ae",
+                "39bgKC7RFbpoCRbtD5KEdkYKtNyhpsNa3Z",
+            ),
+            (
+                2,
+                vec![
+                    "02632b12f4ac5b1d1b72b2a3b508c19172de44f6f46bcee50ba33f3f9291e47ed0",
+                    "027735a29bae7780a9755fae7a1c4374c656ac6a69ea9f3697fda61bb99a4f3e77",
+                    "02e2cc6bd5f45edd43bebe7cb9b675f0ce9ed3efe613b177588290ad188d11b404",
+                ],
+                "522102632b12f4ac5b1d1b72b2a3b508c19172de44f6f46bcee50ba33f3f9291e47ed021027735a29bae7780a9755fae7a1c4374c656ac6a69ea9f3697fda61bb99a4f3e772102e2cc6bd5f45edd43bebe7cb9b675f0ce9ed3efe613b177588290ad188d11b40453ae",
+                "3CKHTjBKxCARLzwABMu9yD85kvtm7WnMfH",
+            ),
+            (
+                2,
+                vec![
+                    "022df8750480ad5b26950b25c7ba79d3e37d75f640f8e5d9bcd5b150a0f85014da",
+                    "03e3818b65bcc73a7d64064106a859cc1a5a728c4345ff0b641209fba0d90de6e9",
+                    "021f2f6e1e50cb6a953935c3601284925decd3fd21bc445712576873fb8c6ebc18",
+                ],
+                "5221021f2f6e1e50cb6a953935c3601284925decd3fd21bc445712576873fb8c6ebc1821022df8750480ad5b26950b25c7ba79d3e37d75f640f8e5d9bcd5b150a0f85014da2103e3818b65bcc73a7d64064106a859cc1a5a728c4345ff0b641209fba0d90de6e953ae",
+                "3Q4sF6tv9wsdqu2NtARzNCpQgwifm2rAba",
+            ),
+        ];
+
+        for (threshold, keys, script, address) in vectors {
+            let keys = keys
+                .iter()
+                .map(|key| PublicKey::from_str(key).unwrap())
+                .collect::<Vec<_>>();
+            let redeem_script = sorted_multisig_script(threshold, &keys).unwrap();
+            assert_eq!(redeem_script.to_hex_string(), script);
+            assert_eq!(
+                ScriptHashOutput::p2sh(redeem_script)
+                    .unwrap()
+                    .address(Network::Bitcoin)
+                    .unwrap()
+                    .to_string(),
+                address
+            );
+        }
+    }
+
+    #[test]
+    fn test_2_of_3() {
+        for kind in KINDS {
+            let multisig = wallet(2, kind);
+            let unsigned = unsigned_psbt(&multisig);
+
+            // the first and the last cosigner sign their own copy
+            let first = cosign(MNEMONICS[0], &unsigned);
+            let last = cosign(MNEMONICS[2], &unsigned);
+
+            let mut one = Psbt::deserialize(&first).unwrap();
+            assert!(finalize_multisig_psbt(&mut one).is_err());
+
+            let mut psbt = combine_psbts(vec![
+                Psbt::deserialize(&first).unwrap(),
+                Psbt::deserialize(&last).unwrap(),
+            ])
+            .unwrap();
+            finalize_multisig_psbt(&mut psbt).unwrap();
+            let tx = psbt.extract_tx();
+
+            verify(&tx, &multisig);
+            assert!(tx
+                .input
+                .iter()
+                .all(|txin| txin.sequence == Sequence::ENABLE_RBF_NO_LOCKTIME));
+        }
+    }
+
+    #[test]
+    fn test_3_of_3() {
+        for kind in KINDS {
+            let multisig = wallet(3, kind);
+
+            // passed around from one cosigner to the next
+            let signed = MNEMONICS
+                .iter()
+                .fold(unsigned_psbt(&multisig), |psbt, mnemonic| {
+                    cosign(mnemonic, &psbt)
+                });
+
+            let mut psbt = Psbt::deserialize(&signed).unwrap();
+            finalize_multisig_psbt(&mut psbt).unwrap();
+            assert!(psbt
+                .inputs
+                .iter()
+                .all(|input| input.partial_sigs.is_empty()));
+            let tx = psbt.extract_tx();
+
+            verify(&tx, &multisig);
+            if kind != ScriptHashType::P2sh {
+                assert!(tx.input.iter().all(|txin| txin.witness != Witness::new()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_sighash_types() {
+        let ag = AccountGenerator::new(MNEMONICS[0], Network::Testnet).unwrap();
+        for kind in KINDS {
+            let multisig = wallet(2, kind);
+            let unsigned = Psbt::deserialize(&unsigned_psbt(&multisig)).unwrap();
+
+            // NONE would let whoever built the psbt change the outputs
+            let none = EcdsaSighashType::None.into();
+            let mut psbt = unsigned.clone();
+            psbt.inputs[1].sighash_type = Some(none);
+            assert!(ag.sign_multisig_psbt(&mut psbt).is_err());
+            assert!(psbt
+                .inputs
+                .iter()
+                .all(|input| input.partial_sigs.is_empty()));
+            assert_eq!(
+                ag.sign_multisig_psbt_allowing(&mut psbt, &[none]).unwrap(),
+                2
+            );
+
+            // SINGLE without an output at the input's index, even when allowed
+            let single = EcdsaSighashType::Single.into();
+            let mut psbt = unsigned.clone();
+            psbt.inputs[1].sighash_type = Some(single);
+            assert!(ag
+                .sign_multisig_psbt_allowing(&mut psbt, &[single])
+                .is_err());
+            psbt.inputs[1].sighash_type = None;
+            psbt.inputs[0].sighash_type = Some(single);
+            assert_eq!(
+                ag.sign_multisig_psbt_allowing(&mut psbt, &[single])
+                    .unwrap(),
+                2
+            );
+        }
+    }
+
+    #[test]
+    fn test_single_key_input_skipped() {
+        let ag = AccountGenerator::new(MNEMONICS[0], Network::Testnet)
+            .unwrap()
+            .with_purpose(Purpose::NativeSegwit);
+        let account = ag.get_account(KeychainKind::External, 0).unwrap();
+        let multisig = wallet(2, ScriptHashType::P2wsh);
+        let mut psbt = Psbt::deserialize(&unsigned_psbt(&multisig)).unwrap();
+
+        // a p2wpkh coin of the same seed next to the multisig ones
+        psbt.unsigned_tx.input.push(TxIn {
+            previous_output: OutPoint::new(Txid::all_zeros(), 7),
+            ..Default::default()
+        });
+        let mut input = Input {
+            witness_utxo: Some(TxOut {
+                value: 10_000,
+                script_pubkey: account.script_pubkey(),
+            }),
+            ..Default::default()
+        };
+        input.bip32_derivation.insert(
+            account.public_key().unwrap().inner,
+            (ag.fingerprint(), account.derivation_path().clone()),
+        );
+        psbt.inputs.push(input);
+
+        assert_eq!(ag.sign_multisig_psbt(&mut psbt).unwrap(), 2);
+        assert!(psbt.inputs[2].partial_sigs.is_empty());
+        // the single key signer takes it from there
+        let report = ag.sign_psbt(&mut psbt).unwrap();
+        assert_eq!(report.signed, vec![2]);
+    }
+}
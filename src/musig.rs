@@ -0,0 +1,857 @@
+// MuSig2 (BIP327): n-of-n schnorr signatures for an aggregate key, spent through the
+// taproot key path they look like any single key p2tr output.
+//
+// 1. every signer builds the same `KeyAggContext` from all the public keys (same order)
+// 2. round 1: each signer runs `nonce_gen` and sends its `PubNonce` to the others
+// 3. round 2: with all nonces aggregated, each signer makes a `PartialSignature`
+// 4. anyone aggregates the partial signatures into the final signature
+//
+// the messages have a hex form so the rounds can go through files or any other channel.
+
+use crate::key_pair::Account;
+use crate::secp::secp;
+use anyhow::Error;
+use bitcoin::{
+    hashes::{sha256, Hash, HashEngine},
+    key::XOnlyPublicKey,
+    secp256k1::{
+        constants::CURVE_ORDER,
+        rand::{thread_rng, RngCore},
+        schnorr, PublicKey, Scalar, SecretKey,
+    },
+    taproot::{TapNodeHash, TapTweakHash},
+    Address, Network,
+};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+fn tagged_hash(tag: &str, parts: &[&[u8]]) -> [u8; 32] {
+    let tag_hash = sha256::Hash::hash(tag.as_bytes());
+    let mut engine = sha256::Hash::engine();
+    engine.input(tag_hash.as_ref());
+    engine.input(tag_hash.as_ref());
+    for part in parts {
+        engine.input(part);
+    }
+    sha256::Hash::from_engine(engine).to_byte_array()
+}
+
+// an integer mod the curve order, None is zero. SecretKey does the arithmetic.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct ModScalar(Option<SecretKey>);
+
+impl ModScalar {
+    const ZERO: ModScalar = ModScalar(None);
+
+    fn one() -> Self {
+        let mut one = [0u8; 32];
+        one[31] = 1;
+        ModScalar(SecretKey::from_slice(&one).ok())
+    }
+
+    // int(bytes) mod n, a 256 bit value is less than 2n so one subtraction is enough
+    fn reduce(mut bytes: [u8; 32]) -> Self {
+        if bytes >= CURVE_ORDER {
+            let mut borrow = 0i16;
+            for i in (0..32).rev() {
+                let diff = bytes[i] as i16 - CURVE_ORDER[i] as i16 - borrow;
+                borrow = (diff < 0) as i16;
+                bytes[i] = diff.rem_euclid(256) as u8;
+            }
+        }
+        ModScalar(SecretKey::from_slice(&bytes).ok())
+    }
+
+    fn add(self, other: Self) -> Self {
+        match (self.0, other.0) {
+            (Some(a), Some(b)) => ModScalar(a.add_tweak(&Scalar::from(b)).ok()),
+            (a, None) | (None, a) => ModScalar(a),
+        }
+    }
+
+    fn mul(self, other: Self) -> Self {
+        match (self.0, other.0) {
+            (Some(a), Some(b)) => ModScalar(a.mul_tweak(&Scalar::from(b)).ok()),
+            _ => ModScalar::ZERO,
+        }
+    }
+
+    fn negate(self) -> Self {
+        ModScalar(self.0.map(|k| k.negate()))
+    }
+
+    fn to_bytes(self) -> [u8; 32] {
+        self.0.map(|k| k.secret_bytes()).unwrap_or_default()
+    }
+
+    fn base_point_mul(self) -> Option<PublicKey> {
+        self.0.map(|k| k.public_key(secp()))
+    }
+}
+
+impl std::fmt::Debug for ModScalar {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", hex::encode(self.to_bytes()))
+    }
+}
+
+// points, None is the point at infinity
+fn point_add(a: Option<PublicKey>, b: Option<PublicKey>) -> Option<PublicKey> {
+    match (a, b) {
+        (Some(a), Some(b)) => a.combine(&b).ok(),
+        (a, None) | (None, a) => a,
+    }
+}
+
+fn point_mul(point: Option<PublicKey>, scalar: ModScalar) -> Option<PublicKey> {
+    match (point, scalar.0) {
+        (Some(point), Some(k)) => point.mul_tweak(secp(), &Scalar::from(k)).ok(),
+        _ => None,
+    }
+}
+
+fn has_even_y(point: &PublicKey) -> bool {
+    point.serialize()[0] == 0x02
+}
+
+fn xbytes(point: &PublicKey) -> [u8; 32] {
+    point.x_only_public_key().0.serialize()
+}
+
+// 1 or -1, to flip a point to even y
+fn parity(point: &PublicKey) -> ModScalar {
+    match has_even_y(point) {
+        true => ModScalar::one(),
+        false => ModScalar::one().negate(),
+    }
+}
+
+// KeySort, lets the signers agree on the order without coordinating
+pub fn sort_keys(keys: &[PublicKey]) -> Vec<PublicKey> {
+    let mut keys = keys.to_vec();
+    keys.sort_by_key(|key| key.serialize());
+    keys
+}
+
+#[derive(Clone, Debug)]
+pub struct KeyAggContext {
+    keys: Vec<PublicKey>,
+    // hash of all the keys, input of every coefficient
+    list_hash: [u8; 32],
+    second_key: Option<PublicKey>,
+    internal_key: PublicKey,
+    // aggregate key after the tweaks, with the accumulated sign and tweak
+    q: PublicKey,
+    gacc: ModScalar,
+    tacc: ModScalar,
+}
+
+impl KeyAggContext {
+    // the keys in the order every signer uses, see `sort_keys`
+    pub fn new(keys: &[PublicKey]) -> anyhow::Result<Self> {
+        if keys.is_empty() {
+            return Err(Error::msg("no keys to aggregate"));
+        }
+
+        let serialized = keys.iter().map(|key| key.serialize()).collect::<Vec<_>>();
+        let list_hash = tagged_hash(
+            "KeyAgg list",
+            &serialized.iter().map(|key| &key[..]).collect::<Vec<_>>(),
+        );
+        let second_key = keys.iter().find(|key| **key != keys[0]).copied();
+
+        let mut ctx = Self {
+            keys: keys.to_vec(),
+            list_hash,
+            second_key,
+            internal_key: keys[0],
+            q: keys[0],
+            gacc: ModScalar::one(),
+            tacc: ModScalar::ZERO,
+        };
+        let q = keys.iter().fold(None, |q, key| {
+            point_add(q, point_mul(Some(*key), ctx.coefficient(key)))
+        });
+        ctx.q = q.ok_or_else(|| Error::msg("the aggregate key is the point at infinity"))?;
+        ctx.internal_key = ctx.q;
+
+        Ok(ctx)
+    }
+
+    fn coefficient(&self, key: &PublicKey) -> ModScalar {
+        match Some(*key) == self.second_key {
+            true => ModScalar::one(),
+            false => ModScalar::reduce(tagged_hash(
+                "KeyAgg coefficient",
+                &[&self.list_hash, &key.serialize()],
+            )),
+        }
+    }
+
+    // the BIP341 tweak, the same as a single key p2tr output (BIP86 without a script tree)
+    pub fn with_taproot_tweak(mut self, merkle_root: Option<TapNodeHash>) -> anyhow::Result<Self> {
+        if self.q != self.internal_key {
+            return Err(Error::msg("the aggregate key is already tweaked"));
+        }
+        let tweak = TapTweakHash::from_key_and_tweak(self.internal_x_only_key(), merkle_root);
+        let t = ModScalar::reduce(tweak.to_byte_array());
+
+        // x-only tweak: Q' = g*Q + t*G, with g flipping Q to even y
+        let g = parity(&self.q);
+        self.q = point_add(point_mul(Some(self.q), g), t.base_point_mul())
+            .ok_or_else(|| Error::msg("the tweaked key is the point at infinity"))?;
+        self.gacc = g.mul(self.gacc);
+        self.tacc = t.add(g.mul(self.tacc));
+        Ok(self)
+    }
+
+    pub fn keys(&self) -> &[PublicKey] {
+        &self.keys
+    }
+
+    // the aggregate key before any tweak, the taproot internal key
+    pub fn internal_x_only_key(&self) -> XOnlyPublicKey {
+        self.internal_key.x_only_public_key().0
+    }
+
+    // the key the final signature verifies against, the output key once tweaked
+    pub fn output_key(&self) -> XOnlyPublicKey {
+        self.q.x_only_public_key().0
+    }
+
+    pub fn p2tr_address(&self, merkle_root: Option<TapNodeHash>, network: Network) -> Address {
+        Address::p2tr(secp(), self.internal_x_only_key(), merkle_root, network)
+    }
+}
+
+// the secret half of a nonce, consumed by `SigningSession::sign` so it can't be reused
+pub struct SecNonce {
+    k1: SecretKey,
+    k2: SecretKey,
+    public_key: PublicKey,
+}
+
+impl std::fmt::Debug for SecNonce {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SecNonce")
+            .field("public_key", &self.public_key)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for SecNonce {
+    fn drop(&mut self) {
+        self.k1.non_secure_erase();
+        self.k2.non_secure_erase();
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PubNonce {
+    r1: PublicKey,
+    r2: PublicKey,
+}
+
+impl PubNonce {
+    pub fn serialize(&self) -> [u8; 66] {
+        let mut bytes = [0u8; 66];
+        bytes[..33].copy_from_slice(&self.r1.serialize());
+        bytes[33..].copy_from_slice(&self.r2.serialize());
+        bytes
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> anyhow::Result<Self> {
+        if bytes.len() != 66 {
+            return Err(Error::msg(format!("invalid nonce length: {}", bytes.len())));
+        }
+        Ok(Self {
+            r1: PublicKey::from_slice(&bytes[..33])?,
+            r2: PublicKey::from_slice(&bytes[33..])?,
+        })
+    }
+}
+
+impl Display for PubNonce {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", hex::encode(self.serialize()))
+    }
+}
+
+impl FromStr for PubNonce {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_slice(&hex::decode(s.trim())?)
+    }
+}
+
+// round 1, `msg` is optional but makes the nonce safer against a bad random generator
+pub fn nonce_gen(
+    account: &Account,
+    ctx: &KeyAggContext,
+    msg: Option<&[u8; 32]>,
+) -> anyhow::Result<(SecNonce, PubNonce)> {
+    let keypair = account.reveal_keypair();
+    let secret_key = keypair.secret_key();
+    let public_key = keypair.public_key();
+    if !ctx.keys.contains(&public_key) {
+        return Err(Error::msg(
+            "the account key is not part of the aggregate key",
+        ));
+    }
+
+    let mut rand_ = [0u8; 32];
+    thread_rng().fill_bytes(&mut rand_);
+    let (k1, k2) = nonce_gen_internal(
+        rand_,
+        Some(&secret_key),
+        &public_key,
+        Some(&ctx.output_key().serialize()),
+        msg.map(|msg| &msg[..]),
+        &[],
+    )?;
+
+    let secnonce = SecNonce { k1, k2, public_key };
+    let pubnonce = PubNonce {
+        r1: secnonce.k1.public_key(secp()),
+        r2: secnonce.k2.public_key(secp()),
+    };
+    Ok((secnonce, pubnonce))
+}
+
+// NonceGen with the random bytes `rand_`, the optional inputs are left out when None
+fn nonce_gen_internal(
+    rand_: [u8; 32],
+    secret_key: Option<&SecretKey>,
+    public_key: &PublicKey,
+    aggpk: Option<&[u8; 32]>,
+    msg: Option<&[u8]>,
+    extra_in: &[u8],
+) -> anyhow::Result<(SecretKey, SecretKey)> {
+    // rand = sk XOR H_aux(rand'), the secret key protects against a bad random generator
+    let rand = match secret_key {
+        Some(secret_key) => {
+            let mut rand = tagged_hash("MuSig/aux", &[&rand_]);
+            rand.iter_mut()
+                .zip(secret_key.secret_bytes())
+                .for_each(|(r, k)| *r ^= k);
+            rand
+        }
+        None => rand_,
+    };
+
+    let aggpk = aggpk.map_or(&[][..], |aggpk| &aggpk[..]);
+    let msg_prefixed = match msg {
+        Some(msg) => [&[1u8][..], &(msg.len() as u64).to_be_bytes(), msg].concat(),
+        None => vec![0u8],
+    };
+    let nonce = |i: u8| {
+        ModScalar::reduce(tagged_hash(
+            "MuSig/nonce",
+            &[
+                &rand,
+                &[33],
+                &public_key.serialize(),
+                &[aggpk.len() as u8],
+                aggpk,
+                &msg_prefixed,
+                &(extra_in.len() as u32).to_be_bytes(),
+                extra_in,
+                &[i],
+            ],
+        ))
+        .0
+        .ok_or_else(|| Error::msg("zero nonce"))
+    };
+    Ok((nonce(0)?, nonce(1)?))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AggNonce {
+    r1: Option<PublicKey>,
+    r2: Option<PublicKey>,
+}
+
+impl AggNonce {
+    // the point at infinity is 33 zero bytes
+    pub fn serialize(&self) -> [u8; 66] {
+        let mut bytes = [0u8; 66];
+        if let Some(r1) = self.r1 {
+            bytes[..33].copy_from_slice(&r1.serialize());
+        }
+        if let Some(r2) = self.r2 {
+            bytes[33..].copy_from_slice(&r2.serialize());
+        }
+        bytes
+    }
+}
+
+pub fn nonce_agg(pubnonces: &[PubNonce]) -> anyhow::Result<AggNonce> {
+    if pubnonces.is_empty() {
+        return Err(Error::msg("no nonces to aggregate"));
+    }
+
+    Ok(pubnonces
+        .iter()
+        .fold(AggNonce { r1: None, r2: None }, |agg, pubnonce| AggNonce {
+            r1: point_add(agg.r1, Some(pubnonce.r1)),
+            r2: point_add(agg.r2, Some(pubnonce.r2)),
+        }))
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct PartialSignature(ModScalar);
+
+impl PartialSignature {
+    pub fn serialize(&self) -> [u8; 32] {
+        self.0.to_bytes()
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> anyhow::Result<Self> {
+        let bytes: [u8; 32] = bytes.try_into().map_err(|_| {
+            Error::msg(format!("invalid partial signature length: {}", bytes.len()))
+        })?;
+        if bytes >= CURVE_ORDER {
+            return Err(Error::msg("partial signature out of range"));
+        }
+        Ok(Self(ModScalar::reduce(bytes)))
+    }
+}
+
+impl std::fmt::Debug for PartialSignature {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("PartialSignature")
+            .field(&self.to_string())
+            .finish()
+    }
+}
+
+impl Display for PartialSignature {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", hex::encode(self.serialize()))
+    }
+}
+
+impl FromStr for PartialSignature {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_slice(&hex::decode(s.trim())?)
+    }
+}
+
+// everything round 2 needs: the (tweaked) keys, the aggregate nonce and the message
+#[derive(Clone, Debug)]
+pub struct SigningSession {
+    ctx: KeyAggContext,
+    msg: [u8; 32],
+    b: ModScalar,
+    r: PublicKey,
+    e: ModScalar,
+}
+
+impl SigningSession {
+    pub fn new(ctx: &KeyAggContext, aggnonce: &AggNonce, msg: [u8; 32]) -> anyhow::Result<Self> {
+        let b = ModScalar::reduce(tagged_hash(
+            "MuSig/noncecoef",
+            &[&aggnonce.serialize(), &xbytes(&ctx.q), &msg],
+        ));
+        // R = G if R1 + b*R2 is infinite, so a malicious nonce can't stop the protocol
+        let r = point_add(aggnonce.r1, point_mul(aggnonce.r2, b))
+            .or_else(|| ModScalar::one().base_point_mul())
+            .ok_or_else(|| Error::msg("no generator"))?;
+        let e = ModScalar::reduce(tagged_hash(
+            "BIP0340/challenge",
+            &[&xbytes(&r), &xbytes(&ctx.q), &msg],
+        ));
+
+        Ok(Self {
+            ctx: ctx.clone(),
+            msg,
+            b,
+            r,
+            e,
+        })
+    }
+
+    pub fn message(&self) -> &[u8; 32] {
+        &self.msg
+    }
+
+    // round 2, the nonce from round 1 is consumed
+    pub fn sign(&self, secnonce: SecNonce, account: &Account) -> anyhow::Result<PartialSignature> {
+        let keypair = account.reveal_keypair();
+        let public_key = keypair.public_key();
+        if secnonce.public_key != public_key {
+            return Err(Error::msg("the nonce was generated for another key"));
+        }
+        if !self.ctx.keys.contains(&public_key) {
+            return Err(Error::msg(
+                "the account key is not part of the aggregate key",
+            ));
+        }
+
+        let r_parity = parity(&self.r);
+        let k1 = ModScalar(Some(secnonce.k1)).mul(r_parity);
+        let k2 = ModScalar(Some(secnonce.k2)).mul(r_parity);
+        let a = self.ctx.coefficient(&public_key);
+        let d = parity(&self.ctx.q)
+            .mul(self.ctx.gacc)
+            .mul(ModScalar(Some(keypair.secret_key())));
+
+        let s = k1.add(self.b.mul(k2)).add(self.e.mul(a).mul(d));
+        let psig = PartialSignature(s);
+
+        let pubnonce = PubNonce {
+            r1: secnonce.k1.public_key(secp()),
+            r2: secnonce.k2.public_key(secp()),
+        };
+        if !self.verify_partial(&psig, &pubnonce, &public_key) {
+            return Err(Error::msg("the partial signature doesn't verify"));
+        }
+        Ok(psig)
+    }
+
+    // catches a cosigner that sent a bad partial signature before aggregating
+    pub fn verify_partial(
+        &self,
+        psig: &PartialSignature,
+        pubnonce: &PubNonce,
+        public_key: &PublicKey,
+    ) -> bool {
+        if !self.ctx.keys.contains(public_key) {
+            return false;
+        }
+
+        let re = point_add(Some(pubnonce.r1), point_mul(Some(pubnonce.r2), self.b));
+        let re = point_mul(re, parity(&self.r));
+        let g = parity(&self.ctx.q).mul(self.ctx.gacc);
+        let ead = self.e.mul(self.ctx.coefficient(public_key)).mul(g);
+
+        psig.0.base_point_mul() == point_add(re, point_mul(Some(*public_key), ead))
+    }
+
+    // the final BIP340 signature for the output key
+    pub fn aggregate(&self, psigs: &[PartialSignature]) -> anyhow::Result<schnorr::Signature> {
+        let tweak = self.e.mul(parity(&self.ctx.q)).mul(self.ctx.tacc);
+        let s = psigs.iter().fold(tweak, |s, psig| s.add(psig.0));
+
+        let signature = schnorr::Signature::from_slice(&[xbytes(&self.r), s.to_bytes()].concat())?;
+        secp()
+            .verify_schnorr(
+                &signature,
+                &bitcoin::secp256k1::Message::from_slice(&self.msg)?,
+                &self.ctx.output_key(),
+            )
+            .map_err(|_| Error::msg("the aggregate signature doesn't verify"))?;
+        Ok(signature)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::key_pair::{Account, AccountGenerator};
+    use crate::musig::{
+        nonce_agg, nonce_gen, nonce_gen_internal, sort_keys, KeyAggContext, PartialSignature,
+        PubNonce, SecNonce, SigningSession,
+    };
+    use crate::secp::secp;
+    use bitcoin::absolute::LockTime;
+    use bitcoin::hashes::Hash;
+    use bitcoin::secp256k1::{PublicKey, SecretKey};
+    use bitcoin::sighash::{Prevouts, SighashCache, TapSighashType};
+    use bitcoin::{Network, PrivateKey, Transaction, TxIn, TxOut, Witness};
+    use std::str::FromStr;
+
+    // key_agg_vectors.json of BIP327
+    #[test]
+    fn test_key_agg_vectors() {
+        let keys = [
+            "02F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9",
+            "03DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659",
+            "023590A94E768F8E1815C2F24B4D80A8E3149316C3518CE7B7AD338368D038CA66",
+        ]
+        .map(|key| PublicKey::from_str(key).unwrap());
+
+        for (indices, expected) in [
+            (
+                vec![0, 1, 2],
+                "90539eede565f5d054f32cc0c220126889ed1e5d193baf15aef344fe59d4610c",
+            ),
+            (
+                vec![2, 1, 0],
+                "6204de8b083426dc6eaf9502d27024d53fc826bf7d2012148a0575435df54b2b",
+            ),
+            (
+                vec![0, 0, 0],
+                "b436e3bad62b8cd409969a224731c193d051162d8c5ae8b109306127da3aa935",
+            ),
+            (
+                vec![0, 0, 1, 1],
+                "69bc22bfa5d106306e48a20679de1d7389386124d07571d0d872686028c26a3e",
+            ),
+        ] {
+            let keys = indices.iter().map(|i| keys[*i]).collect::<Vec<_>>();
+            let ctx = KeyAggContext::new(&keys).unwrap();
+            assert_eq!(ctx.internal_x_only_key().to_string(), expected);
+        }
+    }
+
+    // nonce_gen_vectors.json of BIP327, rand_ is 0f..0f in every case
+    #[test]
+    fn test_nonce_gen_vectors() {
+        let secret_key = SecretKey::from_slice(&[0x02; 32]).unwrap();
+        let public_key = PublicKey::from_str(
+            "024D4B6CD1361032CA9BD2AEB9D900AA4D45D9EAD80AC9423374C451A7254D0766",
+        )
+        .unwrap();
+        assert_eq!(secret_key.public_key(secp()), public_key);
+        let long_msg = [0x26; 38];
+
+        for (secret_key, public_key, aggpk, msg, extra_in, expected_secnonce, expected_pubnonce) in [
+            (
+                Some(&secret_key),
+                public_key,
+                Some(&[0x07; 32]),
+                Some(&[0x01; 32][..]),
+                &[0x08; 32][..],
+                "B114E502BEAA4E301DD08A50264172C84E41650E6CB726B410C0694D59EFFB6495B5CAF28D045B973D63E3C99A44B807BDE375FD6CB39E46DC4A511708D0E9D2",
+                "02F7BE7089E8376EB355272368766B17E88E7DB72047D05E56AA881EA52B3B35DF02C29C8046FDD0DED4C7E55869137200FBDBFE2EB654267B6D7013602CAED3115A",
+            ),
+            (
+                Some(&secret_key),
+                public_key,
+                Some(&[0x07; 32]),
+                Some(&[][..]),
+                &[0x08; 32][..],
+                "E862B068500320088138468D47E0E6F147E01B6024244AE45EAC40ACE5929B9F0789E051170B9E705D0B9EB49049A323BBBBB206D8E05C19F46C6228742AA7A9",
+                "023034FA5E2679F01EE66E12225882A7A48CC66719B1B9D3B6C4DBD743EFEDA2C503F3FD6F01EB3A8E9CB315D73F1F3D287CAFBB44AB321153C6287F407600205109",
+            ),
+            (
+                Some(&secret_key),
+                public_key,
+                Some(&[0x07; 32]),
+                Some(&long_msg[..]),
+                &[0x08; 32][..],
+                "3221975ACBDEA6820EABF02A02B7F27D3A8EF68EE42787B88CBEFD9AA06AF3632EE85B1A61D8EF31126D4663A00DD96E9D1D4959E72D70FE5EBB6E7696EBA66F",
+                "02E5BBC21C69270F59BD634FCBFA281BE9D76601295345112C58954625BF23793A021307511C79F95D38ACACFF1B4DA98228B77E65AA216AD075E9673286EFB4EAF3",
+            ),
+            (
+                None,
+                PublicKey::from_str(
+                    "02F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9",
+                )
+                .unwrap(),
+                None,
+                None,
+                &[][..],
+                "89BDD787D0284E5E4D5FC572E49E316BAB7E21E3B1830DE37DFE80156FA41A6D0B17AE8D024C53679699A6FD7944D9C4A366B514BAF43088E0708B1023DD2897",
+                "02C96E7CB1E8AA5DAC64D872947914198F607D90ECDE5200DE52978AD5DED63C000299EC5117C2D29EDEE8A2092587C3909BE694D5CFF0667D6C02EA4059F7CD9786",
+            ),
+        ] {
+            let (k1, k2) =
+                nonce_gen_internal([0x0f; 32], secret_key, &public_key, aggpk, msg, extra_in)
+                    .unwrap();
+            let pubnonce = PubNonce {
+                r1: k1.public_key(secp()),
+                r2: k2.public_key(secp()),
+            };
+            assert_eq!(
+                hex::encode_upper([k1.secret_bytes(), k2.secret_bytes()].concat()),
+                expected_secnonce
+            );
+            assert_eq!(pubnonce.to_string(), expected_pubnonce.to_lowercase());
+        }
+    }
+
+    // sign_verify_vectors.json of BIP327, the cases with a 32 byte message
+    #[test]
+    fn test_sign_verify_vectors() {
+        let secret_key =
+            SecretKey::from_str("7FB9E0E687ADA1EEBF7ECFE2F21E73EBDB51A7D450948DFE8D76D7F2D1007671")
+                .unwrap();
+        let account = Account::from(PrivateKey::new(secret_key, Network::Bitcoin));
+        let keys = [
+            "03935F972DA013F80AE011890FA89B67A27B7BE6CCB24D3274D18B2D4067F261A9",
+            "02F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9",
+            "02DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA661",
+        ]
+        .map(|key| PublicKey::from_str(key).unwrap());
+        let secnonce = hex::decode("508B81A611F100A6B2B6B29656590898AF488BCF2E1F55CF22E5CFB84421FE61FA27FD49B1D50085B481285E1CA205D55C82CC1B31FF5CD54A489829355901F7").unwrap();
+        let pubnonces = [
+            "0337C87821AFD50A8644D820A8F3E02E499C931865C2360FB43D0A0D20DAFE07EA0287BF891D2A6DEAEBADC909352AA9405D1428C15F4B75F04DAE642A95C2548480",
+            "0279BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F817980279BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F81798",
+            "032DE2662628C90B03F5E720284EB52FF7D71F4284F627B68A853D78C78E1FFE9303E4C5524E83FFE1493B9077CF1CA6BEB2090C93D930321071AD40B2F44E599046",
+            "0237C87821AFD50A8644D820A8F3E02E499C931865C2360FB43D0A0D20DAFE07EA0387BF891D2A6DEAEBADC909352AA9405D1428C15F4B75F04DAE642A95C2548480",
+        ]
+        .map(|pubnonce| PubNonce::from_str(pubnonce).unwrap());
+        let aggnonces = [
+            "028465FCF0BBDBCF443AABCCE533D42B4B5A10966AC09A49655E8C42DAAB8FCD61037496A3CC86926D452CAFCFD55D25972CA1675D549310DE296BFF42F72EEEA8C9",
+            "000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000",
+        ];
+        let msg = <[u8; 32]>::try_from(
+            hex::decode("F95466D086770E689964664219266FE5ED215C92AE20BAB5C9D79ADDDDF3C0CF")
+                .unwrap(),
+        )
+        .unwrap();
+
+        for (key_indices, nonce_indices, aggnonce_index, signer_index, expected) in [
+            (
+                vec![0, 1, 2],
+                vec![0, 1, 2],
+                0,
+                0,
+                "012ABBCB52B3016AC03AD82395A1A415C48B93DEF78718E62A7A90052FE224FB",
+            ),
+            (
+                vec![1, 0, 2],
+                vec![1, 0, 2],
+                0,
+                1,
+                "9FF2F7AAA856150CC8819254218D3ADEEB0535269051897724F9DB3789513A52",
+            ),
+            (
+                vec![1, 2, 0],
+                vec![1, 2, 0],
+                0,
+                2,
+                "FA23C359F6FAC4E7796BB93BC9F0532A95468C539BA20FF86D7C76ED92227900",
+            ),
+            // the nonces add up to the point at infinity
+            (
+                vec![0, 1],
+                vec![0, 3],
+                1,
+                0,
+                "AE386064B26105404798F75DE2EB9AF5EDA5387B064B83D049CB7C5E08879531",
+            ),
+        ] {
+            let keys = key_indices.iter().map(|i| keys[*i]).collect::<Vec<_>>();
+            let pubnonces = nonce_indices
+                .iter()
+                .map(|i| pubnonces[*i])
+                .collect::<Vec<_>>();
+            let ctx = KeyAggContext::new(&keys).unwrap();
+            let aggnonce = nonce_agg(&pubnonces).unwrap();
+            assert_eq!(
+                hex::encode_upper(aggnonce.serialize()),
+                aggnonces[aggnonce_index]
+            );
+
+            let session = SigningSession::new(&ctx, &aggnonce, msg).unwrap();
+            let secnonce = SecNonce {
+                k1: SecretKey::from_slice(&secnonce[..32]).unwrap(),
+                k2: SecretKey::from_slice(&secnonce[32..]).unwrap(),
+                public_key: keys[signer_index],
+            };
+            let psig = session.sign(secnonce, &account).unwrap();
+            assert_eq!(hex::encode_upper(psig.serialize()), expected);
+
+            // partial_sig_verify
+            assert!(session.verify_partial(&psig, &pubnonces[signer_index], &keys[signer_index]));
+            let negated = PartialSignature(psig.0.negate());
+            assert!(!session.verify_partial(
+                &negated,
+                &pubnonces[signer_index],
+                &keys[signer_index]
+            ));
+            let other = (signer_index + 1) % keys.len();
+            assert!(!session.verify_partial(&psig, &pubnonces[other], &keys[other]));
+        }
+
+        // exceeds the group size
+        assert!(PartialSignature::from_str(
+            "FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFEBAAEDCE6AF48A03BBFD25E8CD0364141"
+        )
+        .is_err());
+    }
+
+    const MNEMONICS: [&str; 3] = [
+        "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about",
+        "legal winner thank year wave sausage worth useful legal winner thank yellow",
+        "letter advice cage absurd amount doctor acoustic avoid letter advice cage above",
+    ];
+
+    fn accounts() -> Vec<Account> {
+        MNEMONICS
+            .iter()
+            .map(|mnemonic| {
+                AccountGenerator::new(mnemonic, Network::Bitcoin)
+                    .unwrap()
+                    .get_account_from_index(0)
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_key_path_spend() {
+        let accounts = accounts();
+        let keys = sort_keys(
+            &accounts
+                .iter()
+                .map(|account| account.public_key().unwrap().inner)
+                .collect::<Vec<_>>(),
+        );
+        let ctx = KeyAggContext::new(&keys)
+            .unwrap()
+            .with_taproot_tweak(None)
+            .unwrap();
+
+        let address = ctx.p2tr_address(None, Network::Bitcoin);
+        let prevouts = [TxOut {
+            value: 100_000,
+            script_pubkey: address.script_pubkey(),
+        }];
+        let tx = Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn::default()],
+            output: vec![TxOut {
+                value: 99_000,
+                script_pubkey: address.script_pubkey(),
+            }],
+        };
+        let sighash = SighashCache::new(&tx)
+            .taproot_key_spend_signature_hash(0, &Prevouts::All(&prevouts), TapSighashType::Default)
+            .unwrap();
+        let msg = sighash.to_byte_array();
+
+        // round 1, only the hex of the public nonces leaves each signer
+        let (secnonces, pubnonces): (Vec<_>, Vec<_>) = accounts
+            .iter()
+            .map(|account| {
+                let (secnonce, pubnonce) = nonce_gen(account, &ctx, Some(&msg)).unwrap();
+                (secnonce, pubnonce.to_string())
+            })
+            .unzip();
+        let pubnonces = pubnonces
+            .iter()
+            .map(|pubnonce| PubNonce::from_str(pubnonce).unwrap())
+            .collect::<Vec<_>>();
+        let session = SigningSession::new(&ctx, &nonce_agg(&pubnonces).unwrap(), msg).unwrap();
+
+        // round 2
+        let psigs = secnonces
+            .into_iter()
+            .zip(&accounts)
+            .map(|(secnonce, account)| session.sign(secnonce, account).unwrap().to_string())
+            .map(|psig| PartialSignature::from_str(&psig).unwrap())
+            .collect::<Vec<_>>();
+        for ((psig, pubnonce), account) in psigs.iter().zip(&pubnonces).zip(&accounts) {
+            assert!(session.verify_partial(psig, pubnonce, &account.public_key().unwrap().inner));
+        }
+        assert!(!session.verify_partial(&psigs[0], &pubnonces[1], &keys[1]));
+
+        let signature = session.aggregate(&psigs).unwrap();
+        let witness = Witness::from_slice(&[signature.as_ref()]);
+        assert_eq!(witness.len(), 1);
+        assert_eq!(
+            address.script_pubkey().as_bytes()[2..],
+            ctx.output_key().serialize()
+        );
+
+        // a missing partial signature doesn't aggregate
+        assert!(session.aggregate(&psigs[..2]).is_err());
+    }
+}
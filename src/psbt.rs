@@ -0,0 +1,1011 @@
+// the BIP174 roles around a psbt, so other wallets and signers can take part in a spend:
+// creator (`create_psbt`), updater (`update_input`), signer (`Signer::sign_psbt`,
+// `AccountGenerator::sign_psbt` and `sign_multisig_psbt`), combiner (`combine_psbts`),
+// finalizer (`finalize_psbt`) and extractor (`extract_tx`). psbts go to and from files
+// either as binary (the .psbt files of core and most wallets) or as base64 text.
+
+use crate::error::Error as BtcError;
+use crate::key_pair::{Account, AccountGenerator, KeychainKind, Purpose};
+use crate::multisig::finalize_multisig_input;
+use crate::secp::secp;
+use crate::signer::{psbt_utxo, InputSignature, Signer, SingleKeyType};
+use anyhow::Error;
+use bitcoin::{
+    bip32::{DerivationPath, Fingerprint, KeySource},
+    key::{KeyPair, XOnlyPublicKey},
+    psbt::{Input, Output, Psbt, PsbtSighashType},
+    secp256k1, ScriptBuf, Transaction, TxOut,
+};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+// the first bytes of a binary psbt
+const PSBT_MAGIC: &[u8] = b"psbt\xff";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum PsbtFormat {
+    #[default]
+    Binary,
+    Base64,
+}
+
+// creator: a psbt of `tx` with no input data yet, scriptSigs and witnesses already in the
+// tx are dropped
+pub fn create_psbt(mut tx: Transaction) -> anyhow::Result<Psbt> {
+    for input in tx.input.iter_mut() {
+        input.script_sig = Default::default();
+        input.witness = Default::default();
+    }
+    Ok(Psbt::from_unsigned_tx(tx)?)
+}
+
+// updater: the output spent by input `index`. segwit inputs get it as witness_utxo, legacy
+// ones need the whole previous transaction (`previous_tx`) as non_witness_utxo since their
+// signatures don't commit to the value. p2sh might wrap a witness program, it gets both.
+// segwit v0 gets the previous transaction too when there is one, hardware signers ask for
+// it against the fee attack on v0 signatures, taproot commits to every input amount.
+pub fn update_input(
+    psbt: &mut Psbt,
+    index: usize,
+    utxo: TxOut,
+    previous_tx: Option<Transaction>,
+) -> anyhow::Result<()> {
+    let outpoint = psbt
+        .unsigned_tx
+        .input
+        .get(index)
+        .ok_or_else(|| Error::msg(format!("no input {index}")))?
+        .previous_output;
+    if let Some(previous_tx) = &previous_tx {
+        if previous_tx.txid() != outpoint.txid
+            || previous_tx.output.get(outpoint.vout as usize) != Some(&utxo)
+        {
+            return Err(Error::msg(format!(
+                "the previous transaction of input {index} doesn't have {outpoint}"
+            )));
+        }
+    }
+
+    let script_pubkey = &utxo.script_pubkey;
+    let is_witness = script_pubkey.is_witness_program();
+    if !is_witness && !script_pubkey.is_p2sh() && previous_tx.is_none() {
+        return Err(BtcError::MissingPreviousTx(outpoint).into());
+    }
+
+    let is_taproot = script_pubkey.is_v1_p2tr();
+    let input = &mut psbt.inputs[index];
+    if is_witness || script_pubkey.is_p2sh() {
+        input.witness_utxo = Some(utxo);
+    }
+    if !is_taproot && previous_tx.is_some() {
+        input.non_witness_utxo = previous_tx;
+    }
+
+    Ok(())
+}
+
+// updater: `update_input` for every input, `utxos[i]` is spent by input i
+pub fn update_inputs(psbt: &mut Psbt, utxos: &[TxOut]) -> anyhow::Result<()> {
+    if utxos.len() != psbt.inputs.len() {
+        return Err(Error::msg(format!(
+            "{} inputs but {} utxos",
+            psbt.inputs.len(),
+            utxos.len()
+        )));
+    }
+
+    utxos
+        .iter()
+        .enumerate()
+        .try_for_each(|(index, utxo)| update_input(psbt, index, utxo.clone(), None))
+}
+
+// combiner: merges the signatures and data of copies of the same psbt
+pub fn combine_psbts(psbts: Vec<Psbt>) -> anyhow::Result<Psbt> {
+    let mut psbts = psbts.into_iter();
+    let mut combined = psbts
+        .next()
+        .ok_or_else(|| Error::msg("no psbt to combine"))?;
+    for psbt in psbts {
+        combined.combine(psbt)?;
+    }
+    Ok(combined)
+}
+
+// finalizer: the final scriptSig and witness of every input not finalized yet, single key
+// inputs (p2pkh, p2sh-p2wpkh, p2wpkh and p2tr key path) and CHECKMULTISIG script hashes
+pub fn finalize_psbt(psbt: &mut Psbt) -> anyhow::Result<()> {
+    for index in 0..psbt.inputs.len() {
+        let utxo = psbt_utxo(psbt, index)?;
+        let input = &mut psbt.inputs[index];
+        if is_finalized(input) {
+            continue;
+        }
+
+        if input.witness_script.is_some() || is_script_hash_multisig(input) {
+            finalize_multisig_input(index, input)?;
+            continue;
+        }
+
+        let utxo = utxo.ok_or_else(|| Error::msg(format!("input {index} has no utxo")))?;
+        let (script_sig, witness) = match input.tap_key_sig {
+            Some(signature) if utxo.script_pubkey.is_v1_p2tr() => {
+                SingleKeyType::P2tr.satisfy(&InputSignature::TapKey(signature))?
+            }
+            _ => {
+                let (kind, public_key, signature) = input
+                    .partial_sigs
+                    .iter()
+                    .find_map(|(public_key, signature)| {
+                        SingleKeyType::from_public_key(&utxo.script_pubkey, public_key)
+                            .map(|kind| (kind, *public_key, *signature))
+                    })
+                    .ok_or_else(|| Error::msg(format!("input {index} has no signature")))?;
+                kind.satisfy(&InputSignature::Ecdsa(public_key, signature))?
+            }
+        };
+        input.final_script_sig = Some(script_sig).filter(|script_sig| !script_sig.is_empty());
+        input.final_script_witness = Some(witness).filter(|witness| !witness.is_empty());
+
+        // clear everything but the utxos and the final fields, as BIP174 asks of the finalizer
+        input.partial_sigs.clear();
+        input.sighash_type = None;
+        input.redeem_script = None;
+        input.witness_script = None;
+        input.bip32_derivation.clear();
+        input.tap_key_sig = None;
+        input.tap_internal_key = None;
+        input.tap_key_origins.clear();
+        input.tap_merkle_root = None;
+    }
+
+    Ok(())
+}
+
+fn is_finalized(input: &Input) -> bool {
+    input.final_script_sig.is_some() || input.final_script_witness.is_some()
+}
+
+// a legacy p2sh input with a redeem script that isn't a p2sh-p2wpkh witness program
+fn is_script_hash_multisig(input: &Input) -> bool {
+    input
+        .redeem_script
+        .as_ref()
+        .is_some_and(|redeem_script| !redeem_script.is_witness_program())
+}
+
+// extractor: the signed transaction, once every input is finalized
+pub fn extract_tx(psbt: Psbt) -> anyhow::Result<Transaction> {
+    if let Some(index) = psbt.inputs.iter().position(|input| !is_finalized(input)) {
+        return Err(Error::msg(format!("input {index} is not finalized")));
+    }
+
+    // the fee is only known with every utxo
+    let input_value = (0..psbt.inputs.len())
+        .map(|index| Ok(psbt_utxo(&psbt, index)?.map(|utxo| utxo.value)))
+        .collect::<anyhow::Result<Vec<_>>>()?
+        .into_iter()
+        .sum::<Option<u64>>();
+    let output_value = psbt
+        .unsigned_tx
+        .output
+        .iter()
+        .map(|output| output.value)
+        .sum();
+    if let Some(input_value) = input_value.filter(|input_value| *input_value < output_value) {
+        return Err(BtcError::InsufficientFunds {
+            needed: output_value,
+            available: input_value,
+        }
+        .into());
+    }
+
+    Ok(psbt.extract_tx())
+}
+
+// a binary or base64 psbt, whichever the file holds
+pub fn read_psbt(path: impl AsRef<Path>) -> anyhow::Result<Psbt> {
+    Ok(read_psbt_with_format(path)?.0)
+}
+
+// the psbt and the format it was in, to write it back the same way
+pub fn read_psbt_with_format(path: impl AsRef<Path>) -> anyhow::Result<(Psbt, PsbtFormat)> {
+    let bytes = std::fs::read(path)?;
+    if bytes.starts_with(PSBT_MAGIC) {
+        return Ok((Psbt::deserialize(&bytes)?, PsbtFormat::Binary));
+    }
+
+    let psbt = Psbt::from_str(std::str::from_utf8(&bytes)?.trim())?;
+    Ok((psbt, PsbtFormat::Base64))
+}
+
+pub fn write_psbt(path: impl AsRef<Path>, psbt: &Psbt, format: PsbtFormat) -> anyhow::Result<()> {
+    let bytes = match format {
+        PsbtFormat::Binary => psbt.serialize(),
+        PsbtFormat::Base64 => psbt.to_string().into_bytes(),
+    };
+    Ok(std::fs::write(path, bytes)?)
+}
+
+// `<dir>/<txid>.psbt`, for review or an external signer
+pub fn write_unsigned_psbt(dir: impl AsRef<Path>, psbt: &Psbt) -> anyhow::Result<PathBuf> {
+    let path = dir
+        .as_ref()
+        .join(format!("{}.psbt", psbt.unsigned_tx.txid()));
+    write_psbt(&path, psbt, PsbtFormat::Binary)?;
+    Ok(path)
+}
+
+// why `AccountGenerator::sign_psbt` left an input alone
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SkipReason {
+    // none of the key origins has our fingerprint, someone else's input
+    Foreign,
+    Finalized,
+    // our fingerprint, but the key at the path isn't the one in the psbt
+    KeyMismatch(DerivationPath),
+    // a script hash or taproot script path spend, multisig inputs go through
+    // `sign_multisig_psbt`
+    Unsupported,
+    NoUtxo,
+    // a sighash type the caller didn't allow, e.g. NONE which leaves the outputs open
+    SighashType(PsbtSighashType),
+}
+
+impl Display for SkipReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SkipReason::Foreign => write!(f, "no key of ours"),
+            SkipReason::Finalized => write!(f, "already finalized"),
+            SkipReason::KeyMismatch(path) => write!(f, "the key at {path} is not ours"),
+            SkipReason::Unsupported => write!(f, "not a single key script of ours"),
+            SkipReason::NoUtxo => write!(f, "no utxo"),
+            SkipReason::SighashType(sighash_type) => {
+                write!(f, "sighash type {sighash_type} is not allowed")
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SigningReport {
+    pub signed: Vec<usize>,
+    pub skipped: Vec<(usize, SkipReason)>,
+}
+
+impl<'a> AccountGenerator<'a> {
+    // signs the inputs of a psbt from someone else (a marketplace, a partner) that spend our
+    // single key outputs. our inputs are the ones with a key origin of our fingerprint in
+    // bip32_derivation or tap_key_origins (key path only), the accounts are derived again
+    // from the origin paths. the other inputs are left as they are, so are ours asking for
+    // a sighash type other than ALL or DEFAULT.
+    pub fn sign_psbt(&self, psbt: &mut Psbt) -> anyhow::Result<SigningReport> {
+        self.sign_psbt_allowing(psbt, &[])
+    }
+
+    // like `sign_psbt`, our inputs may also ask for one of `sighash_types`
+    pub fn sign_psbt_allowing(
+        &self,
+        psbt: &mut Psbt,
+        sighash_types: &[PsbtSighashType],
+    ) -> anyhow::Result<SigningReport> {
+        let secp = secp();
+        let mut report = SigningReport::default();
+        let mut accounts = vec![];
+        let mut ours = vec![];
+
+        for (index, input) in psbt.inputs.iter().enumerate() {
+            if is_finalized(input) {
+                report.skipped.push((index, SkipReason::Finalized));
+                continue;
+            }
+
+            let origins = input
+                .bip32_derivation
+                .iter()
+                .map(|(key, source)| (key.x_only_public_key().0, source))
+                .chain(
+                    input
+                        .tap_key_origins
+                        .iter()
+                        .filter(|(_, (leaf_hashes, _))| leaf_hashes.is_empty())
+                        .map(|(key, (_, source))| (*key, source)),
+                )
+                .filter(|(_, (fingerprint, _))| *fingerprint == self.fingerprint())
+                .map(|(key, (_, path))| (key, path.clone()))
+                .collect::<Vec<_>>();
+            if origins.is_empty() {
+                report.skipped.push((index, SkipReason::Foreign));
+                continue;
+            }
+
+            let mut input_accounts = vec![];
+            let mut mismatch = None;
+            for (key, path) in origins {
+                match self.derive_priv(&path) {
+                    Ok(xprv) if XOnlyPublicKey::from(xprv.private_key.public_key(secp)) == key => {
+                        input_accounts.push(Account::new(
+                            KeyPair::from_secret_key(secp, &xprv.private_key),
+                            *self.network(),
+                            path,
+                        ))
+                    }
+                    _ => mismatch = Some(path),
+                }
+            }
+            if let (true, Some(path)) = (input_accounts.is_empty(), mismatch) {
+                report.skipped.push((index, SkipReason::KeyMismatch(path)));
+                continue;
+            }
+
+            let Some(utxo) = psbt_utxo(psbt, index)? else {
+                report.skipped.push((index, SkipReason::NoUtxo));
+                continue;
+            };
+            let signer = Signer::new(input_accounts.clone())?;
+            if signer.owner(&utxo.script_pubkey).is_none() {
+                report.skipped.push((index, SkipReason::Unsupported));
+                continue;
+            }
+            if let Some(sighash_type) = input.sighash_type.filter(|sighash_type| {
+                !signer.allows_sighash(*sighash_type) && !sighash_types.contains(sighash_type)
+            }) {
+                report
+                    .skipped
+                    .push((index, SkipReason::SighashType(sighash_type)));
+                continue;
+            }
+
+            accounts.extend(input_accounts);
+            ours.push(index);
+        }
+
+        let signer = sighash_types
+            .iter()
+            .fold(Signer::new(accounts)?, |signer, sighash_type| {
+                signer.allow_sighash(*sighash_type)
+            });
+        report.signed = signer.sign_psbt_inputs(psbt, &ours)?;
+        Ok(report)
+    }
+}
+
+// inputs and outputs `AccountGenerator::update_psbt` found to be ours
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UpdateReport {
+    pub inputs: Vec<usize>,
+    pub outputs: Vec<usize>,
+}
+
+// what a psbt input or output says about one of our keys
+enum KeyMetadata {
+    // with the p2wpkh redeem script for p2sh-p2wpkh
+    Ecdsa(secp256k1::PublicKey, KeySource, Option<ScriptBuf>),
+    Taproot(XOnlyPublicKey, KeySource),
+}
+
+impl KeyMetadata {
+    fn new(
+        account: &Account,
+        kind: SingleKeyType,
+        fingerprint: Fingerprint,
+    ) -> anyhow::Result<Self> {
+        let source = (fingerprint, account.derivation_path().clone());
+        let public_key = account.public_key()?;
+        Ok(match kind {
+            SingleKeyType::P2tr => KeyMetadata::Taproot(account.x_only_public_key(), source),
+            SingleKeyType::P2shP2wpkh => KeyMetadata::Ecdsa(
+                public_key.inner,
+                source,
+                Some(ScriptBuf::new_v0_p2wpkh(
+                    &public_key
+                        .wpubkey_hash()
+                        .ok_or_else(|| Error::msg("p2wpkh needs a compressed key"))?,
+                )),
+            ),
+            _ => KeyMetadata::Ecdsa(public_key.inner, source, None),
+        })
+    }
+
+    fn update_input(self, input: &mut Input) {
+        match self {
+            KeyMetadata::Ecdsa(public_key, source, redeem_script) => {
+                input.bip32_derivation.insert(public_key, source);
+                input.redeem_script = redeem_script.or(input.redeem_script.take());
+            }
+            KeyMetadata::Taproot(key, source) => {
+                input.tap_internal_key = Some(key);
+                input.tap_key_origins.insert(key, (vec![], source));
+            }
+        }
+    }
+
+    fn update_output(self, output: &mut Output) {
+        match self {
+            KeyMetadata::Ecdsa(public_key, source, redeem_script) => {
+                output.bip32_derivation.insert(public_key, source);
+                output.redeem_script = redeem_script.or(output.redeem_script.take());
+            }
+            KeyMetadata::Taproot(key, source) => {
+                output.tap_internal_key = Some(key);
+                output.tap_key_origins.insert(key, (vec![], source));
+            }
+        }
+    }
+}
+
+impl<'a> AccountGenerator<'a> {
+    // creator and updater in one go, for `tx` spending `utxos` (one per input) of this
+    // wallet. `previous_txs` are the transactions they come from, required for legacy
+    // inputs. our inputs and outputs are annotated within the first `lookahead` indexes.
+    pub fn create_psbt(
+        &self,
+        tx: Transaction,
+        utxos: &[TxOut],
+        previous_txs: &[Transaction],
+        lookahead: u32,
+    ) -> anyhow::Result<Psbt> {
+        let mut psbt = create_psbt(tx)?;
+        if utxos.len() != psbt.inputs.len() {
+            return Err(Error::msg(format!(
+                "{} inputs but {} utxos",
+                psbt.inputs.len(),
+                utxos.len()
+            )));
+        }
+        for (index, utxo) in utxos.iter().enumerate() {
+            let txid = psbt.unsigned_tx.input[index].previous_output.txid;
+            let previous_tx = previous_txs.iter().find(|tx| tx.txid() == txid).cloned();
+            update_input(&mut psbt, index, utxo.clone(), previous_tx)?;
+        }
+        self.update_psbt(&mut psbt, &[], lookahead)?;
+        Ok(psbt)
+    }
+
+    // updater: the key origins of every input and output paying to one of our single key
+    // scripts, of any purpose, within the first `lookahead` indexes of both keychains, so a hardware signer
+    // can sign our inputs and recognize our change. `previous_txs` are the transactions
+    // the inputs spend from, they fill in witness_utxo and non_witness_utxo.
+    pub fn update_psbt(
+        &self,
+        psbt: &mut Psbt,
+        previous_txs: &[Transaction],
+        lookahead: u32,
+    ) -> anyhow::Result<UpdateReport> {
+        let previous_txs = previous_txs
+            .iter()
+            .map(|tx| (tx.txid(), tx))
+            .collect::<HashMap<_, _>>();
+        for index in 0..psbt.inputs.len() {
+            let outpoint = psbt.unsigned_tx.input[index].previous_output;
+            if let Some(previous_tx) = previous_txs.get(&outpoint.txid) {
+                let utxo = previous_tx
+                    .output
+                    .get(outpoint.vout as usize)
+                    .ok_or_else(|| Error::msg(format!("{outpoint} doesn't exist")))?;
+                update_input(psbt, index, utxo.clone(), Some((*previous_tx).clone()))?;
+            }
+        }
+
+        // the keys of every purpose, e.g. a bip84 change output of a wallet spending bip86
+        // coins. an imported account key only derives the purpose of its own path. uncompressed
+        // keys are not a thing in bip32 wallets.
+        let mut scripts = HashMap::new();
+        for purpose in Purpose::ALL {
+            let ag = self.clone().with_purpose(purpose);
+            for keychain in KeychainKind::ALL {
+                let accounts = match ag.derive_range(keychain, 0..lookahead) {
+                    Ok(accounts) => accounts,
+                    Err(e)
+                        if matches!(
+                            e.downcast_ref::<BtcError>(),
+                            Some(BtcError::InvalidDerivation { .. })
+                        ) =>
+                    {
+                        continue
+                    }
+                    Err(e) => return Err(e),
+                };
+                for account in accounts {
+                    for kind in [
+                        SingleKeyType::P2pkh,
+                        SingleKeyType::P2shP2wpkh,
+                        SingleKeyType::P2wpkh,
+                        SingleKeyType::P2tr,
+                    ] {
+                        scripts.insert(kind.script_pubkey(&account)?, (account.clone(), kind));
+                    }
+                }
+            }
+        }
+
+        let mut report = UpdateReport::default();
+        for index in 0..psbt.inputs.len() {
+            let Some(utxo) = psbt_utxo(psbt, index)? else {
+                continue;
+            };
+            if let Some((account, kind)) = scripts.get(&utxo.script_pubkey) {
+                KeyMetadata::new(account, *kind, self.fingerprint())?
+                    .update_input(&mut psbt.inputs[index]);
+                report.inputs.push(index);
+            }
+        }
+        for (index, txout) in psbt.unsigned_tx.output.iter().enumerate() {
+            if let Some((account, kind)) = scripts.get(&txout.script_pubkey) {
+                KeyMetadata::new(account, *kind, self.fingerprint())?
+                    .update_output(&mut psbt.outputs[index]);
+                report.outputs.push(index);
+            }
+        }
+
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::discovery::DEFAULT_GAP_LIMIT;
+    use crate::error::Error as BtcError;
+    use crate::key_pair::{AccountGenerator, KeychainKind, Purpose};
+    use crate::psbt::{
+        combine_psbts, create_psbt, extract_tx, finalize_psbt, read_psbt, read_psbt_with_format,
+        update_input, update_inputs, write_psbt, write_unsigned_psbt, PsbtFormat, SkipReason,
+        UpdateReport,
+    };
+    use crate::secp::secp;
+    use crate::signer::{psbt_utxo, Signer};
+    use bitcoin::absolute::LockTime;
+    use bitcoin::ecdsa;
+    use bitcoin::hashes::Hash;
+    use bitcoin::psbt::PsbtSighashType;
+    use bitcoin::secp256k1::{Message, XOnlyPublicKey};
+    use bitcoin::sighash::{EcdsaSighashType, Prevouts, SighashCache, TapSighashType};
+    use bitcoin::taproot;
+    use bitcoin::{
+        Network, OutPoint, PublicKey, ScriptBuf, Sequence, Transaction, TxIn, TxOut, WPubkeyHash,
+    };
+    use std::path::PathBuf;
+
+    const MNEMONIC: &str =
+        "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+    const OTHER_MNEMONIC: &str =
+        "legal winner thank year wave sausage worth useful legal winner thank yellow";
+
+    // a previous tx paying 50_000 sats to `script_pubkey`, and a tx spending it
+    fn spend(script_pubkey: ScriptBuf) -> (Transaction, Transaction) {
+        let previous_tx = Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn::default()],
+            output: vec![TxOut {
+                value: 50_000,
+                script_pubkey,
+            }],
+        };
+        let tx = Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(previous_tx.txid(), 0),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: 40_000,
+                script_pubkey: ScriptBuf::new_v0_p2wpkh(&WPubkeyHash::all_zeros()),
+            }],
+        };
+        (previous_tx, tx)
+    }
+
+    #[test]
+    fn test_sighash_none_is_not_signed() {
+        for purpose in [Purpose::NativeSegwit, Purpose::Taproot] {
+            let ag = AccountGenerator::new(MNEMONIC, Network::Testnet)
+                .unwrap()
+                .with_purpose(purpose);
+            let account = ag.get_account(KeychainKind::External, 0).unwrap();
+            let (previous_tx, tx) = spend(account.script_pubkey());
+            let mut psbt = ag
+                .create_psbt(tx, &previous_tx.output, &[], DEFAULT_GAP_LIMIT)
+                .unwrap();
+            let none: PsbtSighashType = match purpose {
+                Purpose::Taproot => TapSighashType::None.into(),
+                _ => EcdsaSighashType::None.into(),
+            };
+            psbt.inputs[0].sighash_type = Some(none);
+
+            let report = ag.sign_psbt(&mut psbt).unwrap();
+            assert!(report.signed.is_empty());
+            assert_eq!(report.skipped, vec![(0, SkipReason::SighashType(none))]);
+            assert!(Signer::new(vec![account])
+                .unwrap()
+                .sign_psbt(&mut psbt)
+                .is_err());
+            assert!(psbt.inputs[0].partial_sigs.is_empty());
+            assert!(psbt.inputs[0].tap_key_sig.is_none());
+
+            // unless the caller allows it
+            let report = ag.sign_psbt_allowing(&mut psbt, &[none]).unwrap();
+            assert_eq!(report.signed, vec![0]);
+        }
+    }
+
+    #[test]
+    fn test_wrong_previous_tx() {
+        let ag = AccountGenerator::new(MNEMONIC, Network::Testnet)
+            .unwrap()
+            .with_purpose(Purpose::Legacy);
+        let account = ag.get_account(KeychainKind::External, 0).unwrap();
+        let (previous_tx, tx) = spend(account.script_pubkey());
+        let mut psbt = create_psbt(tx).unwrap();
+
+        // claiming the output is worth more than it is, for a fee that doesn't add up
+        let mut wrong_tx = previous_tx.clone();
+        wrong_tx.output[0].value = 1_000_000;
+        assert!(update_input(
+            &mut psbt,
+            0,
+            wrong_tx.output[0].clone(),
+            Some(wrong_tx.clone())
+        )
+        .is_err());
+
+        update_input(
+            &mut psbt,
+            0,
+            previous_tx.output[0].clone(),
+            Some(previous_tx),
+        )
+        .unwrap();
+        psbt.inputs[0].non_witness_utxo = Some(wrong_tx);
+        assert!(psbt_utxo(&psbt, 0).is_err());
+        assert!(Signer::new(vec![account])
+            .unwrap()
+            .sign_psbt(&mut psbt)
+            .is_err());
+        assert!(ag.sign_psbt(&mut psbt).is_err());
+        assert!(psbt.inputs[0].partial_sigs.is_empty());
+    }
+
+    #[test]
+    fn test_create_psbt_origins() {
+        for purpose in Purpose::ALL {
+            let ag = AccountGenerator::new(MNEMONIC, Network::Testnet)
+                .unwrap()
+                .with_purpose(purpose);
+            let account = ag.get_account(KeychainKind::External, 0).unwrap();
+            let (previous_tx, mut tx) = spend(account.script_pubkey());
+            // change past the default gap limit, and to another purpose of the same wallet
+            let change = ag.get_account(KeychainKind::Internal, 25).unwrap();
+            let other = ag
+                .clone()
+                .with_purpose(match purpose {
+                    Purpose::Taproot => Purpose::NativeSegwit,
+                    _ => Purpose::Taproot,
+                })
+                .get_account(KeychainKind::Internal, 0)
+                .unwrap();
+            tx.output = vec![
+                TxOut {
+                    value: 20_000,
+                    script_pubkey: change.script_pubkey(),
+                },
+                TxOut {
+                    value: 20_000,
+                    script_pubkey: other.script_pubkey(),
+                },
+            ];
+
+            // legacy inputs can't be signed without their previous tx
+            if purpose == Purpose::Legacy {
+                assert!(ag
+                    .create_psbt(tx.clone(), &previous_tx.output, &[], 30)
+                    .is_err());
+            }
+            let mut psbt = ag
+                .create_psbt(
+                    tx,
+                    &previous_tx.output,
+                    std::slice::from_ref(&previous_tx),
+                    30,
+                )
+                .unwrap();
+
+            let input = &psbt.inputs[0];
+            match purpose {
+                Purpose::Legacy => assert!(input.witness_utxo.is_none()),
+                _ => assert_eq!(input.witness_utxo, Some(previous_tx.output[0].clone())),
+            }
+            match purpose {
+                Purpose::Taproot => assert!(input.non_witness_utxo.is_none()),
+                _ => assert_eq!(input.non_witness_utxo, Some(previous_tx.clone())),
+            }
+            assert_eq!(
+                input.redeem_script.is_some(),
+                purpose == Purpose::NestedSegwit
+            );
+            for (account, input, output) in [
+                (&account, Some(input), None),
+                (&change, None, Some(&psbt.outputs[0])),
+                (&other, None, Some(&psbt.outputs[1])),
+            ] {
+                let source = (ag.fingerprint(), account.derivation_path().clone());
+                let (bip32_derivation, tap_internal_key, tap_key_origins) = match (input, output) {
+                    (Some(input), _) => (
+                        &input.bip32_derivation,
+                        input.tap_internal_key,
+                        &input.tap_key_origins,
+                    ),
+                    (_, Some(output)) => (
+                        &output.bip32_derivation,
+                        output.tap_internal_key,
+                        &output.tap_key_origins,
+                    ),
+                    _ => unreachable!(),
+                };
+                if account.script_pubkey().is_v1_p2tr() {
+                    let key = account.x_only_public_key();
+                    assert!(bip32_derivation.is_empty());
+                    assert_eq!(tap_internal_key, Some(key));
+                    assert_eq!(tap_key_origins.get(&key), Some(&(vec![], source)));
+                } else {
+                    let key = account.public_key().unwrap().inner;
+                    assert!(tap_key_origins.is_empty());
+                    assert_eq!(tap_internal_key, None);
+                    assert_eq!(bip32_derivation.get(&key), Some(&source));
+                }
+            }
+
+            let report = ag.sign_psbt(&mut psbt).unwrap();
+            assert_eq!(report.signed, vec![0]);
+        }
+    }
+
+    // a fresh file in the temp dir, removed when dropped
+    struct TempPath(PathBuf);
+
+    impl TempPath {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("btc-{}-{name}.psbt", std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            Self(path)
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    // a p2wpkh and a p2tr input of the same wallet, each signer only has one of the keys
+    #[test]
+    fn test_roles_round_trip() {
+        let ag = AccountGenerator::new(MNEMONIC, Network::Testnet).unwrap();
+        let segwit = ag
+            .clone()
+            .with_purpose(Purpose::NativeSegwit)
+            .get_account(KeychainKind::External, 0)
+            .unwrap();
+        let taproot = ag
+            .clone()
+            .with_purpose(Purpose::Taproot)
+            .get_account(KeychainKind::External, 0)
+            .unwrap();
+        let (mut previous_tx, mut tx) = spend(segwit.script_pubkey());
+        previous_tx.output.push(TxOut {
+            value: 50_000,
+            script_pubkey: taproot.script_pubkey(),
+        });
+        tx.input = (0..2)
+            .map(|vout| TxIn {
+                previous_output: OutPoint::new(previous_tx.txid(), vout),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                ..Default::default()
+            })
+            .collect();
+        let prevouts = previous_tx.output.clone();
+
+        // creator and updaters
+        let mut psbt = create_psbt(tx).unwrap();
+        update_inputs(&mut psbt, &prevouts).unwrap();
+        let report = ag
+            .update_psbt(&mut psbt, &[previous_tx.clone()], DEFAULT_GAP_LIMIT)
+            .unwrap();
+        assert_eq!(
+            report,
+            UpdateReport {
+                inputs: vec![0, 1],
+                outputs: vec![],
+            }
+        );
+        assert_eq!(psbt.inputs[0].non_witness_utxo, Some(previous_tx));
+        assert!(psbt.inputs[1].non_witness_utxo.is_none());
+        let unsigned_tx = psbt.unsigned_tx.clone();
+
+        // signers, each on its own copy
+        let mut first = psbt.clone();
+        assert_eq!(
+            Signer::new(vec![segwit.clone()])
+                .unwrap()
+                .sign_psbt(&mut first)
+                .unwrap(),
+            vec![0]
+        );
+        let mut second = psbt;
+        assert_eq!(
+            Signer::new(vec![taproot.clone()])
+                .unwrap()
+                .sign_psbt(&mut second)
+                .unwrap(),
+            vec![1]
+        );
+        assert!(second.inputs[0].partial_sigs.is_empty());
+        assert!(finalize_psbt(&mut first.clone()).is_err());
+
+        // combiner, finalizer and extractor
+        let mut psbt = combine_psbts(vec![first, second]).unwrap();
+        assert!(extract_tx(psbt.clone()).is_err());
+        finalize_psbt(&mut psbt).unwrap();
+        for input in &psbt.inputs {
+            assert!(input.partial_sigs.is_empty() && input.tap_key_sig.is_none());
+            assert!(input.bip32_derivation.is_empty() && input.tap_key_origins.is_empty());
+            assert!(input.witness_utxo.is_some());
+        }
+
+        // outputs worth more than the inputs
+        let mut overspent = psbt.clone();
+        overspent.unsigned_tx.output[0].value = 100_001;
+        assert_eq!(
+            extract_tx(overspent)
+                .unwrap_err()
+                .downcast_ref::<BtcError>(),
+            Some(&BtcError::InsufficientFunds {
+                needed: 100_001,
+                available: 100_000,
+            })
+        );
+
+        let tx = extract_tx(psbt).unwrap();
+        assert_eq!(tx.txid(), unsigned_tx.txid());
+        let mut cache = SighashCache::new(&unsigned_tx);
+
+        let witness = &tx.input[0].witness;
+        assert!(tx.input[0].script_sig.is_empty());
+        let signature = ecdsa::Signature::from_slice(&witness[0]).unwrap();
+        let public_key = PublicKey::from_slice(&witness[1]).unwrap();
+        assert_eq!(public_key, segwit.public_key().unwrap());
+        let sighash = cache
+            .segwit_signature_hash(
+                0,
+                &ScriptBuf::new_p2pkh(&public_key.pubkey_hash()),
+                prevouts[0].value,
+                signature.hash_ty,
+            )
+            .unwrap();
+        assert!(secp()
+            .verify_ecdsa(&Message::from(sighash), &signature.sig, &public_key.inner)
+            .is_ok());
+
+        let witness = &tx.input[1].witness;
+        assert_eq!(witness.len(), 1);
+        let signature = taproot::Signature::from_slice(&witness[0]).unwrap();
+        let output_key =
+            XOnlyPublicKey::from_slice(&prevouts[1].script_pubkey.as_bytes()[2..]).unwrap();
+        let sighash = cache
+            .taproot_key_spend_signature_hash(1, &Prevouts::All(&prevouts), signature.hash_ty)
+            .unwrap();
+        assert!(secp()
+            .verify_schnorr(&signature.sig, &Message::from(sighash), &output_key)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_psbt_files() {
+        let ag = AccountGenerator::new(MNEMONIC, Network::Testnet).unwrap();
+        let account = ag.get_account(KeychainKind::External, 0).unwrap();
+        let (previous_tx, tx) = spend(account.script_pubkey());
+        let psbt = ag
+            .create_psbt(tx, &previous_tx.output, &[], DEFAULT_GAP_LIMIT)
+            .unwrap();
+
+        for format in [PsbtFormat::Binary, PsbtFormat::Base64] {
+            let path = TempPath::new(&format!("{format:?}"));
+            write_psbt(&path.0, &psbt, format).unwrap();
+            assert_eq!(
+                read_psbt_with_format(&path.0).unwrap(),
+                (psbt.clone(), format)
+            );
+            assert_eq!(read_psbt(&path.0).unwrap(), psbt);
+        }
+
+        // base64 as pasted from another wallet, with a trailing newline
+        let path = TempPath::new("newline");
+        std::fs::write(&path.0, format!("{psbt}\n")).unwrap();
+        assert_eq!(
+            read_psbt_with_format(&path.0).unwrap(),
+            (psbt.clone(), PsbtFormat::Base64)
+        );
+        std::fs::write(&path.0, "not a psbt").unwrap();
+        assert!(read_psbt(&path.0).is_err());
+
+        let path = TempPath(write_unsigned_psbt(std::env::temp_dir(), &psbt).unwrap());
+        assert_eq!(
+            path.0.file_name().unwrap().to_str().unwrap(),
+            format!("{}.psbt", psbt.unsigned_tx.txid())
+        );
+        assert_eq!(
+            read_psbt_with_format(&path.0).unwrap(),
+            (psbt, PsbtFormat::Binary)
+        );
+    }
+
+    // a psbt with an input of each of two wallets, as a coinjoin or a marketplace buy has
+    #[test]
+    fn test_foreign_input() {
+        let ours = AccountGenerator::new(MNEMONIC, Network::Testnet)
+            .unwrap()
+            .with_purpose(Purpose::NativeSegwit);
+        let theirs = AccountGenerator::new(OTHER_MNEMONIC, Network::Testnet)
+            .unwrap()
+            .with_purpose(Purpose::Taproot);
+        let (mut previous_tx, mut tx) = spend(
+            ours.get_account(KeychainKind::External, 0)
+                .unwrap()
+                .script_pubkey(),
+        );
+        previous_tx.output.push(TxOut {
+            value: 50_000,
+            script_pubkey: theirs
+                .get_account(KeychainKind::External, 0)
+                .unwrap()
+                .script_pubkey(),
+        });
+        tx.input = (0..2)
+            .map(|vout| TxIn {
+                previous_output: OutPoint::new(previous_tx.txid(), vout),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                ..Default::default()
+            })
+            .collect();
+
+        let mut psbt = ours
+            .create_psbt(
+                tx,
+                &previous_tx.output,
+                std::slice::from_ref(&previous_tx),
+                DEFAULT_GAP_LIMIT,
+            )
+            .unwrap();
+        let report = theirs
+            .update_psbt(&mut psbt, &[], DEFAULT_GAP_LIMIT)
+            .unwrap();
+        assert_eq!(report.inputs, vec![1]);
+
+        let report = ours
+            .sign_psbt_allowing(
+                &mut psbt,
+                &[EcdsaSighashType::SinglePlusAnyoneCanPay.into()],
+            )
+            .unwrap();
+        assert_eq!(report.signed, vec![0]);
+        assert_eq!(report.skipped, vec![(1, SkipReason::Foreign)]);
+        assert_eq!(psbt.inputs[0].partial_sigs.len(), 1);
+        assert!(psbt.inputs[1].partial_sigs.is_empty());
+        assert!(psbt.inputs[1].tap_key_sig.is_none());
+        assert!(finalize_psbt(&mut psbt.clone()).is_err());
+
+        let report = theirs.sign_psbt(&mut psbt).unwrap();
+        assert_eq!(report.signed, vec![1]);
+        assert_eq!(report.skipped, vec![(0, SkipReason::Foreign)]);
+        assert!(psbt.inputs[1].tap_key_sig.is_some());
+
+        finalize_psbt(&mut psbt).unwrap();
+        let tx = extract_tx(psbt).unwrap();
+        assert_eq!(tx.input[0].witness.len(), 2);
+        assert_eq!(tx.input[1].witness.len(), 1);
+    }
+}
@@ -0,0 +1,369 @@
+// outputs locked to the hash of a script: p2sh (redeem script), p2wsh and p2sh-p2wsh
+// (witness script). the same script is needed again to spend, it's revealed in the
+// scriptSig or as the last witness item.
+
+use anyhow::Error;
+use bitcoin::{
+    opcodes::all,
+    script::{Builder as SBuilder, PushBytesBuf},
+    secp256k1::Message,
+    sighash::{EcdsaSighashType, SighashCache},
+    Address, Network, PublicKey, Script, ScriptBuf, Transaction, TxIn, Witness,
+};
+
+// consensus limit of a p2sh redeem script (a single push)
+pub const MAX_REDEEM_SCRIPT_SIZE: usize = 520;
+// standardness limit of a p2wsh witness script
+pub const MAX_WITNESS_SCRIPT_SIZE: usize = 3600;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ScriptHashType {
+    P2sh,
+    P2wsh,
+    P2shP2wsh,
+}
+
+impl ScriptHashType {
+    pub fn is_segwit(&self) -> bool {
+        !matches!(self, ScriptHashType::P2sh)
+    }
+}
+
+// `<pubkey> OP_CHECKSIG`, the simplest script to lock to a single key
+pub fn single_key_script(public_key: &PublicKey) -> ScriptBuf {
+    SBuilder::new()
+        .push_key(public_key)
+        .push_opcode(all::OP_CHECKSIG)
+        .into_script()
+}
+
+// scriptSig pushes must be minimal to be standard, 1..=16 and -1 have their own opcodes
+fn push_minimal(builder: SBuilder, item: Vec<u8>) -> anyhow::Result<SBuilder> {
+    Ok(match item.as_slice() {
+        [n @ 1..=16] => builder.push_int(*n as i64),
+        [0x81] => builder.push_int(-1),
+        _ => builder.push_slice(PushBytesBuf::try_from(item)?),
+    })
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ScriptHashOutput {
+    kind: ScriptHashType,
+    // the redeem script for p2sh, the witness script otherwise
+    script: ScriptBuf,
+}
+
+impl ScriptHashOutput {
+    pub fn new(kind: ScriptHashType, script: ScriptBuf) -> anyhow::Result<Self> {
+        let limit = match kind {
+            ScriptHashType::P2sh => MAX_REDEEM_SCRIPT_SIZE,
+            ScriptHashType::P2wsh | ScriptHashType::P2shP2wsh => MAX_WITNESS_SCRIPT_SIZE,
+        };
+        if script.len() > limit {
+            return Err(Error::msg(format!(
+                "{kind:?} script is {} bytes, the limit is {limit}",
+                script.len()
+            )));
+        }
+
+        Ok(Self { kind, script })
+    }
+
+    pub fn p2sh(redeem_script: ScriptBuf) -> anyhow::Result<Self> {
+        Self::new(ScriptHashType::P2sh, redeem_script)
+    }
+
+    pub fn p2wsh(witness_script: ScriptBuf) -> anyhow::Result<Self> {
+        Self::new(ScriptHashType::P2wsh, witness_script)
+    }
+
+    pub fn p2shwsh(witness_script: ScriptBuf) -> anyhow::Result<Self> {
+        Self::new(ScriptHashType::P2shP2wsh, witness_script)
+    }
+
+    pub fn kind(&self) -> ScriptHashType {
+        self.kind
+    }
+
+    pub fn script(&self) -> &Script {
+        &self.script
+    }
+
+    pub fn script_pubkey(&self) -> ScriptBuf {
+        match self.kind {
+            ScriptHashType::P2sh => ScriptBuf::new_p2sh(&self.script.script_hash()),
+            ScriptHashType::P2wsh => ScriptBuf::new_v0_p2wsh(&self.script.wscript_hash()),
+            ScriptHashType::P2shP2wsh => {
+                ScriptBuf::new_v0_p2wsh(&self.script.wscript_hash()).to_p2sh()
+            }
+        }
+    }
+
+    pub fn address(&self, network: Network) -> anyhow::Result<Address> {
+        Ok(Address::from_script(&self.script_pubkey(), network)?)
+    }
+
+    // the message an ecdsa signature commits to, `value` is only used by the segwit kinds
+    pub fn signature_hash(
+        &self,
+        tx: &Transaction,
+        input_index: usize,
+        value: u64,
+        sighash_type: EcdsaSighashType,
+    ) -> anyhow::Result<Message> {
+        let mut cache = SighashCache::new(tx);
+        Ok(match self.kind {
+            ScriptHashType::P2sh => Message::from(cache.legacy_signature_hash(
+                input_index,
+                &self.script,
+                sighash_type.to_u32(),
+            )?),
+            ScriptHashType::P2wsh | ScriptHashType::P2shP2wsh => Message::from(
+                cache.segwit_signature_hash(input_index, &self.script, value, sighash_type)?,
+            ),
+        })
+    }
+
+    // the scriptSig and witness that satisfy the script with `stack`, e.g. the signatures
+    // in the order the script consumes them (the first item ends up at the bottom).
+    pub fn finalize(&self, stack: Vec<Vec<u8>>) -> anyhow::Result<(ScriptBuf, Witness)> {
+        match self.kind {
+            ScriptHashType::P2sh => {
+                let script_sig = stack
+                    .into_iter()
+                    .chain(std::iter::once(self.script.to_bytes()))
+                    .try_fold(SBuilder::new(), push_minimal)?
+                    .into_script();
+                Ok((script_sig, Witness::new()))
+            }
+            ScriptHashType::P2wsh | ScriptHashType::P2shP2wsh => {
+                let mut witness = Witness::from_slice(&stack);
+                witness.push(self.script.as_bytes());
+
+                let script_sig = match self.kind {
+                    ScriptHashType::P2shP2wsh => {
+                        let p2wsh = ScriptBuf::new_v0_p2wsh(&self.script.wscript_hash());
+                        SBuilder::new()
+                            .push_slice(PushBytesBuf::try_from(p2wsh.into_bytes())?)
+                            .into_script()
+                    }
+                    _ => ScriptBuf::new(),
+                };
+                Ok((script_sig, witness))
+            }
+        }
+    }
+
+    pub fn finalize_input(&self, input: &mut TxIn, stack: Vec<Vec<u8>>) -> anyhow::Result<()> {
+        let (script_sig, witness) = self.finalize(stack)?;
+        input.script_sig = script_sig;
+        input.witness = witness;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::key_pair::Account;
+    use crate::script_hash::{
+        single_key_script, ScriptHashOutput, ScriptHashType, MAX_REDEEM_SCRIPT_SIZE,
+        MAX_WITNESS_SCRIPT_SIZE,
+    };
+    use crate::secp::secp;
+    use bitcoin::absolute::LockTime;
+    use bitcoin::hashes::Hash;
+    use bitcoin::opcodes::all;
+    use bitcoin::script::Instruction;
+    use bitcoin::{
+        ecdsa, Network, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Txid, WPubkeyHash,
+    };
+
+    // the generator point, the public key of secret key 1
+    const G: &str = "0279BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F81798";
+
+    // `<G> OP_CHECKSIG` is the p2wsh example of BIP173
+    #[test]
+    fn test_addresses() {
+        let script = single_key_script(&G.parse().unwrap());
+        assert_eq!(script.to_hex_string(), format!("21{}ac", G.to_lowercase()));
+
+        let p2wsh = ScriptHashOutput::p2wsh(script.clone()).unwrap();
+        assert_eq!(
+            p2wsh.address(Network::Bitcoin).unwrap().to_string(),
+            "bc1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3qccfmv3"
+        );
+        assert_eq!(
+            p2wsh.address(Network::Testnet).unwrap().to_string(),
+            "tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7"
+        );
+
+        // p2sh-p2wsh nests that same p2wsh program
+        let p2shwsh = ScriptHashOutput::p2shwsh(script.clone()).unwrap();
+        assert_eq!(p2shwsh.script_pubkey(), p2wsh.script_pubkey().to_p2sh());
+        let p2sh = ScriptHashOutput::p2sh(script).unwrap();
+        assert!(p2sh.script_pubkey().is_p2sh());
+        assert_ne!(p2sh.script_pubkey(), p2shwsh.script_pubkey());
+
+        // an account uses its own key
+        let account = Account::from_number(Network::Bitcoin, 1);
+        assert_eq!(
+            account.p2wsh_address().unwrap(),
+            p2wsh.address(Network::Bitcoin).unwrap()
+        );
+        assert_eq!(
+            account.p2shwsh_address().unwrap(),
+            p2shwsh.address(Network::Bitcoin).unwrap()
+        );
+        assert_eq!(
+            account.p2sh_address().unwrap(),
+            p2sh.address(Network::Bitcoin).unwrap()
+        );
+        assert_ne!(
+            Account::from_number(Network::Bitcoin, 2)
+                .p2wsh_address()
+                .unwrap(),
+            account.p2wsh_address().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_script_size_limits() {
+        let script = |len: usize| ScriptBuf::from(vec![all::OP_NOP.to_u8(); len]);
+        assert!(ScriptHashOutput::p2sh(script(MAX_REDEEM_SCRIPT_SIZE)).is_ok());
+        assert!(ScriptHashOutput::p2sh(script(MAX_REDEEM_SCRIPT_SIZE + 1)).is_err());
+        for kind in [ScriptHashType::P2wsh, ScriptHashType::P2shP2wsh] {
+            assert!(ScriptHashOutput::new(kind, script(MAX_WITNESS_SCRIPT_SIZE)).is_ok());
+            assert!(ScriptHashOutput::new(kind, script(MAX_WITNESS_SCRIPT_SIZE + 1)).is_err());
+        }
+    }
+
+    #[test]
+    fn test_finalize() {
+        let script = single_key_script(&G.parse().unwrap());
+        let signature = vec![0x30; 71];
+
+        // p2sh: everything in the scriptSig, small numbers as opcodes
+        let (script_sig, witness) = ScriptHashOutput::p2sh(script.clone())
+            .unwrap()
+            .finalize(vec![vec![], vec![1], signature.clone()])
+            .unwrap();
+        assert!(witness.is_empty());
+        let mut expected = vec![all::OP_PUSHBYTES_0.to_u8(), all::OP_PUSHNUM_1.to_u8(), 71];
+        expected.extend(&signature);
+        expected.push(script.len() as u8);
+        expected.extend(script.as_bytes());
+        assert_eq!(script_sig.as_bytes(), expected);
+
+        // p2wsh: the stack and the script in the witness
+        let (script_sig, witness) = ScriptHashOutput::p2wsh(script.clone())
+            .unwrap()
+            .finalize(vec![signature.clone()])
+            .unwrap();
+        assert!(script_sig.is_empty());
+        assert_eq!(witness.to_vec(), vec![signature.clone(), script.to_bytes()]);
+
+        // p2sh-p2wsh: the same witness, the scriptSig pushes the p2wsh program
+        let p2shwsh = ScriptHashOutput::p2shwsh(script.clone()).unwrap();
+        let (script_sig, witness) = p2shwsh.finalize(vec![signature.clone()]).unwrap();
+        assert_eq!(witness.to_vec(), vec![signature, script.to_bytes()]);
+        let program = ScriptBuf::new_v0_p2wsh(&script.wscript_hash());
+        assert_eq!(script_sig.as_bytes()[1..], *program.as_bytes());
+        assert_eq!(
+            ScriptBuf::from_bytes(script_sig.as_bytes()[1..].to_vec()).to_p2sh(),
+            p2shwsh.script_pubkey()
+        );
+    }
+
+    #[test]
+    fn test_signed_spend() {
+        let account = Account::from_number(Network::Testnet, 7);
+        let value = 50_000;
+        let unsigned = Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Txid::all_zeros(), 0),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: 49_000,
+                script_pubkey: ScriptBuf::new_v0_p2wpkh(&WPubkeyHash::all_zeros()),
+            }],
+        };
+
+        for kind in [
+            ScriptHashType::P2sh,
+            ScriptHashType::P2wsh,
+            ScriptHashType::P2shP2wsh,
+        ] {
+            let output = ScriptHashOutput::new(kind, account.single_key_script().unwrap()).unwrap();
+            let mut tx = unsigned.clone();
+            account
+                .spend_single_key_script_hash(&mut tx, 0, &output, value)
+                .unwrap();
+
+            // the signature and the script where each kind expects them
+            let input = &tx.input[0];
+            let pushes = input
+                .script_sig
+                .instructions()
+                .map(|instruction| match instruction.unwrap() {
+                    Instruction::PushBytes(bytes) => bytes.as_bytes().to_vec(),
+                    Instruction::Op(op) => panic!("{op} in the scriptSig"),
+                })
+                .collect::<Vec<_>>();
+            let signature = match kind {
+                ScriptHashType::P2sh => {
+                    assert!(input.witness.is_empty());
+                    assert_eq!(pushes.len(), 2);
+                    assert_eq!(
+                        ScriptBuf::from_bytes(pushes[1].clone()).to_p2sh(),
+                        output.script_pubkey()
+                    );
+                    pushes[0].clone()
+                }
+                _ => {
+                    assert_eq!(input.witness.len(), 2);
+                    assert_eq!(&input.witness[1], output.script().as_bytes());
+                    let program = ScriptBuf::new_v0_p2wsh(&output.script().wscript_hash());
+                    match kind {
+                        ScriptHashType::P2shP2wsh => {
+                            assert_eq!(pushes, vec![program.to_bytes()]);
+                            assert_eq!(program.to_p2sh(), output.script_pubkey());
+                        }
+                        _ => {
+                            assert!(pushes.is_empty());
+                            assert_eq!(program, output.script_pubkey());
+                        }
+                    }
+                    input.witness[0].to_vec()
+                }
+            };
+
+            let signature = ecdsa::Signature::from_slice(&signature).unwrap();
+            let public_key = account.public_key().unwrap().inner;
+            let msg = output
+                .signature_hash(&unsigned, 0, value, signature.hash_ty)
+                .unwrap();
+            assert!(secp()
+                .verify_ecdsa(&msg, &signature.sig, &public_key)
+                .is_ok());
+            // segwit commits to the value spent, legacy doesn't
+            let other = output
+                .signature_hash(&unsigned, 0, value + 1, signature.hash_ty)
+                .unwrap();
+            assert_eq!(
+                secp()
+                    .verify_ecdsa(&other, &signature.sig, &public_key)
+                    .is_ok(),
+                kind == ScriptHashType::P2sh
+            );
+
+            // not this account's script
+            assert!(Account::from_number(Network::Testnet, 8)
+                .spend_single_key_script_hash(&mut unsigned.clone(), 0, &output, value)
+                .is_err());
+        }
+    }
+}
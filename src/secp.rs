@@ -0,0 +1,9 @@
+use bitcoin::secp256k1::{All, Secp256k1};
+use std::sync::OnceLock;
+
+static SECP: OnceLock<Secp256k1<All>> = OnceLock::new();
+
+// creating a context precomputes large tables, so the whole process shares one
+pub fn secp() -> &'static Secp256k1<All> {
+    SECP.get_or_init(Secp256k1::new)
+}
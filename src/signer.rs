@@ -0,0 +1,775 @@
+// signs the inputs of a transaction that spend single key outputs of our accounts: p2pkh
+// (compressed or not), p2sh-p2wpkh, p2wpkh and p2tr key path. every input needs the output
+// it spends, segwit commits to the value of its own and taproot to all of them.
+//
+// inputs sign with SIGHASH_ALL (SIGHASH_DEFAULT for taproot) unless told otherwise, e.g.
+// SINGLE|ANYONECANPAY for a marketplace offer that commits to one input and its payment.
+
+use crate::error::Error as BtcError;
+use crate::key_pair::Account;
+use crate::secp::secp;
+use anyhow::Error;
+use bitcoin::{
+    ecdsa,
+    key::TapTweak,
+    psbt::{Psbt, PsbtSighashType},
+    script::{Builder as SBuilder, PushBytesBuf},
+    secp256k1::Message,
+    sighash::{EcdsaSighashType, Prevouts, SighashCache, TapSighashType},
+    taproot, PublicKey, Script, ScriptBuf, Transaction, TxOut, Witness,
+};
+use std::borrow::Borrow;
+use std::collections::{HashMap, HashSet};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SingleKeyType {
+    P2pkh,
+    P2pkhUncompressed,
+    P2shP2wpkh,
+    P2wpkh,
+    P2tr,
+}
+
+impl SingleKeyType {
+    pub const ALL: [SingleKeyType; 5] = [
+        SingleKeyType::P2pkh,
+        SingleKeyType::P2pkhUncompressed,
+        SingleKeyType::P2shP2wpkh,
+        SingleKeyType::P2wpkh,
+        SingleKeyType::P2tr,
+    ];
+
+    pub fn script_pubkey(&self, account: &Account) -> anyhow::Result<ScriptBuf> {
+        Ok(match self {
+            SingleKeyType::P2pkh => account.p2pkh_address()?,
+            SingleKeyType::P2pkhUncompressed => account.p2pkh_address_uncompressed()?,
+            SingleKeyType::P2shP2wpkh => account.p2shwpkh_address()?,
+            SingleKeyType::P2wpkh => account.p2wpkh_address()?,
+            SingleKeyType::P2tr => account.p2tr_address(),
+        }
+        .script_pubkey())
+    }
+
+    // the script type of `script_pubkey` if it's one locked to `public_key`, p2tr excluded
+    // (the signature of a taproot key spend doesn't say which key it's from)
+    pub fn from_public_key(script_pubkey: &Script, public_key: &PublicKey) -> Option<Self> {
+        let p2wpkh = public_key
+            .wpubkey_hash()
+            .map(|wpubkey_hash| ScriptBuf::new_v0_p2wpkh(&wpubkey_hash));
+        if *script_pubkey == ScriptBuf::new_p2pkh(&public_key.pubkey_hash()) {
+            Some(match public_key.compressed {
+                true => SingleKeyType::P2pkh,
+                false => SingleKeyType::P2pkhUncompressed,
+            })
+        } else if p2wpkh.as_deref() == Some(script_pubkey) {
+            Some(SingleKeyType::P2wpkh)
+        } else if p2wpkh.map(|p2wpkh| p2wpkh.to_p2sh()).as_deref() == Some(script_pubkey) {
+            Some(SingleKeyType::P2shP2wpkh)
+        } else {
+            None
+        }
+    }
+
+    // the scriptSig and witness that spend this kind of output with `signature`
+    pub fn satisfy(&self, signature: &InputSignature) -> anyhow::Result<(ScriptBuf, Witness)> {
+        match (self, signature) {
+            (SingleKeyType::P2tr, InputSignature::TapKey(signature)) => {
+                Ok((ScriptBuf::new(), Witness::from_slice(&[signature.to_vec()])))
+            }
+            (
+                SingleKeyType::P2pkh | SingleKeyType::P2pkhUncompressed,
+                InputSignature::Ecdsa(public_key, signature),
+            ) => Ok((
+                SBuilder::new()
+                    .push_slice(PushBytesBuf::try_from(signature.to_vec())?)
+                    .push_key(public_key)
+                    .into_script(),
+                Witness::new(),
+            )),
+            (
+                SingleKeyType::P2shP2wpkh | SingleKeyType::P2wpkh,
+                InputSignature::Ecdsa(public_key, signature),
+            ) => {
+                let witness = Witness::from_slice(&[signature.to_vec(), public_key.to_bytes()]);
+                let script_sig = match self {
+                    SingleKeyType::P2shP2wpkh => {
+                        let p2wpkh = ScriptBuf::new_v0_p2wpkh(
+                            &public_key
+                                .wpubkey_hash()
+                                .ok_or_else(|| Error::msg("p2wpkh needs a compressed key"))?,
+                        );
+                        SBuilder::new()
+                            .push_slice(PushBytesBuf::try_from(p2wpkh.into_bytes())?)
+                            .into_script()
+                    }
+                    _ => ScriptBuf::new(),
+                };
+                Ok((script_sig, witness))
+            }
+            _ => Err(Error::msg(format!(
+                "{self:?} can't be spent with {signature:?}"
+            ))),
+        }
+    }
+
+    fn public_key(&self, account: &Account) -> anyhow::Result<PublicKey> {
+        match self {
+            SingleKeyType::P2pkhUncompressed => account.public_key_uncompressed(),
+            _ => account.public_key(),
+        }
+    }
+}
+
+// a signature for a single key input, before it goes in the scriptSig or witness
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InputSignature {
+    Ecdsa(PublicKey, ecdsa::Signature),
+    TapKey(taproot::Signature),
+}
+
+#[derive(Clone, Debug)]
+pub struct Signer {
+    // script pubkey -> the account that can spend it, and how
+    keys: HashMap<ScriptBuf, (Account, SingleKeyType)>,
+    // input index -> sighash type, the default one for the inputs not in here
+    sighash_types: HashMap<usize, PsbtSighashType>,
+    // the sighash types a psbt input may ask for. only ALL and DEFAULT unless allowed, a
+    // NONE signature from us would let whoever made the psbt rewrite its outputs.
+    allowed_sighash_types: HashSet<PsbtSighashType>,
+}
+
+impl Default for Signer {
+    fn default() -> Self {
+        Self {
+            keys: Default::default(),
+            sighash_types: Default::default(),
+            allowed_sighash_types: HashSet::from([
+                EcdsaSighashType::All.into(),
+                TapSighashType::Default.into(),
+                TapSighashType::All.into(),
+            ]),
+        }
+    }
+}
+
+impl Signer {
+    pub fn new(accounts: Vec<Account>) -> anyhow::Result<Self> {
+        accounts
+            .into_iter()
+            .try_fold(Self::default(), |signer, account| {
+                signer.with_account(account)
+            })
+    }
+
+    pub fn with_account(mut self, account: Account) -> anyhow::Result<Self> {
+        for kind in SingleKeyType::ALL {
+            self.keys
+                .insert(kind.script_pubkey(&account)?, (account.clone(), kind));
+        }
+        Ok(self)
+    }
+
+    // the sighash type of the input at `input_index`, ecdsa types for p2pkh and segwit v0
+    // inputs and taproot types for p2tr, e.g. `EcdsaSighashType::SinglePlusAnyoneCanPay.into()`
+    // (which also allows it, see `allow_sighash`)
+    pub fn with_sighash_type(mut self, input_index: usize, sighash_type: PsbtSighashType) -> Self {
+        self.sighash_types.insert(input_index, sighash_type);
+        self.allowed_sighash_types.insert(sighash_type);
+        self
+    }
+
+    // lets a psbt input ask for `sighash_type`, e.g. SINGLE|ANYONECANPAY for a marketplace
+    pub fn allow_sighash(mut self, sighash_type: PsbtSighashType) -> Self {
+        self.allowed_sighash_types.insert(sighash_type);
+        self
+    }
+
+    pub fn allows_sighash(&self, sighash_type: PsbtSighashType) -> bool {
+        self.allowed_sighash_types.contains(&sighash_type)
+    }
+
+    // the account and script type behind `script_pubkey`, if it's one of ours
+    pub fn owner(&self, script_pubkey: &Script) -> Option<(&Account, SingleKeyType)> {
+        self.keys
+            .get(script_pubkey)
+            .map(|(account, kind)| (account, *kind))
+    }
+
+    // signs every input spending one of our outputs, `prevouts[i]` is the output spent by
+    // input i. returns the indexes of the signed inputs, the others are left untouched.
+    pub fn sign(&self, tx: &mut Transaction, prevouts: &[TxOut]) -> anyhow::Result<Vec<usize>> {
+        if prevouts.len() != tx.input.len() {
+            return Err(Error::msg(format!(
+                "{} inputs but {} prevouts",
+                tx.input.len(),
+                prevouts.len()
+            )));
+        }
+
+        if let Some(input_index) =
+            self.sighash_types
+                .keys()
+                .find(|input_index| match prevouts.get(**input_index) {
+                    Some(prevout) => self.owner(&prevout.script_pubkey).is_none(),
+                    None => true,
+                })
+        {
+            return Err(Error::msg(format!(
+                "sighash type set for input {input_index}, which we don't sign"
+            )));
+        }
+
+        let mut cache = SighashCache::new(&*tx);
+        let signed = prevouts
+            .iter()
+            .enumerate()
+            .filter_map(|(input_index, prevout)| {
+                self.owner(&prevout.script_pubkey)
+                    .map(|owner| (input_index, owner))
+            })
+            .map(|(input_index, owner)| {
+                let sighash_type = self.sighash_types.get(&input_index).copied();
+                signature(
+                    &mut cache,
+                    input_index,
+                    &Prevouts::All(prevouts),
+                    owner,
+                    sighash_type,
+                )
+                .and_then(|signature| owner.1.satisfy(&signature))
+                .map_err(|e| {
+                    Error::new(BtcError::SigningFailed {
+                        input: input_index,
+                        reason: e.to_string(),
+                    })
+                })
+                .map(|satisfaction| (input_index, satisfaction))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(signed
+            .into_iter()
+            .map(|(input_index, (script_sig, witness))| {
+                tx.input[input_index].script_sig = script_sig;
+                tx.input[input_index].witness = witness;
+                input_index
+            })
+            .collect())
+    }
+
+    // like `sign`, but fails unless every input is ours
+    pub fn sign_all(&self, tx: &mut Transaction, prevouts: &[TxOut]) -> anyhow::Result<()> {
+        if let Some((input_index, prevout)) = prevouts
+            .iter()
+            .enumerate()
+            .find(|(_, prevout)| self.owner(&prevout.script_pubkey).is_none())
+        {
+            return Err(BtcError::SigningFailed {
+                input: input_index,
+                reason: format!("spends {}, not one of our scripts", prevout.script_pubkey),
+            }
+            .into());
+        }
+
+        self.sign(tx, prevouts).map(|_| ())
+    }
+
+    // the signer role of a psbt: adds a partial signature (tap_key_sig for taproot) to every
+    // input spending one of our outputs, with the sighash type of the input if it has one.
+    // an input asking for a type that isn't allowed is an error, nothing gets signed.
+    // the utxos of the inputs come from witness_utxo or non_witness_utxo, taproot needs all
+    // of them unless it signs with ANYONECANPAY. returns the indexes of the signed inputs.
+    pub fn sign_psbt(&self, psbt: &mut Psbt) -> anyhow::Result<Vec<usize>> {
+        let inputs = (0..psbt.inputs.len()).collect::<Vec<_>>();
+        self.sign_psbt_inputs(psbt, &inputs)
+    }
+
+    // like `sign_psbt`, limited to the inputs at `inputs`
+    pub fn sign_psbt_inputs(
+        &self,
+        psbt: &mut Psbt,
+        inputs: &[usize],
+    ) -> anyhow::Result<Vec<usize>> {
+        let utxos = (0..psbt.inputs.len())
+            .map(|input_index| psbt_utxo(psbt, input_index))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let all_utxos = utxos.iter().cloned().collect::<Option<Vec<_>>>();
+
+        let mut cache = SighashCache::new(&psbt.unsigned_tx);
+        let mut signed = vec![];
+        for (input_index, utxo) in utxos.into_iter().enumerate() {
+            if !inputs.contains(&input_index) {
+                continue;
+            }
+            let Some(owner) = utxo
+                .as_ref()
+                .and_then(|utxo| self.owner(&utxo.script_pubkey))
+            else {
+                continue;
+            };
+            let utxo = utxo.clone().expect("owned inputs have a utxo");
+            let prevouts = match &all_utxos {
+                Some(all_utxos) => Prevouts::All(all_utxos),
+                None => Prevouts::One(input_index, utxo),
+            };
+            let sighash_type = psbt.inputs[input_index]
+                .sighash_type
+                .or_else(|| self.sighash_types.get(&input_index).copied());
+            if let Some(sighash_type) = sighash_type.filter(|t| !self.allows_sighash(*t)) {
+                return Err(BtcError::SigningFailed {
+                    input: input_index,
+                    reason: format!("sighash type {sighash_type} is not allowed"),
+                }
+                .into());
+            }
+
+            let signature = signature(&mut cache, input_index, &prevouts, owner, sighash_type)
+                .map_err(|e| {
+                    Error::new(BtcError::SigningFailed {
+                        input: input_index,
+                        reason: e.to_string(),
+                    })
+                })?;
+            signed.push((input_index, signature));
+        }
+
+        Ok(signed
+            .into_iter()
+            .map(|(input_index, signature)| {
+                let input = &mut psbt.inputs[input_index];
+                match signature {
+                    InputSignature::Ecdsa(public_key, signature) => {
+                        input.partial_sigs.insert(public_key, signature);
+                    }
+                    InputSignature::TapKey(signature) => input.tap_key_sig = Some(signature),
+                }
+                input_index
+            })
+            .collect())
+    }
+}
+
+// the output spent by a psbt input, if the psbt has it. a non_witness_utxo must be the tx
+// the input spends from, or its outputs could claim any value and make the fee lie.
+pub(crate) fn psbt_utxo(psbt: &Psbt, input_index: usize) -> anyhow::Result<Option<TxOut>> {
+    let (Some(input), Some(txin)) = (
+        psbt.inputs.get(input_index),
+        psbt.unsigned_tx.input.get(input_index),
+    ) else {
+        return Ok(None);
+    };
+    let outpoint = txin.previous_output;
+
+    let non_witness_utxo = match &input.non_witness_utxo {
+        Some(previous_tx) if previous_tx.txid() != outpoint.txid => {
+            return Err(Error::msg(format!(
+                "input {input_index} spends {outpoint}, its non_witness_utxo is {}",
+                previous_tx.txid()
+            )))
+        }
+        Some(previous_tx) => Some(
+            previous_tx
+                .output
+                .get(outpoint.vout as usize)
+                .cloned()
+                .ok_or_else(|| Error::msg(format!("{outpoint} doesn't exist")))?,
+        ),
+        None => None,
+    };
+    match (&input.witness_utxo, non_witness_utxo) {
+        (Some(witness_utxo), Some(utxo)) if *witness_utxo != utxo => Err(Error::msg(format!(
+            "the witness_utxo of input {input_index} is not in its non_witness_utxo"
+        ))),
+        (Some(witness_utxo), _) => Ok(Some(witness_utxo.clone())),
+        (None, utxo) => Ok(utxo),
+    }
+}
+
+// signs `prevouts[input_index]`, an output of `owner`, with the default sighash type of
+// the script unless `sighash_type` says otherwise
+fn signature<T: Borrow<TxOut>>(
+    cache: &mut SighashCache<&Transaction>,
+    input_index: usize,
+    prevouts: &Prevouts<T>,
+    (account, kind): (&Account, SingleKeyType),
+    sighash_type: Option<PsbtSighashType>,
+) -> anyhow::Result<InputSignature> {
+    let secp = secp();
+    let prevout = match prevouts {
+        Prevouts::All(prevouts) => prevouts
+            .get(input_index)
+            .ok_or_else(|| Error::msg("no prevout"))?
+            .borrow(),
+        Prevouts::One(_, prevout) => prevout.borrow(),
+    };
+    let output_count = cache.transaction().output.len();
+
+    if kind == SingleKeyType::P2tr {
+        let hash_ty = match sighash_type {
+            Some(sighash_type) => sighash_type
+                .taproot_hash_ty()
+                .map_err(|_| Error::msg(format!("{sighash_type} is not a taproot sighash type")))?,
+            None => TapSighashType::Default,
+        };
+        if matches!(
+            hash_ty,
+            TapSighashType::Single | TapSighashType::SinglePlusAnyoneCanPay
+        ) {
+            check_single(input_index, output_count)?;
+        }
+        let sighash = cache.taproot_key_spend_signature_hash(input_index, prevouts, hash_ty)?;
+        let keypair = account.reveal_keypair().tap_tweak(secp, None).to_inner();
+
+        return Ok(InputSignature::TapKey(taproot::Signature {
+            sig: secp.sign_schnorr(&sighash.into(), &keypair),
+            hash_ty,
+        }));
+    }
+
+    let hash_ty = match sighash_type {
+        Some(sighash_type) => sighash_type
+            .ecdsa_hash_ty()
+            .map_err(|_| Error::msg(format!("{sighash_type} is not an ecdsa sighash type")))?,
+        None => EcdsaSighashType::All,
+    };
+    if matches!(
+        hash_ty,
+        EcdsaSighashType::Single | EcdsaSighashType::SinglePlusAnyoneCanPay
+    ) {
+        check_single(input_index, output_count)?;
+    }
+    let public_key = kind.public_key(account)?;
+    let msg = match kind {
+        SingleKeyType::P2pkh | SingleKeyType::P2pkhUncompressed => Message::from(
+            cache.legacy_signature_hash(input_index, &prevout.script_pubkey, hash_ty.to_u32())?,
+        ),
+        // the script code of p2wpkh is the p2pkh script of the key
+        _ => Message::from(cache.segwit_signature_hash(
+            input_index,
+            &ScriptBuf::new_p2pkh(&public_key.pubkey_hash()),
+            prevout.value,
+            hash_ty,
+        )?),
+    };
+
+    Ok(InputSignature::Ecdsa(
+        public_key,
+        ecdsa::Signature {
+            sig: secp.sign_ecdsa_low_r(&msg, &account.reveal_secret_key()),
+            hash_ty,
+        },
+    ))
+}
+
+// SINGLE commits to the output at the input's index. without one, legacy signs the constant
+// 1 (the SIGHASH_SINGLE bug, anyone can reuse the signature), segwit v0 signs no output at
+// all and taproot has no valid sighash.
+pub(crate) fn check_single(input_index: usize, output_count: usize) -> anyhow::Result<()> {
+    if input_index >= output_count {
+        return Err(Error::msg(format!(
+            "SIGHASH_SINGLE needs an output {input_index}, the tx has {output_count} outputs"
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error as BtcError;
+    use crate::key_pair::{Account, AccountGenerator, KeychainKind};
+    use crate::secp::secp;
+    use crate::signer::{check_single, Signer, SingleKeyType};
+    use bitcoin::absolute::LockTime;
+    use bitcoin::hashes::Hash;
+    use bitcoin::script::Instruction;
+    use bitcoin::secp256k1::{Message, XOnlyPublicKey};
+    use bitcoin::sighash::{EcdsaSighashType, Prevouts, SighashCache, TapSighashType};
+    use bitcoin::{
+        ecdsa, taproot, Network, OutPoint, PublicKey, ScriptBuf, Sequence, Transaction, TxIn,
+        TxOut, Txid, WPubkeyHash, Witness,
+    };
+
+    const MNEMONIC: &str =
+        "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+
+    fn account(index: u32) -> Account {
+        AccountGenerator::new(MNEMONIC, Network::Testnet)
+            .unwrap()
+            .get_account(KeychainKind::External, index)
+            .unwrap()
+    }
+
+    // every script type of account 0, then the p2wpkh of account 1
+    fn prevouts() -> Vec<(TxOut, SingleKeyType)> {
+        SingleKeyType::ALL
+            .iter()
+            .map(|kind| (account(0), *kind))
+            .chain([(account(1), SingleKeyType::P2wpkh)])
+            .enumerate()
+            .map(|(i, (account, kind))| {
+                let prevout = TxOut {
+                    value: 10_000 * (i as u64 + 1),
+                    script_pubkey: kind.script_pubkey(&account).unwrap(),
+                };
+                (prevout, kind)
+            })
+            .collect()
+    }
+
+    fn unsigned_tx(inputs: usize, outputs: usize) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: (0..inputs as u32)
+                .map(|vout| TxIn {
+                    previous_output: OutPoint::new(Txid::all_zeros(), vout),
+                    script_sig: ScriptBuf::new(),
+                    sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                    witness: Witness::new(),
+                })
+                .collect(),
+            output: (0..outputs)
+                .map(|_| TxOut {
+                    value: 1_000,
+                    script_pubkey: ScriptBuf::new_v0_p2wpkh(&WPubkeyHash::all_zeros()),
+                })
+                .collect(),
+        }
+    }
+
+    // checks the signature of input `input_index` of `signed` against the sighash of the
+    // unsigned tx and the key locked in its prevout, returns the signature as serialized
+    fn verify(
+        unsigned: &Transaction,
+        signed: &Transaction,
+        input_index: usize,
+        prevouts: &[TxOut],
+        kind: SingleKeyType,
+    ) -> Vec<u8> {
+        let secp = secp();
+        let mut cache = SighashCache::new(unsigned);
+        let input = &signed.input[input_index];
+        let prevout = &prevouts[input_index];
+
+        if kind == SingleKeyType::P2tr {
+            assert!(input.script_sig.is_empty());
+            assert_eq!(input.witness.len(), 1);
+            let bytes = input.witness.nth(0).unwrap().to_vec();
+            let signature = taproot::Signature::from_slice(&bytes).unwrap();
+            let output_key =
+                XOnlyPublicKey::from_slice(&prevout.script_pubkey.as_bytes()[2..]).unwrap();
+            let sighash = cache
+                .taproot_key_spend_signature_hash(
+                    input_index,
+                    &Prevouts::All(prevouts),
+                    signature.hash_ty,
+                )
+                .unwrap();
+            secp.verify_schnorr(&signature.sig, &sighash.into(), &output_key)
+                .unwrap();
+            return bytes;
+        }
+
+        let (bytes, public_key) = match kind {
+            SingleKeyType::P2pkh | SingleKeyType::P2pkhUncompressed => {
+                assert!(input.witness.is_empty());
+                let pushes = input
+                    .script_sig
+                    .instructions()
+                    .map(|instruction| match instruction.unwrap() {
+                        Instruction::PushBytes(bytes) => bytes.as_bytes().to_vec(),
+                        Instruction::Op(op) => panic!("{op} in a p2pkh scriptSig"),
+                    })
+                    .collect::<Vec<_>>();
+                assert_eq!(pushes.len(), 2);
+                (
+                    pushes[0].clone(),
+                    PublicKey::from_slice(&pushes[1]).unwrap(),
+                )
+            }
+            _ => {
+                assert_eq!(input.witness.len(), 2);
+                (
+                    input.witness.nth(0).unwrap().to_vec(),
+                    PublicKey::from_slice(input.witness.nth(1).unwrap()).unwrap(),
+                )
+            }
+        };
+        assert_eq!(
+            SingleKeyType::from_public_key(&prevout.script_pubkey, &public_key),
+            Some(kind)
+        );
+
+        let signature = ecdsa::Signature::from_slice(&bytes).unwrap();
+        let msg = match kind {
+            SingleKeyType::P2pkh | SingleKeyType::P2pkhUncompressed => Message::from(
+                cache
+                    .legacy_signature_hash(
+                        input_index,
+                        &prevout.script_pubkey,
+                        signature.hash_ty.to_u32(),
+                    )
+                    .unwrap(),
+            ),
+            _ => Message::from(
+                cache
+                    .segwit_signature_hash(
+                        input_index,
+                        &ScriptBuf::new_p2pkh(&public_key.pubkey_hash()),
+                        prevout.value,
+                        signature.hash_ty,
+                    )
+                    .unwrap(),
+            ),
+        };
+        secp.verify_ecdsa(&msg, &signature.sig, &public_key.inner)
+            .unwrap();
+        bytes
+    }
+
+    #[test]
+    fn test_sign_all_types() {
+        let (prevouts, kinds): (Vec<_>, Vec<_>) = prevouts().into_iter().unzip();
+        let signer = Signer::new(vec![account(0), account(1)]).unwrap();
+        let unsigned = unsigned_tx(prevouts.len(), 2);
+
+        let mut tx = unsigned.clone();
+        assert_eq!(
+            signer.sign(&mut tx, &prevouts).unwrap(),
+            (0..prevouts.len()).collect::<Vec<_>>()
+        );
+        for (input_index, kind) in kinds.iter().enumerate() {
+            let signature = verify(&unsigned, &tx, input_index, &prevouts, *kind);
+            // SIGHASH_ALL is appended to ecdsa, taproot signs with DEFAULT and appends nothing
+            match kind {
+                SingleKeyType::P2tr => assert_eq!(signature.len(), 64),
+                _ => assert_eq!(signature.last(), Some(&0x01)),
+            }
+        }
+
+        // schnorr signatures are randomized, the two txs don't have to be the same
+        let mut all = unsigned.clone();
+        signer.sign_all(&mut all, &prevouts).unwrap();
+        for (input_index, kind) in kinds.iter().enumerate() {
+            verify(&unsigned, &all, input_index, &prevouts, *kind);
+        }
+    }
+
+    #[test]
+    fn test_sign_errors() {
+        let prevouts = prevouts()
+            .into_iter()
+            .map(|(prevout, _)| prevout)
+            .collect::<Vec<_>>();
+        let signer = Signer::new(vec![account(0), account(1)]).unwrap();
+        let unsigned = unsigned_tx(prevouts.len(), 2);
+
+        let mut tx = unsigned.clone();
+        let e = signer.sign(&mut tx, &prevouts[1..]).unwrap_err();
+        assert_eq!(e.to_string(), "6 inputs but 5 prevouts");
+        assert_eq!(tx, unsigned);
+
+        // a foreign input, signing the others is fine but not all of them
+        let mut prevouts = prevouts;
+        let foreign = ScriptBuf::new_v0_p2wpkh(&WPubkeyHash::all_zeros());
+        prevouts[2].script_pubkey = foreign.clone();
+        let e = signer.sign_all(&mut tx, &prevouts).unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<BtcError>(),
+            Some(&BtcError::SigningFailed {
+                input: 2,
+                reason: format!("spends {foreign}, not one of our scripts"),
+            })
+        );
+        assert_eq!(tx, unsigned);
+
+        assert_eq!(
+            signer.sign(&mut tx, &prevouts).unwrap(),
+            vec![0, 1, 3, 4, 5]
+        );
+        assert_eq!(tx.input[2], unsigned.input[2]);
+    }
+
+    #[test]
+    fn test_single_anyone_can_pay() {
+        let prevouts = prevouts()
+            .into_iter()
+            .map(|(prevout, _)| prevout)
+            .collect::<Vec<_>>();
+        let p2wpkh = 3;
+        let p2tr = 4;
+        let signer = Signer::new(vec![account(0), account(1)])
+            .unwrap()
+            .with_sighash_type(p2wpkh, EcdsaSighashType::SinglePlusAnyoneCanPay.into())
+            .with_sighash_type(p2tr, TapSighashType::SinglePlusAnyoneCanPay.into());
+        let unsigned = unsigned_tx(prevouts.len(), prevouts.len());
+
+        let mut tx = unsigned.clone();
+        signer.sign(&mut tx, &prevouts).unwrap();
+        let signature = verify(&unsigned, &tx, p2wpkh, &prevouts, SingleKeyType::P2wpkh);
+        assert_eq!(signature.last(), Some(&0x83));
+        let signature = verify(&unsigned, &tx, p2tr, &prevouts, SingleKeyType::P2tr);
+        assert_eq!(signature.len(), 65);
+        assert_eq!(signature.last(), Some(&0x83));
+
+        // the other inputs keep the default
+        let signature = verify(&unsigned, &tx, 5, &prevouts, SingleKeyType::P2wpkh);
+        assert_eq!(signature.last(), Some(&0x01));
+    }
+
+    #[test]
+    fn test_single_without_output() {
+        assert!(check_single(1, 2).is_ok());
+        assert!(check_single(2, 2).is_err());
+
+        let prevouts = prevouts()
+            .into_iter()
+            .map(|(prevout, _)| prevout)
+            .collect::<Vec<_>>();
+        let unsigned = unsigned_tx(prevouts.len(), 2);
+        for (input_index, sighash_type) in [
+            (3, EcdsaSighashType::Single.into()),
+            (4, TapSighashType::SinglePlusAnyoneCanPay.into()),
+        ] {
+            let signer = Signer::new(vec![account(0), account(1)])
+                .unwrap()
+                .with_sighash_type(input_index, sighash_type);
+            let mut tx = unsigned.clone();
+            let e = signer.sign(&mut tx, &prevouts).unwrap_err();
+            assert_eq!(
+                e.downcast_ref::<BtcError>(),
+                Some(&BtcError::SigningFailed {
+                    input: input_index,
+                    reason: format!(
+                        "SIGHASH_SINGLE needs an output {input_index}, the tx has 2 outputs"
+                    ),
+                })
+            );
+            assert_eq!(tx, unsigned);
+        }
+    }
+
+    #[test]
+    fn test_sighash_type_not_ours() {
+        let mut prevouts = prevouts()
+            .into_iter()
+            .map(|(prevout, _)| prevout)
+            .collect::<Vec<_>>();
+        prevouts[2].script_pubkey = ScriptBuf::new_v0_p2wpkh(&WPubkeyHash::all_zeros());
+        let unsigned = unsigned_tx(prevouts.len(), prevouts.len());
+
+        // a foreign input and one the tx doesn't have
+        for input_index in [2, prevouts.len()] {
+            let signer = Signer::new(vec![account(0), account(1)])
+                .unwrap()
+                .with_sighash_type(input_index, EcdsaSighashType::SinglePlusAnyoneCanPay.into());
+            let mut tx = unsigned.clone();
+            let e = signer.sign(&mut tx, &prevouts).unwrap_err();
+            assert_eq!(
+                e.to_string(),
+                format!("sighash type set for input {input_index}, which we don't sign")
+            );
+            assert_eq!(tx, unsigned);
+        }
+    }
+}
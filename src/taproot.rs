@@ -0,0 +1,545 @@
+// taproot outputs with a script tree: the output key commits to an internal key and the
+// merkle root of the leaf scripts, any leaf can be spent by revealing it with its control
+// block, the internal key still spends through the key path.
+
+use crate::key_pair::Account;
+use crate::secp::secp;
+use anyhow::Error;
+use bitcoin::{
+    absolute,
+    hashes::{sha256, Hash},
+    key::{TweakedPublicKey, XOnlyPublicKey},
+    opcodes::all,
+    script::Builder as SBuilder,
+    sighash::{Prevouts, SighashCache, TapSighash, TapSighashType},
+    taproot::{
+        self, ControlBlock, LeafVersion, TapLeafHash, TapNodeHash, TaprootBuilder, TaprootSpendInfo,
+    },
+    Address, Network, Script, ScriptBuf, Sequence, Transaction, TxOut, Witness,
+};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+// <key> OP_CHECKSIG
+pub fn single_key_leaf(key: &XOnlyPublicKey) -> ScriptBuf {
+    SBuilder::new()
+        .push_x_only_key(key)
+        .push_opcode(all::OP_CHECKSIG)
+        .into_script()
+}
+
+// <locktime> OP_CHECKLOCKTIMEVERIFY OP_DROP <key> OP_CHECKSIG, spendable by `key` once the
+// chain reaches `lock_time` (a height or a unix time). the spending tx sets its lock_time to
+// at least `lock_time` and a non final sequence on the input.
+pub fn timelock_leaf(key: &XOnlyPublicKey, lock_time: absolute::LockTime) -> ScriptBuf {
+    SBuilder::new()
+        .push_int(lock_time.to_consensus_u32() as i64)
+        .push_opcode(all::OP_CLTV)
+        .push_opcode(all::OP_DROP)
+        .push_x_only_key(key)
+        .push_opcode(all::OP_CHECKSIG)
+        .into_script()
+}
+
+// <sequence> OP_CHECKSEQUENCEVERIFY OP_DROP <key> OP_CHECKSIG, spendable by `key` a number of
+// blocks (or 512 seconds units) after the output confirmed, e.g. a recovery key. the
+// spending input sets its sequence to at least `sequence`, in a version 2 tx.
+pub fn relative_timelock_leaf(
+    key: &XOnlyPublicKey,
+    sequence: Sequence,
+) -> anyhow::Result<ScriptBuf> {
+    if !sequence.is_relative_lock_time() {
+        return Err(Error::msg(format!(
+            "sequence {sequence} is not a relative lock time"
+        )));
+    }
+
+    Ok(SBuilder::new()
+        .push_int(sequence.to_consensus_u32() as i64)
+        .push_opcode(all::OP_CSV)
+        .push_opcode(all::OP_DROP)
+        .push_x_only_key(key)
+        .push_opcode(all::OP_CHECKSIG)
+        .into_script())
+}
+
+// OP_SHA256 <hash> OP_EQUALVERIFY <key> OP_CHECKSIG, spendable by `key` with the preimage,
+// the witness stack is [signature, preimage].
+pub fn hashlock_leaf(key: &XOnlyPublicKey, hash: &sha256::Hash) -> ScriptBuf {
+    SBuilder::new()
+        .push_opcode(all::OP_SHA256)
+        .push_slice(hash.as_byte_array())
+        .push_opcode(all::OP_EQUALVERIFY)
+        .push_x_only_key(key)
+        .push_opcode(all::OP_CHECKSIG)
+        .into_script()
+}
+
+#[derive(Clone, Debug)]
+pub struct TaprootScriptTree {
+    internal_key: XOnlyPublicKey,
+    leaves: Vec<ScriptBuf>,
+    spend_info: TaprootSpendInfo,
+}
+
+impl TaprootScriptTree {
+    // all leaves equally likely, the tree is as balanced as it gets
+    pub fn new(internal_key: XOnlyPublicKey, leaves: Vec<ScriptBuf>) -> anyhow::Result<Self> {
+        Self::with_weights(
+            internal_key,
+            leaves.into_iter().map(|leaf| (1, leaf)).collect(),
+        )
+    }
+
+    // leaves with a higher weight (how likely they are spent) get a shorter control block
+    pub fn with_weights(
+        internal_key: XOnlyPublicKey,
+        leaves: Vec<(u32, ScriptBuf)>,
+    ) -> anyhow::Result<Self> {
+        let secp = secp();
+        if leaves.is_empty() {
+            return Err(Error::msg("a script tree needs at least one leaf"));
+        }
+
+        let scripts = leaves.iter().map(|(_, leaf)| leaf.clone()).collect();
+        let spend_info = TaprootBuilder::with_huffman_tree(leaves)?
+            .finalize(secp, internal_key)
+            .map_err(|_| Error::msg("can't finalize the taproot script tree"))?;
+
+        Ok(Self {
+            internal_key,
+            leaves: scripts,
+            spend_info,
+        })
+    }
+
+    pub fn internal_key(&self) -> XOnlyPublicKey {
+        self.internal_key
+    }
+
+    pub fn leaves(&self) -> &[ScriptBuf] {
+        &self.leaves
+    }
+
+    pub fn merkle_root(&self) -> Option<TapNodeHash> {
+        self.spend_info.merkle_root()
+    }
+
+    pub fn output_key(&self) -> TweakedPublicKey {
+        self.spend_info.output_key()
+    }
+
+    pub fn spend_info(&self) -> &TaprootSpendInfo {
+        &self.spend_info
+    }
+
+    pub fn script_pubkey(&self) -> ScriptBuf {
+        ScriptBuf::new_v1_p2tr_tweaked(self.output_key())
+    }
+
+    pub fn address(&self, network: Network) -> Address {
+        Address::p2tr_tweaked(self.output_key(), network)
+    }
+
+    pub fn leaf_hash(&self, leaf: &Script) -> TapLeafHash {
+        TapLeafHash::from_script(leaf, LeafVersion::TapScript)
+    }
+
+    // the path from the leaf to the merkle root, with the internal key
+    pub fn control_block(&self, leaf: &Script) -> anyhow::Result<ControlBlock> {
+        self.spend_info
+            .control_block(&(leaf.to_owned(), LeafVersion::TapScript))
+            .ok_or_else(|| Error::msg(format!("{leaf} is not a leaf of the script tree")))
+    }
+
+    // the message the signatures of a leaf commit to, taproot signs all the prevouts
+    pub fn script_spend_sighash(
+        &self,
+        tx: &Transaction,
+        input_index: usize,
+        prevouts: &[TxOut],
+        leaf: &Script,
+        sighash_type: TapSighashType,
+    ) -> anyhow::Result<TapSighash> {
+        self.control_block(leaf)?;
+
+        Ok(SighashCache::new(tx).taproot_script_spend_signature_hash(
+            input_index,
+            &Prevouts::All(prevouts),
+            self.leaf_hash(leaf),
+            sighash_type,
+        )?)
+    }
+
+    // [stack..., leaf, control block], `stack` from the bottom, e.g. [signature, preimage]
+    pub fn script_spend_witness(
+        &self,
+        leaf: &Script,
+        stack: Vec<Vec<u8>>,
+    ) -> anyhow::Result<Witness> {
+        let mut witness = Witness::from_slice(&stack);
+        witness.push(leaf.as_bytes());
+        witness.push(self.control_block(leaf)?.serialize());
+        Ok(witness)
+    }
+}
+
+// H from BIP341, the x coordinate of sha256(G): a point without a known private key,
+// as internal key it leaves only the script path to spend.
+pub const NUMS_INTERNAL_KEY: &str =
+    "50929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac0";
+
+pub fn nums_internal_key() -> XOnlyPublicKey {
+    XOnlyPublicKey::from_str(NUMS_INTERNAL_KEY).expect("valid NUMS point")
+}
+
+// tapscript has no key count limit but the stack holds at most 1000 items
+pub const MAX_MULTI_A_KEYS: usize = 999;
+
+// <pk1> OP_CHECKSIG <pk2> OP_CHECKSIGADD ... <pkn> OP_CHECKSIGADD <k> OP_NUMEQUAL,
+// with the keys sorted like the `sortedmulti_a` descriptor.
+pub fn multi_a_leaf(threshold: usize, keys: &[XOnlyPublicKey]) -> anyhow::Result<ScriptBuf> {
+    if keys.is_empty() || keys.len() > MAX_MULTI_A_KEYS {
+        return Err(Error::msg(format!(
+            "multi_a needs 1 to {MAX_MULTI_A_KEYS} keys, got {}",
+            keys.len()
+        )));
+    }
+    if threshold == 0 || threshold > keys.len() {
+        return Err(Error::msg(format!(
+            "threshold must be between 1 and {}, got {threshold}",
+            keys.len()
+        )));
+    }
+
+    let mut keys = keys.to_vec();
+    keys.sort_by_key(|key| key.serialize());
+    if keys.windows(2).any(|pair| pair[0] == pair[1]) {
+        return Err(Error::msg("duplicate multi_a key"));
+    }
+
+    Ok(keys
+        .iter()
+        .enumerate()
+        .fold(SBuilder::new(), |builder, (i, key)| {
+            builder.push_x_only_key(key).push_opcode(match i {
+                0 => all::OP_CHECKSIG,
+                _ => all::OP_CHECKSIGADD,
+            })
+        })
+        .push_int(threshold as i64)
+        .push_opcode(all::OP_NUMEQUAL)
+        .into_script())
+}
+
+// a k-of-n taproot wallet with a single multi_a leaf
+#[derive(Clone, Debug)]
+pub struct TaprootMultisig {
+    threshold: usize,
+    // in script order
+    keys: Vec<XOnlyPublicKey>,
+    leaf: ScriptBuf,
+    tree: TaprootScriptTree,
+}
+
+impl TaprootMultisig {
+    // `internal_key` is the NUMS point when None, or e.g. a MuSig2 aggregate of the keys
+    // so all of them together can spend through the cheaper key path.
+    pub fn new(
+        threshold: usize,
+        keys: &[XOnlyPublicKey],
+        internal_key: Option<XOnlyPublicKey>,
+    ) -> anyhow::Result<Self> {
+        let leaf = multi_a_leaf(threshold, keys)?;
+        let tree = TaprootScriptTree::new(
+            internal_key.unwrap_or_else(nums_internal_key),
+            vec![leaf.clone()],
+        )?;
+        let mut keys = keys.to_vec();
+        keys.sort_by_key(|key| key.serialize());
+
+        Ok(Self {
+            threshold,
+            keys,
+            leaf,
+            tree,
+        })
+    }
+
+    pub fn threshold(&self) -> usize {
+        self.threshold
+    }
+
+    pub fn keys(&self) -> &[XOnlyPublicKey] {
+        &self.keys
+    }
+
+    pub fn leaf(&self) -> &Script {
+        &self.leaf
+    }
+
+    pub fn tree(&self) -> &TaprootScriptTree {
+        &self.tree
+    }
+
+    pub fn script_pubkey(&self) -> ScriptBuf {
+        self.tree.script_pubkey()
+    }
+
+    pub fn address(&self, network: Network) -> Address {
+        self.tree.address(network)
+    }
+
+    // one participant's signature for the multi_a leaf
+    pub fn sign(
+        &self,
+        account: &Account,
+        tx: &Transaction,
+        input_index: usize,
+        prevouts: &[TxOut],
+        sighash_type: TapSighashType,
+    ) -> anyhow::Result<taproot::Signature> {
+        if !self.keys.contains(&account.x_only_public_key()) {
+            return Err(Error::msg(format!(
+                "{} is not a key of the multisig",
+                account.x_only_public_key()
+            )));
+        }
+
+        account.sign_tap_leaf(
+            tx,
+            input_index,
+            prevouts,
+            &self.tree,
+            &self.leaf,
+            sighash_type,
+        )
+    }
+
+    // exactly `threshold` signatures (OP_NUMEQUAL), the first key's signature on top of the
+    // stack and an empty one for every key that doesn't sign.
+    pub fn witness(
+        &self,
+        signatures: &BTreeMap<XOnlyPublicKey, taproot::Signature>,
+    ) -> anyhow::Result<Witness> {
+        let mut remaining = self.threshold;
+        let stack = self
+            .keys
+            .iter()
+            .map(|key| match signatures.get(key) {
+                Some(signature) if remaining > 0 => {
+                    remaining -= 1;
+                    signature.to_vec()
+                }
+                _ => vec![],
+            })
+            .collect::<Vec<_>>();
+        if remaining > 0 {
+            return Err(Error::msg(format!(
+                "{} of {} signatures",
+                self.threshold - remaining,
+                self.threshold
+            )));
+        }
+
+        self.tree
+            .script_spend_witness(&self.leaf, stack.into_iter().rev().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::key_pair::{AccountGenerator, KeychainKind};
+    use crate::secp::secp;
+    use crate::taproot::{
+        hashlock_leaf, nums_internal_key, single_key_leaf, timelock_leaf, TaprootMultisig,
+        TaprootScriptTree,
+    };
+    use bitcoin::absolute::LockTime;
+    use bitcoin::hashes::{sha256, Hash};
+    use bitcoin::secp256k1::Message;
+    use bitcoin::sighash::TapSighashType;
+    use bitcoin::taproot::{self, ControlBlock};
+    use bitcoin::{
+        Network, OutPoint, Script, ScriptBuf, Sequence, Transaction, TxIn, TxOut, WPubkeyHash,
+    };
+    use std::collections::BTreeMap;
+
+    const MNEMONIC: &str =
+        "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+
+    #[test]
+    fn test_script_path_spend() {
+        let ag = AccountGenerator::new(MNEMONIC, Network::Testnet).unwrap();
+        let accounts = (0..3)
+            .map(|index| ag.get_account(KeychainKind::External, index).unwrap())
+            .collect::<Vec<_>>();
+        let keys = accounts
+            .iter()
+            .map(|account| account.x_only_public_key())
+            .collect::<Vec<_>>();
+        let preimage = b"the preimage".to_vec();
+        let leaves = vec![
+            single_key_leaf(&keys[1]),
+            hashlock_leaf(&keys[2], &sha256::Hash::hash(&preimage)),
+            timelock_leaf(&keys[1], LockTime::from_height(800_000).unwrap()),
+        ];
+        let tree = TaprootScriptTree::new(keys[0], leaves.clone()).unwrap();
+
+        let prevouts = [TxOut {
+            value: 50_000,
+            script_pubkey: tree.script_pubkey(),
+        }];
+        let tx = Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: 49_000,
+                script_pubkey: ScriptBuf::new_v0_p2wpkh(&WPubkeyHash::all_zeros()),
+            }],
+        };
+
+        // the hashlock leaf: [signature, preimage, leaf, control block]
+        let leaf = &leaves[1];
+        let signature = accounts[2]
+            .sign_tap_leaf(&tx, 0, &prevouts, &tree, leaf, TapSighashType::Default)
+            .unwrap();
+        let witness = tree
+            .script_spend_witness(leaf, vec![signature.to_vec(), preimage])
+            .unwrap();
+        assert_eq!(witness.len(), 4);
+
+        // what a verifier does with it, from the output key in the script pubkey
+        let output_key = prevouts[0].script_pubkey.as_bytes()[2..].to_vec();
+        assert_eq!(output_key, tree.output_key().serialize());
+        let control_block = ControlBlock::decode(&witness[3]).unwrap();
+        let revealed = Script::from_bytes(&witness[2]);
+        assert_eq!(control_block.internal_key, keys[0]);
+        assert!(control_block.verify_taproot_commitment(
+            secp(),
+            tree.output_key().to_inner(),
+            revealed
+        ));
+        // the control block only opens its own leaf
+        assert!(!control_block.verify_taproot_commitment(
+            secp(),
+            tree.output_key().to_inner(),
+            &leaves[0]
+        ));
+
+        let signature = taproot::Signature::from_slice(&witness[0]).unwrap();
+        let sighash = tree
+            .script_spend_sighash(&tx, 0, &prevouts, revealed, signature.hash_ty)
+            .unwrap();
+        let msg = Message::from(sighash);
+        assert!(secp()
+            .verify_schnorr(&signature.sig, &msg, &keys[2])
+            .is_ok());
+        assert!(secp()
+            .verify_schnorr(&signature.sig, &msg, &keys[1])
+            .is_err());
+        // the sighash commits to the leaf
+        let other = tree
+            .script_spend_sighash(&tx, 0, &prevouts, &leaves[0], signature.hash_ty)
+            .unwrap();
+        assert!(secp()
+            .verify_schnorr(&signature.sig, &Message::from(other), &keys[2])
+            .is_err());
+        assert_eq!(
+            sha256::Hash::hash(&witness[1]),
+            sha256::Hash::hash(b"the preimage")
+        );
+
+        // not a leaf of the tree
+        assert!(tree.control_block(&single_key_leaf(&keys[0])).is_err());
+    }
+
+    #[test]
+    fn test_multisig_spend() {
+        let ag = AccountGenerator::new(MNEMONIC, Network::Testnet).unwrap();
+        let accounts = (0..4)
+            .map(|index| ag.get_account(KeychainKind::External, index).unwrap())
+            .collect::<Vec<_>>();
+        let keys = accounts[..3]
+            .iter()
+            .map(|account| account.x_only_public_key())
+            .collect::<Vec<_>>();
+        let multisig = TaprootMultisig::new(2, &keys, None).unwrap();
+        assert_eq!(multisig.tree().internal_key(), nums_internal_key());
+
+        let prevouts = [TxOut {
+            value: 50_000,
+            script_pubkey: multisig.script_pubkey(),
+        }];
+        let tx = Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: 49_000,
+                script_pubkey: ScriptBuf::new_v0_p2wpkh(&WPubkeyHash::all_zeros()),
+            }],
+        };
+
+        // accounts 0 and 2 sign, account 1 doesn't
+        let sighash = multisig
+            .tree()
+            .script_spend_sighash(&tx, 0, &prevouts, multisig.leaf(), TapSighashType::Default)
+            .unwrap();
+        let signatures = [&accounts[0], &accounts[2]]
+            .into_iter()
+            .map(|account| {
+                let signature = multisig
+                    .sign(account, &tx, 0, &prevouts, TapSighashType::Default)
+                    .unwrap();
+                let key = account.x_only_public_key();
+                assert!(secp()
+                    .verify_schnorr(&signature.sig, &Message::from(sighash), &key)
+                    .is_ok());
+                (key, signature)
+            })
+            .collect::<BTreeMap<_, _>>();
+        assert!(multisig
+            .sign(&accounts[3], &tx, 0, &prevouts, TapSighashType::Default)
+            .is_err());
+
+        // the last key's item at the bottom of the stack, the first one's on top
+        let witness = multisig.witness(&signatures).unwrap();
+        assert_eq!(witness.len(), 3 + 2);
+        for (item, key) in witness.iter().take(3).zip(multisig.keys().iter().rev()) {
+            match signatures.get(key) {
+                Some(signature) => assert_eq!(item, signature.to_vec().as_slice()),
+                None => {
+                    assert_eq!(*key, keys[1]);
+                    assert!(item.is_empty());
+                }
+            }
+        }
+        assert_eq!(&witness[3], multisig.leaf().as_bytes());
+        let control_block = ControlBlock::decode(&witness[4]).unwrap();
+        assert!(control_block.verify_taproot_commitment(
+            secp(),
+            multisig.tree().output_key().to_inner(),
+            multisig.leaf()
+        ));
+
+        let one = signatures
+            .iter()
+            .take(1)
+            .map(|(key, signature)| (*key, *signature))
+            .collect();
+        assert_eq!(
+            multisig.witness(&one).unwrap_err().to_string(),
+            "1 of 2 signatures"
+        );
+    }
+}
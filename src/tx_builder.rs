@@ -0,0 +1,593 @@
+// builds an unsigned psbt from the utxos to spend, the recipients and a fee rate. the fee
+// is the predicted weight of the signed tx (see `weight`) times the fee rate, what's left
+// over goes to the change output (or to the fee, see `ChangePolicy`).
+
+use crate::coin_selection::{CoinSelection, WeightedUtxo};
+use crate::error::Error;
+use crate::psbt::update_input;
+use crate::weight::{fee_for_weight, satisfaction_weight, InputType};
+use bitcoin::{
+    absolute::LockTime, psbt::Psbt, script::PushBytes, Address, FeeRate, OutPoint, ScriptBuf,
+    Sequence, Transaction, TxIn, TxOut, Weight,
+};
+
+// standardness limit of an OP_RETURN output script (80 bytes of data)
+pub const MAX_OP_RETURN_SIZE: usize = 83;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum ChangePolicy {
+    // no change output, whatever is left over goes to the fee
+    #[default]
+    NoChange,
+    // change to this script, left to the fee when it would be dust
+    Change(ScriptBuf),
+    // change to this script, it being dust is an error. e.g. a runestone pointing at it.
+    RequiredChange(ScriptBuf),
+}
+
+#[derive(Clone, Debug)]
+struct Utxo {
+    outpoint: OutPoint,
+    txout: TxOut,
+    previous_tx: Option<Transaction>,
+    // how the input is signed, guessed from the script pubkey when none
+    input_type: Option<InputType>,
+}
+
+#[derive(Clone, Debug)]
+pub struct TxBuilder {
+    utxos: Vec<Utxo>,
+    outputs: Vec<TxOut>,
+    fee_rate: FeeRate,
+    change: ChangePolicy,
+    rbf: bool,
+    lock_time: LockTime,
+    version: i32,
+}
+
+impl TxBuilder {
+    // a version 2 tx signaling rbf, without a lock time or change
+    pub fn new(fee_rate: FeeRate) -> Self {
+        Self {
+            utxos: vec![],
+            outputs: vec![],
+            fee_rate,
+            change: ChangePolicy::NoChange,
+            rbf: true,
+            lock_time: LockTime::ZERO,
+            version: 2,
+        }
+    }
+
+    pub fn add_utxo(mut self, outpoint: OutPoint, txout: TxOut) -> Self {
+        self.utxos.push(Utxo {
+            outpoint,
+            txout,
+            previous_tx: None,
+            input_type: None,
+        });
+        self
+    }
+
+    // a utxo the script pubkey doesn't tell how it's spent, e.g. a multisig or a tap leaf
+    pub fn add_utxo_with_input_type(
+        mut self,
+        outpoint: OutPoint,
+        txout: TxOut,
+        input_type: InputType,
+    ) -> Self {
+        self.utxos.push(Utxo {
+            outpoint,
+            txout,
+            previous_tx: None,
+            input_type: Some(input_type),
+        });
+        self
+    }
+
+    // output `vout` of `previous_tx`, which legacy inputs need in the psbt
+    pub fn add_utxo_with_previous_tx(
+        mut self,
+        previous_tx: Transaction,
+        vout: u32,
+    ) -> Result<Self, Error> {
+        let outpoint = OutPoint::new(previous_tx.txid(), vout);
+        let txout = previous_tx
+            .output
+            .get(vout as usize)
+            .cloned()
+            .ok_or(Error::NoSuchOutput(outpoint))?;
+        self.utxos.push(Utxo {
+            outpoint,
+            txout,
+            previous_tx: Some(previous_tx),
+            input_type: None,
+        });
+        Ok(self)
+    }
+
+    pub fn add_recipient(mut self, script_pubkey: ScriptBuf, value: u64) -> Self {
+        self.outputs.push(TxOut {
+            value,
+            script_pubkey,
+        });
+        self
+    }
+
+    pub fn add_address_recipient(self, address: &Address, value: u64) -> Self {
+        self.add_recipient(address.script_pubkey(), value)
+    }
+
+    // an OP_RETURN output script, e.g. a runestone
+    pub fn add_op_return(self, script: ScriptBuf) -> Self {
+        self.add_recipient(script, 0)
+    }
+
+    // OP_RETURN <data>
+    pub fn add_op_return_data(self, data: impl AsRef<PushBytes>) -> Self {
+        self.add_op_return(ScriptBuf::new_op_return(&data))
+    }
+
+    pub fn with_fee_rate(mut self, fee_rate: FeeRate) -> Self {
+        self.fee_rate = fee_rate;
+        self
+    }
+
+    pub fn with_change(mut self, change: ChangePolicy) -> Self {
+        self.change = change;
+        self
+    }
+
+    pub fn with_rbf(mut self, rbf: bool) -> Self {
+        self.rbf = rbf;
+        self
+    }
+
+    pub fn with_lock_time(mut self, lock_time: LockTime) -> Self {
+        self.lock_time = lock_time;
+        self
+    }
+
+    pub fn with_version(mut self, version: i32) -> Self {
+        self.version = version;
+        self
+    }
+
+    // the sequence of every input, a lock time only applies with a non final one
+    fn sequence(&self) -> Sequence {
+        match (self.rbf, self.lock_time == LockTime::ZERO) {
+            (true, _) => Sequence::ENABLE_RBF_NO_LOCKTIME,
+            (false, false) => Sequence::ENABLE_LOCKTIME_NO_RBF,
+            (false, true) => Sequence::MAX,
+        }
+    }
+
+    fn fee(&self, weight: Weight) -> u64 {
+        fee_for_weight(self.fee_rate, weight)
+    }
+
+    // the tx without satisfactions or change
+    fn unsigned_tx(&self) -> Transaction {
+        Transaction {
+            version: self.version,
+            lock_time: self.lock_time,
+            input: self
+                .utxos
+                .iter()
+                .map(|utxo| TxIn {
+                    previous_output: utxo.outpoint,
+                    sequence: self.sequence(),
+                    ..Default::default()
+                })
+                .collect(),
+            output: self.outputs.clone(),
+        }
+    }
+
+    fn input_types(&self) -> Result<Vec<InputType>, Error> {
+        self.utxos
+            .iter()
+            .map(|utxo| {
+                utxo.input_type
+                    .clone()
+                    .or_else(|| InputType::from_script_pubkey(&utxo.txout.script_pubkey))
+                    .ok_or(Error::UnknownInputScript(utxo.outpoint))
+            })
+            .collect()
+    }
+
+    fn satisfaction_weight(&self) -> Result<Weight, Error> {
+        Ok(satisfaction_weight(&self.input_types()?))
+    }
+
+    // adds the utxos `algorithm` picks from `candidates` to pay for the outputs and the fee
+    // the utxos already added don't cover. a changeless selection drops `ChangePolicy::Change`.
+    pub fn select_utxos(
+        mut self,
+        candidates: Vec<WeightedUtxo>,
+        algorithm: CoinSelection,
+    ) -> Result<Self, Error> {
+        // the segwit marker and flag a selected input may bring, unless the inputs so far
+        // have a witness and they're in `satisfaction_weight` already
+        let inputs = self.input_types()?;
+        let marker = match inputs.iter().any(|input| input.witness_size().is_some()) {
+            true => Weight::ZERO,
+            false => Weight::from_witness_data_size(2),
+        };
+        let weight = self.unsigned_tx().weight() + satisfaction_weight(&inputs) + marker;
+        let available = self.utxos.iter().map(|utxo| utxo.txout.value).sum::<u64>();
+        let sent = self.outputs.iter().map(|output| output.value).sum::<u64>();
+        let target = (sent + self.fee(weight)).saturating_sub(available);
+        if target == 0 && !self.utxos.is_empty() {
+            return Ok(self);
+        }
+
+        let selection = algorithm.select(candidates, target, self.fee_rate, &self.change)?;
+        if !selection.change && matches!(self.change, ChangePolicy::Change(_)) {
+            self.change = ChangePolicy::NoChange;
+        }
+        self.utxos
+            .extend(selection.utxos.into_iter().map(|utxo| Utxo {
+                outpoint: utxo.outpoint,
+                txout: utxo.txout,
+                previous_tx: utxo.previous_tx,
+                input_type: utxo.input_type,
+            }));
+        Ok(self)
+    }
+
+    // the unsigned tx with the fee taken out of the change
+    pub fn build_tx(&self) -> Result<Transaction, Error> {
+        if self.utxos.is_empty() {
+            return Err(Error::NoInputs);
+        }
+        if self.outputs.is_empty() && self.change == ChangePolicy::NoChange {
+            return Err(Error::NoOutputs);
+        }
+        for (index, output) in self.outputs.iter().enumerate() {
+            let script = &output.script_pubkey;
+            if script.is_op_return() {
+                if script.len() > MAX_OP_RETURN_SIZE {
+                    return Err(Error::OpReturnTooLarge(script.len()));
+                }
+                continue;
+            }
+            let dust = script.dust_value().to_sat();
+            if output.value < dust {
+                return Err(Error::DustOutput {
+                    index,
+                    value: output.value,
+                    dust,
+                });
+            }
+        }
+
+        let mut tx = self.unsigned_tx();
+        let satisfaction_weight = self.satisfaction_weight()?;
+
+        let available = self.utxos.iter().map(|utxo| utxo.txout.value).sum::<u64>();
+        let sent = self.outputs.iter().map(|output| output.value).sum::<u64>();
+        let fee = self.fee(tx.weight() + satisfaction_weight);
+        if available < sent + fee {
+            return Err(Error::InsufficientFunds {
+                needed: sent + fee,
+                available,
+            });
+        }
+
+        let (change_script, required) = match &self.change {
+            ChangePolicy::NoChange => return Ok(tx),
+            ChangePolicy::Change(script) => (script, false),
+            ChangePolicy::RequiredChange(script) => (script, true),
+        };
+        tx.output.push(TxOut {
+            value: 0,
+            script_pubkey: change_script.clone(),
+        });
+        let fee = self.fee(tx.weight() + satisfaction_weight);
+        let change = available.saturating_sub(sent + fee);
+        let dust = change_script.dust_value().to_sat();
+        match (change < dust, required) {
+            (false, _) => tx.output.last_mut().expect("the change output").value = change,
+            (true, false) => {
+                tx.output.pop();
+            }
+            (true, true) => {
+                return Err(Error::InsufficientFunds {
+                    needed: sent + fee + dust,
+                    available,
+                })
+            }
+        }
+
+        Ok(tx)
+    }
+
+    // the unsigned psbt of `build_tx`, with the utxo of every input
+    pub fn build(&self) -> Result<Psbt, Error> {
+        let tx = self.build_tx()?;
+        let mut psbt = Psbt::from_unsigned_tx(tx).expect("the tx is unsigned");
+        for (index, utxo) in self.utxos.iter().enumerate() {
+            update_input(
+                &mut psbt,
+                index,
+                utxo.txout.clone(),
+                utxo.previous_tx.clone(),
+            )
+            .map_err(|_| Error::MissingPreviousTx(utxo.outpoint))?;
+        }
+        Ok(psbt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coin_selection::{CoinSelection, WeightedUtxo};
+    use crate::error::Error;
+    use crate::key_pair::{Account, AccountGenerator, KeychainKind};
+    use crate::signer::{Signer, SingleKeyType};
+    use crate::tx_builder::{ChangePolicy, TxBuilder};
+    use crate::weight::{fee_for_weight, predict_weight, InputType};
+    use bitcoin::absolute::LockTime;
+    use bitcoin::hashes::Hash;
+    use bitcoin::script::PushBytesBuf;
+    use bitcoin::{FeeRate, Network, OutPoint, ScriptBuf, Sequence, Transaction, TxOut, Txid};
+
+    const MNEMONIC: &str =
+        "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+
+    fn account() -> Account {
+        AccountGenerator::new(MNEMONIC, Network::Testnet)
+            .unwrap()
+            .get_account(KeychainKind::External, 0)
+            .unwrap()
+    }
+
+    fn p2wpkh() -> ScriptBuf {
+        SingleKeyType::P2wpkh.script_pubkey(&account()).unwrap()
+    }
+
+    fn fee_rate() -> FeeRate {
+        FeeRate::from_sat_per_vb(10).unwrap()
+    }
+
+    // `values` sats to `script_pubkey`, one utxo each
+    fn utxos(script_pubkey: &ScriptBuf, values: &[u64]) -> Vec<(OutPoint, TxOut)> {
+        values
+            .iter()
+            .enumerate()
+            .map(|(vout, value)| {
+                (
+                    OutPoint::new(Txid::all_zeros(), vout as u32),
+                    TxOut {
+                        value: *value,
+                        script_pubkey: script_pubkey.clone(),
+                    },
+                )
+            })
+            .collect()
+    }
+
+    fn builder(utxos: &[(OutPoint, TxOut)]) -> TxBuilder {
+        utxos
+            .iter()
+            .fold(TxBuilder::new(fee_rate()), |builder, (outpoint, txout)| {
+                builder.add_utxo(*outpoint, txout.clone())
+            })
+    }
+
+    fn fee(tx: &Transaction, prevouts: &[TxOut]) -> u64 {
+        prevouts.iter().map(|prevout| prevout.value).sum::<u64>()
+            - tx.output.iter().map(|output| output.value).sum::<u64>()
+    }
+
+    #[test]
+    fn test_fee_of_signed_weight() {
+        let account = account();
+        let utxos = [
+            SingleKeyType::P2pkh,
+            SingleKeyType::P2wpkh,
+            SingleKeyType::P2tr,
+        ]
+        .iter()
+        .enumerate()
+        .map(|(vout, kind)| {
+            (
+                OutPoint::new(Txid::all_zeros(), vout as u32),
+                TxOut {
+                    value: 50_000,
+                    script_pubkey: kind.script_pubkey(&account).unwrap(),
+                },
+            )
+        })
+        .collect::<Vec<_>>();
+        let prevouts = utxos
+            .iter()
+            .map(|(_, txout)| txout.clone())
+            .collect::<Vec<_>>();
+
+        let mut tx = builder(&utxos)
+            .add_recipient(p2wpkh(), 100_000)
+            .add_op_return_data(b"hello")
+            .with_change(ChangePolicy::Change(p2wpkh()))
+            .build_tx()
+            .unwrap();
+        assert_eq!(tx.output.len(), 3);
+
+        Signer::new(vec![account])
+            .unwrap()
+            .sign_all(&mut tx, &prevouts)
+            .unwrap();
+        assert_eq!(fee(&tx, &prevouts), fee_for_weight(fee_rate(), tx.weight()));
+    }
+
+    #[test]
+    fn test_dust_change() {
+        let utxos = utxos(&p2wpkh(), &[50_000]);
+        let prevouts = vec![utxos[0].1.clone()];
+        let dust = p2wpkh().dust_value().to_sat();
+
+        // the fee with a change output, the same for any change value
+        let tx = builder(&utxos)
+            .add_recipient(p2wpkh(), 10_000)
+            .with_change(ChangePolicy::Change(p2wpkh()))
+            .build_tx()
+            .unwrap();
+        let fee = fee(&tx, &prevouts);
+
+        for (change, kept) in [(dust, true), (dust - 1, false)] {
+            let sent = 50_000 - fee - change;
+            let tx = builder(&utxos)
+                .add_recipient(p2wpkh(), sent)
+                .with_change(ChangePolicy::Change(p2wpkh()))
+                .build_tx()
+                .unwrap();
+            assert_eq!(tx.output.len(), if kept { 2 } else { 1 });
+            if kept {
+                assert_eq!(tx.output[1].value, change);
+            }
+
+            let required = builder(&utxos)
+                .add_recipient(p2wpkh(), sent)
+                .with_change(ChangePolicy::RequiredChange(p2wpkh()))
+                .build_tx();
+            match kept {
+                true => assert_eq!(required.unwrap(), tx),
+                false => assert_eq!(
+                    required.unwrap_err(),
+                    Error::InsufficientFunds {
+                        needed: sent + fee + dust,
+                        available: 50_000,
+                    }
+                ),
+            }
+        }
+    }
+
+    #[test]
+    fn test_invalid_outputs() {
+        let utxos = utxos(&p2wpkh(), &[50_000]);
+        let dust = p2wpkh().dust_value().to_sat();
+        assert_eq!(
+            builder(&utxos)
+                .add_recipient(p2wpkh(), 10_000)
+                .add_recipient(p2wpkh(), dust - 1)
+                .build_tx()
+                .unwrap_err(),
+            Error::DustOutput {
+                index: 1,
+                value: dust - 1,
+                dust,
+            }
+        );
+
+        // 80 bytes of data is the most, OP_RETURN OP_PUSHDATA1 <len> <data>
+        assert!(builder(&utxos)
+            .add_op_return_data(PushBytesBuf::try_from(vec![0; 80]).unwrap())
+            .build_tx()
+            .is_ok());
+        assert_eq!(
+            builder(&utxos)
+                .add_op_return_data(PushBytesBuf::try_from(vec![0; 81]).unwrap())
+                .build_tx()
+                .unwrap_err(),
+            Error::OpReturnTooLarge(84)
+        );
+
+        assert_eq!(
+            TxBuilder::new(fee_rate())
+                .add_recipient(p2wpkh(), 10_000)
+                .build_tx()
+                .unwrap_err(),
+            Error::NoInputs
+        );
+        assert_eq!(builder(&utxos).build_tx().unwrap_err(), Error::NoOutputs);
+    }
+
+    #[test]
+    fn test_sequence() {
+        let utxos = utxos(&p2wpkh(), &[50_000, 50_000]);
+        let height = LockTime::from_height(840_000).unwrap();
+        for (rbf, lock_time, sequence) in [
+            (true, LockTime::ZERO, Sequence::ENABLE_RBF_NO_LOCKTIME),
+            (true, height, Sequence::ENABLE_RBF_NO_LOCKTIME),
+            (false, height, Sequence::ENABLE_LOCKTIME_NO_RBF),
+            (false, LockTime::ZERO, Sequence::MAX),
+        ] {
+            let tx = builder(&utxos)
+                .add_recipient(p2wpkh(), 10_000)
+                .with_rbf(rbf)
+                .with_lock_time(lock_time)
+                .build_tx()
+                .unwrap();
+            assert_eq!(tx.lock_time, lock_time);
+            assert!(tx.input.iter().all(|input| input.sequence == sequence));
+            assert_eq!(tx.is_explicitly_rbf(), rbf);
+            assert_eq!(tx.is_lock_time_enabled(), sequence != Sequence::MAX);
+        }
+    }
+
+    #[test]
+    fn test_select_utxos() {
+        let candidates = utxos(&p2wpkh(), &[10_000, 20_000, 40_000, 80_000])
+            .into_iter()
+            .enumerate()
+            .map(|(height, (outpoint, txout))| {
+                WeightedUtxo::new(outpoint, txout, height as u32 + 1)
+            })
+            .collect::<Vec<_>>();
+        let input = InputType::SingleKey(SingleKeyType::P2wpkh);
+
+        for algorithm in [
+            CoinSelection::BranchAndBound,
+            CoinSelection::LargestFirst,
+            CoinSelection::OldestFirst,
+            CoinSelection::SingleRandomDraw,
+        ] {
+            let builder = TxBuilder::new(fee_rate())
+                .add_recipient(p2wpkh(), 45_000)
+                .with_change(ChangePolicy::Change(p2wpkh()))
+                .select_utxos(candidates.clone(), algorithm)
+                .unwrap();
+            let tx = builder.build_tx().unwrap();
+            let prevouts = tx
+                .input
+                .iter()
+                .map(|input| {
+                    candidates[input.previous_output.vout as usize]
+                        .txout
+                        .clone()
+                })
+                .collect::<Vec<_>>();
+
+            // the fee rate is met, the change being dropped only adds to it
+            let predicted = predict_weight(&tx, &vec![input.clone(); tx.input.len()]);
+            assert!(fee(&tx, &prevouts) >= fee_for_weight(fee_rate(), predicted));
+            let values = prevouts
+                .iter()
+                .map(|prevout| prevout.value)
+                .collect::<Vec<_>>();
+            match algorithm {
+                CoinSelection::LargestFirst => assert_eq!(values, vec![80_000]),
+                CoinSelection::OldestFirst => assert_eq!(values, vec![10_000, 20_000, 40_000]),
+                _ => assert!(values.iter().sum::<u64>() > 45_000),
+            }
+
+            // already paid for, nothing more is selected
+            let again = builder
+                .clone()
+                .select_utxos(candidates.clone(), algorithm)
+                .unwrap();
+            assert_eq!(again.build_tx().unwrap(), tx);
+        }
+
+        assert_eq!(
+            TxBuilder::new(fee_rate())
+                .add_recipient(p2wpkh(), 200_000)
+                .select_utxos(candidates, CoinSelection::LargestFirst)
+                .err()
+                .map(|e| matches!(e, Error::InsufficientFunds { .. })),
+            Some(true)
+        );
+    }
+}
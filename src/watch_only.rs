@@ -0,0 +1,301 @@
+// derive addresses from an account xpub, without any private key material.
+
+use crate::key_pair::{KeychainKind, Purpose};
+use crate::secp::secp;
+use anyhow::Error;
+use bitcoin::{
+    base58,
+    bip32::{ChildNumber, DerivationPath, ExtendedPubKey, Fingerprint},
+    key::XOnlyPublicKey,
+    secp256k1::PublicKey,
+    Address, Network, ScriptBuf,
+};
+use std::str::FromStr;
+
+// SLIP-132 version bytes, https://github.com/satoshilabs/slips/blob/master/slip-0132.md
+const XPUB: [u8; 4] = [0x04, 0x88, 0xb2, 0x1e];
+const YPUB: [u8; 4] = [0x04, 0x9d, 0x7c, 0xb2];
+const ZPUB: [u8; 4] = [0x04, 0xb2, 0x47, 0x46];
+const TPUB: [u8; 4] = [0x04, 0x35, 0x87, 0xcf];
+const UPUB: [u8; 4] = [0x04, 0x4a, 0x52, 0x62];
+const VPUB: [u8; 4] = [0x04, 0x5f, 0x1c, 0xf6];
+
+// xpub/tpub for legacy and taproot, ypub/upub for p2sh-p2wpkh and zpub/vpub for p2wpkh
+pub fn encode_slip132(xpub: &ExtendedPubKey, purpose: Purpose) -> String {
+    let version = match (xpub.network, purpose) {
+        (Network::Bitcoin, Purpose::NestedSegwit) => YPUB,
+        (Network::Bitcoin, Purpose::NativeSegwit) => ZPUB,
+        (Network::Bitcoin, _) => XPUB,
+        (_, Purpose::NestedSegwit) => UPUB,
+        (_, Purpose::NativeSegwit) => VPUB,
+        (_, _) => TPUB,
+    };
+
+    let mut data = xpub.encode();
+    data[0..4].copy_from_slice(&version);
+    base58::encode_check(&data)
+}
+
+// the purpose is only known for ypub/zpub (and upub/vpub), xpub/tpub could be anything
+pub fn decode_slip132(s: &str) -> anyhow::Result<(ExtendedPubKey, Option<Purpose>)> {
+    let mut data = base58::decode_check(s)?;
+    if data.len() != 78 {
+        return Err(Error::msg(format!(
+            "invalid extended key length: {}",
+            data.len()
+        )));
+    }
+
+    let (version, purpose) = match data[0..4].try_into()? {
+        XPUB => (XPUB, None),
+        YPUB => (XPUB, Some(Purpose::NestedSegwit)),
+        ZPUB => (XPUB, Some(Purpose::NativeSegwit)),
+        TPUB => (TPUB, None),
+        UPUB => (TPUB, Some(Purpose::NestedSegwit)),
+        VPUB => (TPUB, Some(Purpose::NativeSegwit)),
+        _ => return Err(Error::msg(format!("unknown xpub version: {}", &s[..4]))),
+    };
+    data[0..4].copy_from_slice(&version);
+
+    Ok((ExtendedPubKey::decode(&data)?, purpose))
+}
+
+#[derive(Clone, Debug)]
+pub struct WatchOnlyAccount {
+    network: Network,
+    public_key: PublicKey,
+    derivation_path: DerivationPath,
+    purpose: Purpose,
+}
+
+impl WatchOnlyAccount {
+    pub fn public_key(&self) -> bitcoin::PublicKey {
+        bitcoin::PublicKey::new(self.public_key)
+    }
+
+    pub fn x_only_public_key(&self) -> XOnlyPublicKey {
+        XOnlyPublicKey::from(self.public_key)
+    }
+
+    pub fn script_pubkey(&self) -> ScriptBuf {
+        self.purpose.script_pubkey(&self.public_key)
+    }
+
+    pub fn address(&self) -> anyhow::Result<Address> {
+        Ok(Address::from_script(&self.script_pubkey(), self.network)?)
+    }
+
+    pub fn derivation_path(&self) -> &DerivationPath {
+        &self.derivation_path
+    }
+
+    pub fn network(&self) -> &Network {
+        &self.network
+    }
+
+    pub fn purpose(&self) -> Purpose {
+        self.purpose
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct WatchOnlyGenerator {
+    network: Network,
+    purpose: Purpose,
+    account_xpub: ExtendedPubKey,
+    // master fingerprint and path of the account xpub
+    fingerprint: Fingerprint,
+    account_derivation_path: DerivationPath,
+}
+
+impl WatchOnlyGenerator {
+    // without a key origin, the xpub itself is treated as the root
+    pub fn new(account_xpub: ExtendedPubKey, purpose: Purpose) -> Self {
+        Self {
+            network: account_xpub.network,
+            purpose,
+            account_xpub,
+            fingerprint: account_xpub.fingerprint(),
+            account_derivation_path: DerivationPath::master(),
+        }
+    }
+
+    // accepts xpub/tpub and the SLIP-132 ypub/zpub/upub/vpub,
+    // `purpose` is required for xpub/tpub and must agree with ypub/zpub.
+    pub fn from_xpub_str(s: &str, purpose: Option<Purpose>) -> anyhow::Result<Self> {
+        let (account_xpub, slip132_purpose) = decode_slip132(s)?;
+
+        let purpose = match (purpose, slip132_purpose) {
+            (Some(purpose), Some(slip132)) if purpose != slip132 => {
+                return Err(Error::msg(format!(
+                    "purpose {purpose} does not match the xpub version ({slip132})"
+                )))
+            }
+            (Some(purpose), _) | (None, Some(purpose)) => purpose,
+            (None, None) => return Err(Error::msg("purpose is required for xpub/tpub")),
+        };
+
+        Ok(Self::new(account_xpub, purpose))
+    }
+
+    // where the account xpub comes from, e.g. [73c5da0a/86'/0'/0']
+    pub fn with_origin(mut self, fingerprint: Fingerprint, path: DerivationPath) -> Self {
+        self.fingerprint = fingerprint;
+        self.account_derivation_path = path;
+        self
+    }
+
+    pub fn get_account_from_index(&self, index: u32) -> anyhow::Result<WatchOnlyAccount> {
+        self.get_account(KeychainKind::External, index)
+    }
+
+    pub fn get_change_account_from_index(&self, index: u32) -> anyhow::Result<WatchOnlyAccount> {
+        self.get_account(KeychainKind::Internal, index)
+    }
+
+    pub fn get_account(
+        &self,
+        keychain: KeychainKind,
+        index: u32,
+    ) -> anyhow::Result<WatchOnlyAccount> {
+        let secp = secp();
+        let children = [
+            ChildNumber::from_normal_idx(keychain.index())?,
+            ChildNumber::from_normal_idx(index)?,
+        ];
+        let derived = self.account_xpub.derive_pub(secp, &children)?;
+
+        Ok(WatchOnlyAccount {
+            network: self.network,
+            public_key: derived.public_key,
+            derivation_path: self.account_derivation_path.extend(children),
+            purpose: self.purpose,
+        })
+    }
+
+    pub fn keychain_derivation_path(
+        &self,
+        keychain: KeychainKind,
+        index: u32,
+    ) -> anyhow::Result<DerivationPath> {
+        Ok(DerivationPath::from_str(&format!(
+            "{}/{}/{index}",
+            self.account_derivation_path,
+            keychain.index()
+        ))?)
+    }
+
+    pub fn account_xpub(&self) -> &ExtendedPubKey {
+        &self.account_xpub
+    }
+
+    pub fn account_xpub_slip132(&self) -> String {
+        encode_slip132(&self.account_xpub, self.purpose)
+    }
+
+    pub fn account_derivation_path(&self) -> &DerivationPath {
+        &self.account_derivation_path
+    }
+
+    pub fn fingerprint(&self) -> Fingerprint {
+        self.fingerprint
+    }
+
+    pub fn network(&self) -> &Network {
+        &self.network
+    }
+
+    pub fn purpose(&self) -> Purpose {
+        self.purpose
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::key_pair::{AccountGenerator, KeychainKind, Purpose};
+    use crate::watch_only::{decode_slip132, WatchOnlyGenerator};
+    use bitcoin::Network;
+
+    const MNEMONIC: &str =
+        "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+
+    #[test]
+    fn test_addresses_match_account_generator() {
+        for (network, prefixes) in [
+            (Network::Bitcoin, ["xpub", "ypub", "zpub", "xpub"]),
+            (Network::Testnet, ["tpub", "upub", "vpub", "tpub"]),
+        ] {
+            for (purpose, prefix) in Purpose::ALL.into_iter().zip(prefixes) {
+                let ag = AccountGenerator::new(MNEMONIC, network)
+                    .unwrap()
+                    .with_purpose(purpose);
+                let exported = ag.account_xpub_slip132().unwrap();
+                assert!(exported.starts_with(prefix), "{exported}");
+
+                // ypub/zpub carry the purpose, xpub/tpub need it
+                let slip132_purpose = decode_slip132(&exported).unwrap().1;
+                assert_eq!(
+                    slip132_purpose.is_some(),
+                    matches!(purpose, Purpose::NestedSegwit | Purpose::NativeSegwit)
+                );
+                let wo = WatchOnlyGenerator::from_xpub_str(&exported, Some(purpose))
+                    .unwrap()
+                    .with_origin(ag.fingerprint(), ag.account_derivation_path().unwrap());
+                assert_eq!(wo.account_xpub_slip132(), exported);
+
+                for keychain in KeychainKind::ALL {
+                    for index in 0..5 {
+                        let account = ag.get_account(keychain, index).unwrap();
+                        let watch_only = wo.get_account(keychain, index).unwrap();
+                        assert_eq!(watch_only.address().unwrap(), account.address().unwrap());
+                        assert_eq!(watch_only.derivation_path(), account.derivation_path());
+                    }
+                }
+            }
+        }
+    }
+
+    // the first receive addresses of the BIP44/49/84/86 test vectors
+    #[test]
+    fn test_bip_vectors() {
+        for (purpose, xpub, address) in [
+            (Purpose::Legacy, None, "1LqBGSKuX5yYUonjxT5qGfpUsXKYYWeabA"),
+            (Purpose::NestedSegwit, None, "37VucYSaXLCAsxYyAPfbSi9eh4iEcbShgf"),
+            (
+                Purpose::NativeSegwit,
+                Some("zpub6rFR7y4Q2AijBEqTUquhVz398htDFrtymD9xYYfG1m4wAcvPhXNfE3EfH1r1ADqtfSdVCToUG868RvUUkgDKf31mGDtKsAYz2oz2AGutZYs"),
+                "bc1qcr8te4kr609gcawutmrza0j4xv80jy8z306fyu",
+            ),
+            (
+                Purpose::Taproot,
+                None,
+                "bc1p5cyxnuxmeuwuvkwfem96lqzszd02n6xdcjrs20cac6yqjjwudpxqkedrcr",
+            ),
+        ] {
+            let ag = AccountGenerator::new(MNEMONIC, Network::Bitcoin)
+                .unwrap()
+                .with_purpose(purpose);
+            let exported = ag.account_xpub_slip132().unwrap();
+            if let Some(xpub) = xpub {
+                assert_eq!(exported, xpub);
+            }
+            let wo = WatchOnlyGenerator::from_xpub_str(&exported, Some(purpose)).unwrap();
+            assert_eq!(
+                wo.get_account_from_index(0)
+                    .unwrap()
+                    .address()
+                    .unwrap()
+                    .to_string(),
+                address
+            );
+        }
+
+        // a zpub is not a BIP44 key
+        let zpub = AccountGenerator::new(MNEMONIC, Network::Bitcoin)
+            .unwrap()
+            .with_purpose(Purpose::NativeSegwit)
+            .account_xpub_slip132()
+            .unwrap();
+        assert!(WatchOnlyGenerator::from_xpub_str(&zpub, Some(Purpose::Legacy)).is_err());
+    }
+}
@@ -0,0 +1,402 @@
+// predicts the weight of a tx once its inputs are signed, from the unsigned tx and how each
+// input will be satisfied, so the fee paid is the fee rate of the tx that gets broadcast.
+// ecdsa signatures are ground to a low R (`sign_ecdsa_low_r`) and so at most 71 bytes.
+
+use crate::script_hash::ScriptHashType;
+use crate::signer::SingleKeyType;
+use crate::taproot::{TaprootMultisig, TaprootScriptTree};
+use bitcoin::{FeeRate, Script, Transaction, VarInt, Weight};
+
+// a DER signature with a low R and a low S is at most 70 bytes, plus the sighash type
+pub const ECDSA_SIGNATURE_SIZE: usize = 71;
+// SIGHASH_DEFAULT, any other sighash type adds a byte
+pub const SCHNORR_SIGNATURE_SIZE: usize = 64;
+const COMPRESSED_KEY_SIZE: usize = 33;
+const UNCOMPRESSED_KEY_SIZE: usize = 65;
+// outpoint and sequence
+const TXIN_BASE_SIZE: usize = 36 + 4;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum InputType {
+    SingleKey(SingleKeyType),
+    // a leaf of a taproot tree, with the sizes of the items it takes from the stack
+    TapScript {
+        leaf_size: usize,
+        control_block_size: usize,
+        stack: Vec<usize>,
+    },
+    // OP_m <n compressed keys> OP_n OP_CHECKMULTISIG
+    Multisig {
+        kind: ScriptHashType,
+        threshold: usize,
+        keys: usize,
+    },
+}
+
+impl InputType {
+    // the single key input a script pubkey is most likely spent as, p2sh taken as p2sh-p2wpkh
+    pub fn from_script_pubkey(script_pubkey: &Script) -> Option<Self> {
+        let kind = if script_pubkey.is_p2pkh() {
+            SingleKeyType::P2pkh
+        } else if script_pubkey.is_p2sh() {
+            SingleKeyType::P2shP2wpkh
+        } else if script_pubkey.is_v0_p2wpkh() {
+            SingleKeyType::P2wpkh
+        } else if script_pubkey.is_v1_p2tr() {
+            SingleKeyType::P2tr
+        } else {
+            return None;
+        };
+        Some(InputType::SingleKey(kind))
+    }
+
+    // spending `leaf` of `tree` with items of these sizes on the stack
+    pub fn tap_script(
+        tree: &TaprootScriptTree,
+        leaf: &Script,
+        stack: Vec<usize>,
+    ) -> anyhow::Result<Self> {
+        Ok(InputType::TapScript {
+            leaf_size: leaf.len(),
+            control_block_size: tree.control_block(leaf)?.size(),
+            stack,
+        })
+    }
+
+    // `threshold` signatures and an empty item for every other key
+    pub fn tap_multisig(multisig: &TaprootMultisig) -> anyhow::Result<Self> {
+        let mut stack = vec![SCHNORR_SIGNATURE_SIZE; multisig.threshold()];
+        stack.resize(multisig.keys().len(), 0);
+        Self::tap_script(multisig.tree(), multisig.leaf(), stack)
+    }
+
+    // the bytes of the scriptSig
+    pub fn script_sig_size(&self) -> usize {
+        match self {
+            InputType::SingleKey(SingleKeyType::P2pkh) => {
+                push_size(ECDSA_SIGNATURE_SIZE) + push_size(COMPRESSED_KEY_SIZE)
+            }
+            InputType::SingleKey(SingleKeyType::P2pkhUncompressed) => {
+                push_size(ECDSA_SIGNATURE_SIZE) + push_size(UNCOMPRESSED_KEY_SIZE)
+            }
+            // the p2wpkh program
+            InputType::SingleKey(SingleKeyType::P2shP2wpkh) => push_size(22),
+            InputType::SingleKey(SingleKeyType::P2wpkh | SingleKeyType::P2tr) => 0,
+            InputType::TapScript { .. } => 0,
+            InputType::Multisig {
+                kind,
+                threshold,
+                keys,
+            } => match kind {
+                // OP_0 for the CHECKMULTISIG bug, the signatures and the redeem script
+                ScriptHashType::P2sh => {
+                    1 + threshold * push_size(ECDSA_SIGNATURE_SIZE)
+                        + push_size(multisig_script_size(*keys))
+                }
+                ScriptHashType::P2wsh => 0,
+                // the p2wsh program
+                ScriptHashType::P2shP2wsh => push_size(34),
+            },
+        }
+    }
+
+    // the bytes of the witness with its item count, none for a non segwit input
+    pub fn witness_size(&self) -> Option<usize> {
+        let items = match self {
+            InputType::SingleKey(SingleKeyType::P2pkh | SingleKeyType::P2pkhUncompressed) => {
+                return None
+            }
+            InputType::SingleKey(SingleKeyType::P2shP2wpkh | SingleKeyType::P2wpkh) => {
+                vec![ECDSA_SIGNATURE_SIZE, COMPRESSED_KEY_SIZE]
+            }
+            InputType::SingleKey(SingleKeyType::P2tr) => vec![SCHNORR_SIGNATURE_SIZE],
+            InputType::TapScript {
+                leaf_size,
+                control_block_size,
+                stack,
+            } => {
+                let mut items = stack.clone();
+                items.extend([*leaf_size, *control_block_size]);
+                items
+            }
+            InputType::Multisig {
+                kind: ScriptHashType::P2sh,
+                ..
+            } => return None,
+            InputType::Multisig {
+                threshold, keys, ..
+            } => {
+                let mut items = vec![0];
+                items.extend(vec![ECDSA_SIGNATURE_SIZE; *threshold]);
+                items.push(multisig_script_size(*keys));
+                items
+            }
+        };
+        Some(witness_size(&items))
+    }
+
+    // the weight of the signed input in a segwit tx, an empty witness for a non segwit one
+    pub fn input_weight(&self) -> Weight {
+        let script_sig = self.script_sig_size();
+        Weight::from_non_witness_data_size(
+            (TXIN_BASE_SIZE + VarInt(script_sig as u64).len() + script_sig) as u64,
+        ) + Weight::from_witness_data_size(self.witness_size().unwrap_or(1) as u64)
+    }
+}
+
+// the weight satisfying `inputs` adds to an unsigned tx (with empty scriptSigs and witnesses):
+// the scriptSigs count 4 times, a segwit tx has the marker and flag and an empty witness for
+// every non segwit input
+pub fn satisfaction_weight(inputs: &[InputType]) -> Weight {
+    let script_sig = inputs
+        .iter()
+        .map(|input| {
+            let size = input.script_sig_size();
+            // the unsigned tx already has the length byte of the empty scriptSig
+            VarInt(size as u64).len() - 1 + size
+        })
+        .sum::<usize>();
+    let witnesses = inputs
+        .iter()
+        .map(InputType::witness_size)
+        .collect::<Vec<_>>();
+    let witness = match witnesses.iter().any(Option::is_some) {
+        true => {
+            2 + witnesses
+                .iter()
+                .map(|size| size.unwrap_or(1))
+                .sum::<usize>()
+        }
+        false => 0,
+    };
+    Weight::from_non_witness_data_size(script_sig as u64)
+        + Weight::from_witness_data_size(witness as u64)
+}
+
+// the weight of `unsigned_tx` once its inputs are satisfied as `inputs`, in input order
+pub fn predict_weight(unsigned_tx: &Transaction, inputs: &[InputType]) -> Weight {
+    unsigned_tx.weight() + satisfaction_weight(inputs)
+}
+
+// the fee of a tx of `weight` paying `fee_rate` for each of its (rounded up) vbytes
+pub fn fee_for_weight(fee_rate: FeeRate, weight: Weight) -> u64 {
+    (fee_rate.to_sat_per_kwu() * weight.to_vbytes_ceil() * 4).div_ceil(1000)
+}
+
+// OP_m, the pushes of the keys, OP_n and OP_CHECKMULTISIG
+fn multisig_script_size(keys: usize) -> usize {
+    1 + keys * push_size(COMPRESSED_KEY_SIZE) + 1 + 1
+}
+
+// a minimal push of `size` bytes in a scriptSig
+fn push_size(size: usize) -> usize {
+    let opcode = match size {
+        0..=0x4b => 1,
+        0x4c..=0xff => 2,
+        0x100..=0xffff => 3,
+        _ => 5,
+    };
+    opcode + size
+}
+
+fn witness_size(items: &[usize]) -> usize {
+    VarInt(items.len() as u64).len()
+        + items
+            .iter()
+            .map(|size| VarInt(*size as u64).len() + size)
+            .sum::<usize>()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::key_pair::{AccountGenerator, KeychainKind};
+    use crate::multisig::{finalize_multisig_psbt, Cosigner, Multisig, MultisigUtxo};
+    use crate::script_hash::ScriptHashType;
+    use crate::signer::{Signer, SingleKeyType};
+    use crate::taproot::TaprootMultisig;
+    use crate::weight::{fee_for_weight, predict_weight, InputType};
+    use bitcoin::absolute::LockTime;
+    use bitcoin::hashes::Hash;
+    use bitcoin::sighash::TapSighashType;
+    use bitcoin::{
+        FeeRate, Network, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, WPubkeyHash,
+    };
+    use std::collections::BTreeMap;
+
+    const MNEMONICS: [&str; 3] = [
+        "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about",
+        "legal winner thank year wave sausage worth useful legal winner thank yellow",
+        "letter advice cage absurd amount doctor acoustic avoid letter advice cage above",
+    ];
+
+    // a previous tx paying 50_000 sats to each script
+    fn previous_tx(scripts: Vec<ScriptBuf>) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn::default()],
+            output: scripts
+                .into_iter()
+                .map(|script_pubkey| TxOut {
+                    value: 50_000,
+                    script_pubkey,
+                })
+                .collect(),
+        }
+    }
+
+    // spends `vouts` of `previous_tx` to a p2wpkh output
+    fn unsigned_tx(previous_tx: &Transaction, vouts: &[u32]) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vouts
+                .iter()
+                .map(|vout| TxIn {
+                    previous_output: OutPoint::new(previous_tx.txid(), *vout),
+                    sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                    ..Default::default()
+                })
+                .collect(),
+            output: vec![TxOut {
+                value: 10_000,
+                script_pubkey: ScriptBuf::new_v0_p2wpkh(&WPubkeyHash::all_zeros()),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_single_key() {
+        let ag = AccountGenerator::new(MNEMONICS[0], Network::Testnet).unwrap();
+        let account = ag.get_account(KeychainKind::External, 0).unwrap();
+        let previous_tx = previous_tx(
+            SingleKeyType::ALL
+                .iter()
+                .map(|kind| kind.script_pubkey(&account).unwrap())
+                .collect(),
+        );
+        let signer = Signer::new(vec![account]).unwrap();
+
+        // each type alone, a legacy one with a segwit one and all of them together
+        let mut spends = (0..SingleKeyType::ALL.len() as u32)
+            .map(|vout| vec![vout])
+            .collect::<Vec<_>>();
+        spends.push(vec![0, 3]);
+        spends.push((0..SingleKeyType::ALL.len() as u32).collect());
+        for vouts in spends {
+            let mut tx = unsigned_tx(&previous_tx, &vouts);
+            let inputs = vouts
+                .iter()
+                .map(|vout| InputType::SingleKey(SingleKeyType::ALL[*vout as usize]))
+                .collect::<Vec<_>>();
+            let predicted = predict_weight(&tx, &inputs);
+
+            let prevouts = vouts
+                .iter()
+                .map(|vout| previous_tx.output[*vout as usize].clone())
+                .collect::<Vec<_>>();
+            signer.sign_all(&mut tx, &prevouts).unwrap();
+            assert_eq!(predicted, tx.weight(), "{inputs:?}");
+        }
+    }
+
+    #[test]
+    fn test_multisig() {
+        let generators = MNEMONICS
+            .iter()
+            .map(|mnemonic| AccountGenerator::new(mnemonic, Network::Testnet).unwrap())
+            .collect::<Vec<_>>();
+        for kind in [
+            ScriptHashType::P2sh,
+            ScriptHashType::P2shP2wsh,
+            ScriptHashType::P2wsh,
+        ] {
+            let cosigners = generators
+                .iter()
+                .map(|ag| Cosigner::from_generator(ag, kind).unwrap())
+                .collect();
+            let multisig = Multisig::new(2, cosigners, Network::Testnet)
+                .unwrap()
+                .with_kind(kind);
+            let previous_tx = previous_tx(vec![multisig
+                .output(KeychainKind::External, 0)
+                .unwrap()
+                .script_pubkey()]);
+            let utxo = MultisigUtxo {
+                outpoint: OutPoint::new(previous_tx.txid(), 0),
+                txout: previous_tx.output[0].clone(),
+                keychain: KeychainKind::External,
+                index: 0,
+                previous_tx: Some(previous_tx.clone()),
+            };
+            let mut psbt = multisig
+                .create_psbt(&[utxo], unsigned_tx(&previous_tx, &[0]).output)
+                .unwrap();
+            let predicted = predict_weight(
+                &psbt.unsigned_tx,
+                &[InputType::Multisig {
+                    kind,
+                    threshold: 2,
+                    keys: 3,
+                }],
+            );
+
+            for ag in &generators[1..] {
+                ag.sign_multisig_psbt(&mut psbt).unwrap();
+            }
+            finalize_multisig_psbt(&mut psbt).unwrap();
+            assert_eq!(predicted, psbt.extract_tx().weight(), "{kind:?}");
+        }
+    }
+
+    #[test]
+    fn test_tap_multisig() {
+        let accounts = MNEMONICS
+            .iter()
+            .map(|mnemonic| {
+                let ag = AccountGenerator::new(mnemonic, Network::Testnet).unwrap();
+                ag.get_account(KeychainKind::External, 0).unwrap()
+            })
+            .collect::<Vec<_>>();
+        let keys = accounts
+            .iter()
+            .map(|account| account.x_only_public_key())
+            .collect::<Vec<_>>();
+        let multisig = TaprootMultisig::new(2, &keys, None).unwrap();
+        let previous_tx = previous_tx(vec![multisig.script_pubkey()]);
+        let mut tx = unsigned_tx(&previous_tx, &[0]);
+        let predicted = predict_weight(&tx, &[InputType::tap_multisig(&multisig).unwrap()]);
+
+        let signatures = accounts[..2]
+            .iter()
+            .map(|account| {
+                let signature = multisig
+                    .sign(
+                        account,
+                        &tx,
+                        0,
+                        &previous_tx.output,
+                        TapSighashType::Default,
+                    )
+                    .unwrap();
+                (account.x_only_public_key(), signature)
+            })
+            .collect::<BTreeMap<_, _>>();
+        tx.input[0].witness = multisig.witness(&signatures).unwrap();
+        assert_eq!(predicted, tx.weight());
+    }
+
+    #[test]
+    fn test_fee_for_weight() {
+        let fee_rate = FeeRate::from_sat_per_vb_unchecked(10);
+        // 110.25 vbytes pay for 111
+        assert_eq!(
+            fee_for_weight(fee_rate, bitcoin::Weight::from_wu(441)),
+            1110
+        );
+        assert_eq!(
+            fee_for_weight(fee_rate, bitcoin::Weight::from_wu(440)),
+            1100
+        );
+    }
+}